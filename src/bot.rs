@@ -0,0 +1,433 @@
+//! Headless clients for load and integration tests. A bot connects through the same
+//! supervisor as the game, plays a scripted route instead of reading the keyboard, and
+//! reports how much traffic its connection cost and how long the server took to apply
+//! its inputs.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{Instant, sleep_until};
+use url::Url;
+
+use crate::codec::Codec;
+use crate::math::wrap_angle;
+use crate::movement::{MAX_INPUT_DT_MS, MoveInput};
+use crate::net::{
+    ConnectionStatus, INPUT_QUEUE_CAPACITY, InputBatcher, NetEvent, Traffic, TrafficTotals, run_connection_supervisor,
+};
+use crate::protocol::ServerMessage;
+
+/// Length of a scripted frame, about 60 fps like the game.
+pub const FRAME_MS: u16 = 16;
+/// Chance per frame that a random walk turns to a new heading, about every two seconds.
+const TURN_CHANCE: f64 = 1.0 / 120.0;
+/// Chance per frame that a random walk jumps.
+const JUMP_CHANCE: f64 = 1.0 / 300.0;
+/// Radians per second a bot walking in circles turns, a lap every 12 seconds or so.
+const CIRCLE_TURN_RATE: f32 = 0.5;
+
+/// The route a bot plays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Script {
+    /// Walks straight ahead, now and then turning to a random heading or jumping.
+    RandomWalk,
+    /// Walks forward while turning at a steady rate.
+    Circle,
+    /// Plays recorded inputs back with fresh sequence numbers, from the top again when
+    /// they run out.
+    Replay(Recording),
+}
+
+/// Inputs to replay; never empty, so there is always a next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording(Vec<MoveInput>);
+
+impl Recording {
+    pub fn new(inputs: Vec<MoveInput>) -> Option<Recording> {
+        (!inputs.is_empty()).then_some(Recording(inputs))
+    }
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "walk" => Ok(Script::RandomWalk),
+            "circle" => Ok(Script::Circle),
+            other => Err(format!("unknown script {:?}, expected walk or circle", other)),
+        }
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Script::RandomWalk => write!(f, "walk"),
+            Script::Circle => write!(f, "circle"),
+            Script::Replay(Recording(inputs)) => write!(f, "replay of {} inputs", inputs.len()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BotError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, usize, String),
+    Empty(PathBuf),
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            BotError::Parse(path, line, e) => write!(f, "{}:{}: {}", path.display(), line, e),
+            BotError::Empty(path) => write!(f, "{} has no inputs to replay", path.display()),
+        }
+    }
+}
+
+/// Reads an input file to replay: one input per line, as JSON in the shape of the
+/// `inputs` message, e.g. `{"seq":0,"forward":1,"right":0,"up":0,"yaw":0.5,"pitch":0.0,"dt_ms":16}`.
+/// Blank lines are skipped, and the sequence numbers are ignored. Bots only walk, so
+/// inputs with `"fly": true` are refused.
+pub fn load_inputs(path: &Path) -> Result<Recording, BotError> {
+    let text = std::fs::read_to_string(path).map_err(|e| BotError::Io(path.to_path_buf(), e))?;
+    let mut inputs = Vec::new();
+    for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let parse_error = |e: String| BotError::Parse(path.to_path_buf(), index + 1, e);
+        let input: MoveInput = serde_json::from_str(line).map_err(|e| parse_error(e.to_string()))?;
+        if !(1..=MAX_INPUT_DT_MS).contains(&input.dt_ms) {
+            return Err(parse_error(format!("dt_ms must be between 1 and {}", MAX_INPUT_DT_MS)));
+        }
+        if input.fly {
+            return Err(parse_error("bots cannot fly".to_string()));
+        }
+        inputs.push(input);
+    }
+    Recording::new(inputs).ok_or_else(|| BotError::Empty(path.to_path_buf()))
+}
+
+/// Plays a script one frame at a time.
+#[derive(Debug, Clone)]
+pub struct Pilot {
+    script: Script,
+    rng: StdRng,
+    yaw: f32,
+    frame: usize,
+}
+
+impl Pilot {
+    /// Bots with different seeds set off in different directions and take different turns.
+    pub fn new(script: Script, seed: u64) -> Pilot {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let yaw = rng.random_range(-PI..PI);
+        Pilot { script, rng, yaw, frame: 0 }
+    }
+
+    /// The next frame's input. Its sequence number is left for the sender to fill in.
+    pub fn next_input(&mut self) -> MoveInput {
+        let frame = self.frame;
+        self.frame += 1;
+        let walk = MoveInput { forward: 1, dt_ms: FRAME_MS, ..MoveInput::default() };
+        match &self.script {
+            Script::RandomWalk => {
+                if self.rng.random_bool(TURN_CHANCE) {
+                    self.yaw = self.rng.random_range(-PI..PI);
+                }
+                let up = i8::from(self.rng.random_bool(JUMP_CHANCE));
+                MoveInput { up, yaw: self.yaw, ..walk }
+            }
+            Script::Circle => {
+                self.yaw = wrap_angle(self.yaw + CIRCLE_TURN_RATE * walk.dt());
+                MoveInput { yaw: self.yaw, ..walk }
+            }
+            Script::Replay(Recording(inputs)) => MoveInput { seq: 0, ..inputs[frame % inputs.len()] },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BotConfig {
+    pub server_url: Url,
+    pub name: String,
+    pub codec: Codec,
+    pub send_rate: u32,
+    pub script: Script,
+    pub seed: u64,
+    /// How long to play before disconnecting.
+    pub duration: Duration,
+}
+
+/// What one bot saw of its connection.
+#[derive(Debug, Clone, Default)]
+pub struct BotReport {
+    pub name: String,
+    /// Whether the server ever welcomed the bot.
+    pub welcomed: bool,
+    pub elapsed: Duration,
+    pub inputs_sent: u32,
+    pub traffic: TrafficTotals,
+    /// For every batch of inputs, the time from handing it to the connection until a
+    /// snapshot showed the server had applied it. Includes waiting for the next tick.
+    pub latencies: Vec<Duration>,
+}
+
+impl BotReport {
+    /// Several bots' reports as one, e.g. for everyone in a load test.
+    pub fn merge(name: &str, reports: &[BotReport]) -> BotReport {
+        let mut merged = BotReport { name: name.to_string(), welcomed: !reports.is_empty(), ..BotReport::default() };
+        for report in reports {
+            merged.welcomed &= report.welcomed;
+            merged.elapsed = merged.elapsed.max(report.elapsed);
+            merged.inputs_sent += report.inputs_sent;
+            merged.traffic.sent_bytes += report.traffic.sent_bytes;
+            merged.traffic.sent_frames += report.traffic.sent_frames;
+            merged.traffic.received_bytes += report.traffic.received_bytes;
+            merged.traffic.received_frames += report.traffic.received_frames;
+            merged.latencies.extend(&report.latencies);
+        }
+        merged
+    }
+
+    /// Bytes per second sent to the server.
+    pub fn upload_rate(&self) -> f64 {
+        self.traffic.sent_bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Bytes per second received from the server.
+    pub fn download_rate(&self) -> f64 {
+        self.traffic.received_bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The latency that `fraction` (0..=1) of the batches were no slower than.
+    pub fn latency_percentile(&self, fraction: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = (fraction.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+impl fmt::Display for BotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.welcomed {
+            return write!(f, "{}: never welcomed by the server", self.name);
+        }
+        write!(
+            f,
+            "{}: {} inputs in {:.1}s, up {:.2} kB/s ({} frames), down {:.2} kB/s ({} frames)",
+            self.name,
+            self.inputs_sent,
+            self.elapsed.as_secs_f32(),
+            self.upload_rate() / 1000.0,
+            self.traffic.sent_frames,
+            self.download_rate() / 1000.0,
+            self.traffic.received_frames,
+        )?;
+        match (self.latency_percentile(0.5), self.latency_percentile(0.95), self.latency_percentile(1.0)) {
+            (Some(median), Some(p95), Some(max)) => write!(
+                f,
+                ", input latency median {} ms, p95 {} ms, max {} ms",
+                median.as_millis(),
+                p95.as_millis(),
+                max.as_millis()
+            ),
+            _ => write!(f, ", no inputs acknowledged"),
+        }
+    }
+}
+
+/// Connects, plays `config.script` until `config.duration` is up or the connection gives
+/// up, then disconnects and reports.
+pub async fn run_bot(config: BotConfig) -> BotReport {
+    let traffic = Arc::new(Traffic::default());
+    let (input_tx, input_rx) = broadcast::channel(INPUT_QUEUE_CAPACITY);
+    // Bots never edit, but the connection stops once nothing can send it edits.
+    let (_edits_tx, edits_rx) = mpsc::unbounded_channel();
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let connection = tokio::spawn(run_connection_supervisor(
+        config.server_url,
+        config.name.clone(),
+        config.codec,
+        input_rx,
+        edits_rx,
+        events_tx,
+        traffic.clone(),
+    ));
+
+    let mut report = BotReport { name: config.name, ..BotReport::default() };
+    let mut pilot = Pilot::new(config.script, config.seed);
+    let mut batcher = InputBatcher::new(config.send_rate);
+    let mut my_id: Option<String> = None;
+    let mut next_seq: u32 = 1;
+    // The last sequence number of every batch the server has not applied yet, and when
+    // the batch left.
+    let mut in_flight: VecDeque<(u32, Instant)> = VecDeque::new();
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let mut next_frame = start;
+    loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => break,
+            event = events_rx.recv() => match event {
+                None | Some(NetEvent::Status(ConnectionStatus::Failed)) => break,
+                Some(NetEvent::Message(ServerMessage::Welcome(welcome))) => {
+                    report.welcomed = true;
+                    my_id = Some(welcome.your_id);
+                    // Whatever was in flight went down with the previous connection.
+                    in_flight.clear();
+                }
+                Some(NetEvent::Message(ServerMessage::Snapshot { players, .. })) => {
+                    let Some(me) = players.iter().find(|state| my_id.as_ref() == Some(&state.id)) else { continue };
+                    let now = Instant::now();
+                    while let Some(&(_, sent)) = in_flight.front().filter(|(seq, _)| *seq <= me.last_input_seq) {
+                        report.latencies.push(now - sent);
+                        in_flight.pop_front();
+                    }
+                }
+                Some(_) => {}
+            },
+            _ = sleep_until(next_frame) => {
+                let mut input = pilot.next_input();
+                next_frame += Duration::from_millis(input.dt_ms.max(1).into());
+                // The connection drops inputs until the server has welcomed us.
+                // Bots do not simulate, so they cannot tell whether an idle frame moves them.
+                if my_id.is_none() || !batcher.wants(&input, true) {
+                    continue;
+                }
+                input.seq = next_seq;
+                next_seq = next_seq.wrapping_add(1);
+                batcher.queue(input);
+                if let Some(batch) = batcher.take_due((Instant::now() - start).as_secs_f64()) {
+                    report.inputs_sent += batch.len() as u32;
+                    in_flight.push_back((input.seq, Instant::now()));
+                    let _ = input_tx.send(batch);
+                }
+            }
+        }
+    }
+    report.elapsed = start.elapsed();
+    // Nobody is listening any more, so there is nothing to wait for, not even a reconnect.
+    connection.abort();
+    report.traffic = traffic.totals();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("three-dee-bot-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn scripts_parse_from_their_names() {
+        for script in [Script::RandomWalk, Script::Circle] {
+            assert_eq!(script.to_string().parse::<Script>(), Ok(script));
+        }
+        assert!("replay".parse::<Script>().is_err());
+    }
+
+    #[test]
+    fn random_walks_are_repeatable_per_seed() {
+        let inputs = |seed| {
+            let mut pilot = Pilot::new(Script::RandomWalk, seed);
+            (0..600).map(|_| pilot.next_input()).collect::<Vec<_>>()
+        };
+        let walk = inputs(7);
+        assert_eq!(walk, inputs(7));
+        assert_ne!(walk, inputs(8));
+        assert!(walk.iter().all(|input| input.forward == 1 && input.dt_ms == FRAME_MS));
+        let headings = walk.windows(2).filter(|pair| pair[0].yaw != pair[1].yaw).count();
+        assert!((1..20).contains(&headings), "{} turns", headings);
+    }
+
+    #[test]
+    fn circles_turn_at_a_steady_rate() {
+        let mut pilot = Pilot::new(Script::Circle, 1);
+        let inputs: Vec<MoveInput> = (0..100).map(|_| pilot.next_input()).collect();
+        for pair in inputs.windows(2) {
+            let turn = wrap_angle(pair[1].yaw - pair[0].yaw);
+            assert!((turn - CIRCLE_TURN_RATE * FRAME_MS as f32 / 1000.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn replays_loop_through_the_recording() {
+        let recording = vec![
+            MoveInput { seq: 40, forward: 1, yaw: 0.5, dt_ms: 20, ..MoveInput::default() },
+            MoveInput { seq: 41, right: -1, yaw: 0.7, dt_ms: 10, ..MoveInput::default() },
+        ];
+        let mut pilot = Pilot::new(Script::Replay(Recording::new(recording.clone()).unwrap()), 0);
+        let played: Vec<MoveInput> = (0..5).map(|_| pilot.next_input()).collect();
+        assert_eq!(played[0], MoveInput { seq: 0, ..recording[0] });
+        assert_eq!(played[3], MoveInput { seq: 0, ..recording[1] });
+        assert_eq!(played[4], played[0]);
+    }
+
+    #[test]
+    fn loads_input_files() {
+        let path = temp_file(
+            "inputs.jsonl",
+            "{\"seq\":1,\"forward\":1,\"right\":0,\"up\":0,\"yaw\":0.5,\"pitch\":0.0,\"dt_ms\":16}\n\n\
+             {\"seq\":2,\"forward\":0,\"right\":1,\"up\":1,\"yaw\":0.6,\"pitch\":0.1,\"dt_ms\":17,\"fly\":false}\n",
+        );
+        let Recording(inputs) = load_inputs(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!((inputs[1].right, inputs[1].dt_ms, inputs[1].up), (1, 17, 1));
+    }
+
+    #[test]
+    fn rejects_bad_input_files() {
+        let path = temp_file(
+            "bad.jsonl",
+            "{\"seq\":1,\"forward\":1,\"right\":0,\"up\":0,\"yaw\":0.5,\"pitch\":0.0,\"dt_ms\":0}\n",
+        );
+        assert!(matches!(load_inputs(&path), Err(BotError::Parse(_, 1, _))));
+        std::fs::write(&path, "\n{not json\n").unwrap();
+        assert!(matches!(load_inputs(&path), Err(BotError::Parse(_, 2, _))));
+        std::fs::write(
+            &path,
+            "{\"seq\":1,\"forward\":1,\"right\":0,\"up\":0,\"yaw\":0.5,\"pitch\":0.0,\"dt_ms\":16,\"fly\":true}\n",
+        )
+        .unwrap();
+        assert!(matches!(load_inputs(&path), Err(BotError::Parse(_, 1, _))));
+        std::fs::write(&path, "\n").unwrap();
+        assert!(matches!(load_inputs(&path), Err(BotError::Empty(_))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(load_inputs(&path), Err(BotError::Io(..))));
+    }
+
+    #[test]
+    fn reports_add_up_and_rank_latencies() {
+        let ms = Duration::from_millis;
+        let report = |sent_bytes, latencies: &[u64]| BotReport {
+            welcomed: true,
+            elapsed: Duration::from_secs(2),
+            traffic: TrafficTotals { sent_bytes, ..TrafficTotals::default() },
+            latencies: latencies.iter().map(|&l| ms(l)).collect(),
+            ..BotReport::default()
+        };
+        let all = BotReport::merge("all", &[report(1000, &[30, 10]), report(3000, &[20, 40, 50])]);
+        assert!(all.welcomed);
+        assert_eq!(all.upload_rate(), 2000.0);
+        assert_eq!(all.latency_percentile(0.5), Some(ms(30)));
+        assert_eq!(all.latency_percentile(0.0), Some(ms(10)));
+        assert_eq!(all.latency_percentile(1.0), Some(ms(50)));
+        assert_eq!(BotReport::default().latency_percentile(0.5), None);
+        assert!(!BotReport::merge("none", &[]).welcomed);
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::editing::{HeightPatch, TerrainEdit};
+use crate::movement::Ground;
+use crate::terrain::Heightfield;
+use crate::worldgen::{BiomeMap, Generator, TERRAIN_ORIGIN, WorldParams};
+
+/// Cells along each side of a chunk; chunks hold one more sample than this per side.
+pub const CHUNK_CELLS: usize = 32;
+/// Width of one grid cell in world units. A power-of-two fraction, so grid positions are exact.
+pub const CELL_SIZE: f32 = 3.90625;
+/// Width of one chunk in world units.
+pub const CHUNK_SIZE: f32 = CHUNK_CELLS as f32 * CELL_SIZE;
+/// Chunks survive this many chunks past the load radius before being dropped, so walking
+/// back and forth over a chunk border does not regenerate the same chunks over and over.
+pub const UNLOAD_MARGIN: i32 = 1;
+/// Global grid indices some chunk holds, from the first sample of the lowest chunk to the
+/// last sample of the highest.
+pub const SAMPLE_RANGE: std::ops::RangeInclusive<i64> =
+    i32::MIN as i64 * CHUNK_CELLS as i64..=(i32::MAX as i64 + 1) * CHUNK_CELLS as i64;
+
+/// A chunk's position on the chunk grid; chunk (0, 0) starts at `TERRAIN_ORIGIN`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub z: i32,
+}
+
+impl ChunkCoord {
+    /// The chunk world (x, z) lies in.
+    pub fn containing(x: f32, z: f32) -> ChunkCoord {
+        ChunkCoord {
+            x: ((x - TERRAIN_ORIGIN.0) / CHUNK_SIZE).floor() as i32,
+            z: ((z - TERRAIN_ORIGIN.2) / CHUNK_SIZE).floor() as i32,
+        }
+    }
+
+    /// World position of the chunk's first sample at height zero.
+    pub fn origin(self) -> (f32, f32, f32) {
+        (TERRAIN_ORIGIN.0 + self.x as f32 * CHUNK_SIZE, TERRAIN_ORIGIN.1, TERRAIN_ORIGIN.2 + self.z as f32 * CHUNK_SIZE)
+    }
+
+    /// Global grid index of the chunk's first sample.
+    pub fn first_sample(self) -> (i64, i64) {
+        (self.x as i64 * CHUNK_CELLS as i64, self.z as i64 * CHUNK_CELLS as i64)
+    }
+
+    /// Chunks between the two along the longer axis.
+    pub fn distance(self, other: ChunkCoord) -> i32 {
+        (self.x - other.x).abs().max((self.z - other.z).abs())
+    }
+
+    /// Every chunk within `radius` of this one, nearest first.
+    pub fn around(self, radius: i32) -> Vec<ChunkCoord> {
+        let mut coords: Vec<ChunkCoord> = (-radius..=radius)
+            .flat_map(|z| (-radius..=radius).map(move |x| ChunkCoord { x: self.x + x, z: self.z + z }))
+            .collect();
+        coords.sort_by_key(|coord| {
+            let (dx, dz) = (coord.x - self.x, coord.z - self.z);
+            dx * dx + dz * dz
+        });
+        coords
+    }
+
+    /// Whether a chunk loaded for a player in chunk `center` is still worth keeping.
+    pub fn worth_keeping(self, center: ChunkCoord, radius: i32) -> bool {
+        self.distance(center) <= radius + UNLOAD_MARGIN
+    }
+}
+
+/// The terrain chunks currently in memory. Collision goes through here; a point over a
+/// chunk that is not loaded has no ground, like a point off the edge of a heightfield.
+pub struct ChunkManager {
+    generator: Arc<Generator>,
+    chunks: HashMap<ChunkCoord, Heightfield>,
+    /// Every edit so far, merged into one patch per chunk it touched, newest heights
+    /// winning. Laid over each chunk as it is loaded.
+    edits: BTreeMap<ChunkCoord, HeightPatch>,
+    /// Goes up with every patch applied.
+    revision: u64,
+}
+
+impl ChunkManager {
+    pub fn new(world: WorldParams) -> ChunkManager {
+        ChunkManager {
+            generator: Arc::new(Generator::new(world)),
+            chunks: HashMap::new(),
+            edits: BTreeMap::new(),
+            revision: 0,
+        }
+    }
+
+    pub fn world(&self) -> WorldParams {
+        self.generator.world()
+    }
+
+    /// Shared, so chunks can be generated without holding on to the manager.
+    pub fn generator(&self) -> &Arc<Generator> {
+        &self.generator
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    pub fn is_loaded(&self, coord: ChunkCoord) -> bool {
+        self.chunks.contains_key(&coord)
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&Heightfield> {
+        self.chunks.get(&coord)
+    }
+
+    /// Adds a chunk generated elsewhere, replacing any copy already loaded. The edits
+    /// made so far go on top.
+    pub fn insert(&mut self, coord: ChunkCoord, mut heights: Heightfield) {
+        if let Some(patch) = self.edits.get(&coord) {
+            patch.apply_to(coord, &mut heights);
+        }
+        self.chunks.insert(coord, heights);
+    }
+
+    /// Generates whatever is missing within `radius` chunks of world (x, z), right away.
+    pub fn load_around(&mut self, x: f32, z: f32, radius: i32) {
+        for coord in self.missing_around(x, z, radius) {
+            self.insert(coord, self.generator.chunk(coord));
+        }
+    }
+
+    /// The chunks within `radius` chunks of world (x, z) that are not loaded, nearest first.
+    pub fn missing_around(&self, x: f32, z: f32, radius: i32) -> Vec<ChunkCoord> {
+        ChunkCoord::containing(x, z)
+            .around(radius)
+            .into_iter()
+            .filter(|coord| !self.chunks.contains_key(coord))
+            .collect()
+    }
+
+    /// Height above `TERRAIN_ORIGIN.1` of global grid sample (x, z), if the chunk it
+    /// starts is loaded.
+    pub fn sample(&self, x: i64, z: i64) -> Option<f32> {
+        let cells = CHUNK_CELLS as i64;
+        let coord = ChunkCoord { x: x.div_euclid(cells) as i32, z: z.div_euclid(cells) as i32 };
+        let (first_x, first_z) = coord.first_sample();
+        Some(self.chunks.get(&coord)?.sample((x - first_x) as usize, (z - first_z) as usize))
+    }
+
+    /// One patch for every chunk edited so far; what a late joiner needs to catch up.
+    /// However many times a chunk is edited, it only ever has the one.
+    pub fn edits(&self) -> impl ExactSizeIterator<Item = &HeightPatch> {
+        self.edits.values()
+    }
+
+    /// Goes up with every patch, so savers can tell whether anything changed.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Works `edit` out against the loaded chunks and applies it, or returns `None` if the
+    /// brush reaches into chunks that are not loaded.
+    pub fn apply_edit(&mut self, edit: &TerrainEdit) -> Option<HeightPatch> {
+        let patch = edit.apply(|x, z| self.sample(x, z))?;
+        self.apply_patch(patch.clone());
+        Some(patch)
+    }
+
+    /// Applies `patch` to the loaded chunks and keeps it for chunks loaded later. Returns
+    /// the loaded chunks it changed.
+    pub fn apply_patch(&mut self, patch: HeightPatch) -> Vec<ChunkCoord> {
+        let changed = self
+            .chunks
+            .iter_mut()
+            .filter_map(|(coord, heights)| patch.apply_to(*coord, heights).then_some(*coord))
+            .collect();
+        self.merge(&patch);
+        self.revision += 1;
+        changed
+    }
+
+    /// Swaps every edit made so far for `patches`, oldest first, such as the log a server
+    /// sends on joining. Regenerates the loaded chunks either set touches and returns them.
+    pub fn replace_edits(&mut self, patches: Vec<HeightPatch>) -> Vec<ChunkCoord> {
+        let old = std::mem::take(&mut self.edits);
+        for patch in &patches {
+            self.merge(patch);
+        }
+        self.revision += 1;
+        let changed: Vec<ChunkCoord> = self
+            .chunks
+            .keys()
+            .copied()
+            .filter(|coord| old.contains_key(coord) || self.edits.contains_key(coord))
+            .collect();
+        for &coord in &changed {
+            self.insert(coord, self.generator.chunk(coord));
+        }
+        changed
+    }
+
+    /// Drops every chunk `keep` says no to.
+    pub fn retain(&mut self, mut keep: impl FnMut(ChunkCoord) -> bool) {
+        self.chunks.retain(|coord, _| keep(*coord));
+    }
+
+    /// Folds `patch` into the patches of the chunks it touches. Samples those patches grow
+    /// over without anyone having edited them keep their generated heights.
+    fn merge(&mut self, patch: &HeightPatch) {
+        for coord in patch.chunks() {
+            let older = self.edits.get(&coord);
+            if let Some(merged) = patch.merged_over(older, coord, |x, z| self.generator.sample(x, z)) {
+                self.edits.insert(coord, merged);
+            }
+        }
+    }
+
+    fn chunk_at(&self, x: f32, z: f32) -> Option<&Heightfield> {
+        self.chunks.get(&ChunkCoord::containing(x, z))
+    }
+}
+
+impl Ground for ChunkManager {
+    fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.chunk_at(x, z)?.height_at(x, z)
+    }
+
+    fn normal_at(&self, x: f32, z: f32) -> Option<(f32, f32, f32)> {
+        self.chunk_at(x, z)?.normal_at(x, z)
+    }
+
+    fn water_level(&self) -> Option<f32> {
+        self.world().sea_level
+    }
+}
+
+/// Where the player is, so the streamer knows what to generate next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamRequest {
+    pub world: WorldParams,
+    pub center: ChunkCoord,
+    pub radius: i32,
+}
+
+/// One chunk from the streamer, with what a client needs to draw it.
+pub struct GeneratedChunk {
+    pub world: WorldParams,
+    pub coord: ChunkCoord,
+    pub heights: Heightfield,
+    pub biomes: BiomeMap,
+}
+
+/// Generates chunks around the latest requested position, nearest first, until the
+/// requests channel closes. Generation runs on the blocking pool so the runtime keeps
+/// serving the network. Chunks that stop being worth keeping are forgotten, and generated
+/// again if the player comes back for them.
+pub async fn run_streamer(
+    mut requests: mpsc::UnboundedReceiver<StreamRequest>,
+    chunks: mpsc::UnboundedSender<GeneratedChunk>,
+) {
+    let Some(mut request) = requests.recv().await else { return };
+    let mut generator = Arc::new(Generator::new(request.world));
+    let mut sent: HashSet<ChunkCoord> = HashSet::new();
+    loop {
+        while let Ok(newer) = requests.try_recv() {
+            request = newer;
+        }
+        if generator.world() != request.world {
+            generator = Arc::new(Generator::new(request.world));
+            sent.clear();
+        }
+        sent.retain(|coord| coord.worth_keeping(request.center, request.radius));
+
+        let Some(coord) = request.center.around(request.radius).into_iter().find(|coord| !sent.contains(coord)) else {
+            // Everything is loaded; sleep until the player moves.
+            match requests.recv().await {
+                Some(newer) => request = newer,
+                None => return,
+            }
+            continue;
+        };
+        let worker = generator.clone();
+        let generated = tokio::task::spawn_blocking(move || {
+            let heights = worker.chunk(coord);
+            let biomes = worker.biomes(coord, &heights);
+            GeneratedChunk { world: worker.world(), coord, heights, biomes }
+        })
+        .await;
+        let Ok(generated) = generated else { return };
+        sent.insert(coord);
+        if chunks.send(generated).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editing::{Brush, MAX_RAISE};
+    use crate::worldgen::{DEFAULT_WORLD, TerrainPreset};
+
+    const MOUNTAINS: WorldParams = WorldParams::new(11, TerrainPreset::Mountains);
+
+    #[test]
+    fn finds_the_chunk_under_a_point() {
+        assert_eq!(ChunkCoord::containing(TERRAIN_ORIGIN.0, TERRAIN_ORIGIN.2), ChunkCoord { x: 0, z: 0 });
+        assert_eq!(
+            ChunkCoord::containing(TERRAIN_ORIGIN.0 - 0.1, TERRAIN_ORIGIN.2 + CHUNK_SIZE),
+            ChunkCoord { x: -1, z: 1 }
+        );
+        let coord = ChunkCoord { x: 7, z: -4 };
+        let (x, _, z) = coord.origin();
+        assert_eq!(ChunkCoord::containing(x + CHUNK_SIZE / 2.0, z + CHUNK_SIZE / 2.0), coord);
+    }
+
+    #[test]
+    fn nearest_chunks_come_first() {
+        let center = ChunkCoord { x: 2, z: -1 };
+        let around = center.around(2);
+        assert_eq!(around.len(), 25);
+        assert_eq!(around[0], center);
+        assert!(around[1..5].iter().all(|coord| coord.distance(center) == 1));
+        assert!(around.iter().all(|coord| coord.distance(center) <= 2));
+    }
+
+    #[test]
+    fn neighbouring_chunks_share_their_edges_exactly() {
+        let generator = Generator::new(MOUNTAINS);
+        let (a, right, below) = (ChunkCoord { x: -1, z: 3 }, ChunkCoord { x: 0, z: 3 }, ChunkCoord { x: -1, z: 4 });
+        let (a_heights, right_heights, below_heights) =
+            (generator.chunk(a), generator.chunk(right), generator.chunk(below));
+        for i in 0..=CHUNK_CELLS {
+            assert_eq!(a_heights.sample(CHUNK_CELLS, i), right_heights.sample(0, i));
+            assert_eq!(a_heights.sample(i, CHUNK_CELLS), below_heights.sample(i, 0));
+        }
+    }
+
+    #[test]
+    fn collision_is_continuous_across_chunk_borders() {
+        let mut manager = ChunkManager::new(MOUNTAINS);
+        let (x, _, z) = ChunkCoord { x: 1, z: 1 }.origin();
+        manager.load_around(x, z, 1);
+        // Walk straight over the corner where four chunks meet.
+        let mut previous = manager.height_at(x - 2.0, z - 2.0).unwrap();
+        for step in 1..=400 {
+            let offset = -2.0 + step as f32 * 0.01;
+            let height = manager.height_at(x + offset, z + offset).expect("ground at every step");
+            assert!((height - previous).abs() < 0.5, "jump of {} at offset {}", height - previous, offset);
+            previous = height;
+        }
+        assert!(manager.normal_at(x, z).is_some());
+    }
+
+    #[test]
+    fn unloaded_chunks_have_no_ground() {
+        let mut manager = ChunkManager::new(DEFAULT_WORLD);
+        manager.load_around(0.0, 0.0, 1);
+        assert_eq!(manager.len(), 9);
+        assert!(manager.height_at(0.0, 0.0).is_some());
+        assert!(manager.height_at(CHUNK_SIZE * 3.0, 0.0).is_none());
+
+        let center = ChunkCoord::containing(0.0, 0.0);
+        manager.retain(|coord| coord == center);
+        assert_eq!(manager.len(), 1);
+        assert!(manager.is_loaded(center));
+    }
+
+    fn raise_corner(coord: ChunkCoord) -> TerrainEdit {
+        // Right on the corner four chunks share, so the patch spans all of them.
+        let (x, _, z) = coord.origin();
+        TerrainEdit { brush: Brush::Raise, center: (x, z), radius: 10.0, strength: 1.0 }
+    }
+
+    #[test]
+    fn edits_reach_every_copy_of_shared_samples() {
+        let mut manager = ChunkManager::new(MOUNTAINS);
+        let corner = ChunkCoord { x: 1, z: 1 };
+        let (x, _, z) = corner.origin();
+        manager.load_around(x, z, 1);
+        let before = manager.height_at(x, z).unwrap();
+
+        manager.apply_edit(&raise_corner(corner)).unwrap();
+        assert_eq!(manager.edits().len(), 4);
+        assert!((manager.height_at(x, z).unwrap() - before - MAX_RAISE).abs() < 1e-4);
+        let (first_x, first_z) = corner.first_sample();
+        let shared: Vec<f32> = [(0, 0), (-1, 0), (0, -1), (-1, -1)]
+            .into_iter()
+            .map(|(dx, dz)| {
+                let coord = ChunkCoord { x: corner.x + dx, z: corner.z + dz };
+                let (chunk_x, chunk_z) = coord.first_sample();
+                manager.chunk(coord).unwrap().sample((first_x - chunk_x) as usize, (first_z - chunk_z) as usize)
+            })
+            .collect();
+        assert!(shared.iter().all(|&height| height == shared[0]), "{:?}", shared);
+    }
+
+    #[test]
+    fn edits_survive_unloading_and_reloading() {
+        let mut manager = ChunkManager::new(MOUNTAINS);
+        let corner = ChunkCoord { x: 1, z: 1 };
+        let (x, _, z) = corner.origin();
+        manager.load_around(x, z, 1);
+        manager.apply_edit(&raise_corner(corner)).unwrap();
+        let edited = manager.height_at(x, z).unwrap();
+
+        manager.retain(|_| false);
+        manager.load_around(x, z, 1);
+        assert_eq!(manager.height_at(x, z), Some(edited));
+
+        // Chunks streamed in from elsewhere get the edits too.
+        manager.insert(corner, Generator::new(MOUNTAINS).chunk(corner));
+        assert_eq!(manager.height_at(x, z), Some(edited));
+    }
+
+    #[test]
+    fn repeated_edits_merge_into_one_patch_per_chunk() {
+        let mut manager = ChunkManager::new(MOUNTAINS);
+        let corner = ChunkCoord { x: 1, z: 1 };
+        let (x, _, z) = corner.origin();
+        manager.load_around(x, z, 1);
+        for step in 0..20 {
+            let edit = TerrainEdit { center: (x + step as f32, z - step as f32 / 2.0), ..raise_corner(corner) };
+            manager.apply_edit(&edit).unwrap();
+        }
+        assert_eq!(manager.edits().len(), 4);
+        assert_eq!(manager.revision(), 20);
+
+        // The merged patches over freshly generated chunks make the same ground as every stroke.
+        let mut replayed = ChunkManager::new(MOUNTAINS);
+        replayed.replace_edits(manager.edits().cloned().collect());
+        replayed.load_around(x, z, 1);
+        for coord in corner.around(1) {
+            assert_eq!(replayed.chunk(coord), manager.chunk(coord), "chunk {:?}", coord);
+        }
+    }
+
+    #[test]
+    fn replacing_the_edits_restores_the_generated_ground() {
+        let mut manager = ChunkManager::new(MOUNTAINS);
+        let corner = ChunkCoord { x: 1, z: 1 };
+        let (x, _, z) = corner.origin();
+        manager.load_around(x, z, 1);
+        let generated = manager.height_at(x, z).unwrap();
+        let patch = manager.apply_edit(&raise_corner(corner)).unwrap();
+
+        let mut changed = manager.replace_edits(Vec::new());
+        changed.sort_by_key(|coord| (coord.x, coord.z));
+        assert_eq!(changed, [(0, 0), (0, 1), (1, 0), (1, 1)].map(|(x, z)| ChunkCoord { x, z }));
+        assert_eq!(manager.height_at(x, z), Some(generated));
+        assert_eq!(manager.edits().len(), 0);
+
+        manager.replace_edits(vec![patch]);
+        assert!(manager.height_at(x, z).unwrap() > generated);
+    }
+
+    #[tokio::test]
+    async fn streams_chunks_nearest_first_and_follows_the_player() {
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_streamer(request_rx, chunk_tx));
+
+        let start = ChunkCoord { x: 0, z: 0 };
+        request_tx.send(StreamRequest { world: MOUNTAINS, center: start, radius: 1 }).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..9 {
+            let chunk = chunk_rx.recv().await.unwrap();
+            assert_eq!(chunk.heights, Generator::new(MOUNTAINS).chunk(chunk.coord));
+            received.push(chunk.coord);
+        }
+        assert_eq!(received[0], start);
+        assert_eq!(received.iter().copied().collect::<HashSet<_>>(), start.around(1).into_iter().collect());
+
+        // One chunk east only needs the new column, as the old one is still worth keeping.
+        let east = ChunkCoord { x: 1, z: 0 };
+        request_tx.send(StreamRequest { world: MOUNTAINS, center: east, radius: 1 }).unwrap();
+        let mut fresh = HashSet::new();
+        for _ in 0..3 {
+            fresh.insert(chunk_rx.recv().await.unwrap().coord);
+        }
+        assert_eq!(fresh, [-1, 0, 1].into_iter().map(|z| ChunkCoord { x: 2, z }).collect());
+
+        // A new world starts over from scratch.
+        request_tx.send(StreamRequest { world: DEFAULT_WORLD, center: east, radius: 0 }).unwrap();
+        let chunk = chunk_rx.recv().await.unwrap();
+        assert_eq!((chunk.world, chunk.coord), (DEFAULT_WORLD, east));
+
+        drop(request_tx);
+        assert!(chunk_rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::{BTreeSet, HashMap};
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+
+use crate::editing::HeightPatch;
+use crate::math::wrap_angle;
+use crate::movement::MoveInput;
+use crate::protocol::{
+    self, ClientMessage, DecodeError, PROTOCOL_VERSION, PlayerState, RosterEntry, ServerMessage, Welcome,
+};
+
+/// Binary positions are fixed point with this many steps per world unit (about 1.5 cm).
+const POSITION_SCALE: f32 = 64.0;
+/// Binary angles map [-PI, PI) onto the whole i16 range.
+const ANGLE_SCALE: f32 = 32768.0 / PI;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// Tagged JSON text frames, easy to read in a proxy or a log. Peers that never
+    /// asked for anything else get this.
+    #[default]
+    Json,
+    /// bincode frames with quantized transforms and player indices instead of UUIDs
+    /// for the per-tick traffic, and for the welcome and terrain patches, whose heights
+    /// dwarf everything else; the rest still goes out as JSON text.
+    Binary,
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "binary" => Ok(Codec::Binary),
+            other => Err(format!("unknown codec {:?}, expected json or binary", other)),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+/// Who is behind each compact player index. The server owns the real one and sends
+/// copies to clients as `ServerMessage::Roster`.
+#[derive(Debug, Clone, Default)]
+pub struct Roster {
+    entries: HashMap<u16, RosterEntry>,
+    indices: HashMap<String, u16>,
+    /// Indices below `next_index` that players have left behind.
+    free: BTreeSet<u16>,
+    /// The lowest index never handed out; `u16::MAX + 1` once all of them have been.
+    next_index: u32,
+}
+
+impl Roster {
+    /// Hands out the lowest free index, or `None` once all of them are taken.
+    pub fn join(&mut self, id: &str) -> Option<u16> {
+        let index = match self.free.pop_first() {
+            Some(index) => index,
+            None => {
+                let index = u16::try_from(self.next_index).ok()?;
+                self.next_index += 1;
+                index
+            }
+        };
+        self.entries.insert(index, RosterEntry { index, id: id.to_string(), name: String::new() });
+        self.indices.insert(id.to_string(), index);
+        Some(index)
+    }
+
+    pub fn leave(&mut self, id: &str) {
+        if let Some(index) = self.indices.remove(id) {
+            self.entries.remove(&index);
+            self.free.insert(index);
+        }
+    }
+
+    pub fn rename(&mut self, id: &str, name: &str) {
+        if let Some(entry) = self.indices.get(id).and_then(|index| self.entries.get_mut(index)) {
+            entry.name = name.to_string();
+        }
+    }
+
+    pub fn replace(&mut self, entries: Vec<RosterEntry>) {
+        self.indices = entries.iter().map(|entry| (entry.id.clone(), entry.index)).collect();
+        self.entries = entries.into_iter().map(|entry| (entry.index, entry)).collect();
+        self.next_index = self.entries.keys().max().map_or(0, |&index| index as u32 + 1);
+        self.free = (0..self.next_index).map(|index| index as u16).filter(|i| !self.entries.contains_key(i)).collect();
+    }
+
+    pub fn entries(&self) -> Vec<RosterEntry> {
+        let mut entries: Vec<RosterEntry> = self.entries.values().cloned().collect();
+        entries.sort_by_key(|entry| entry.index);
+        entries
+    }
+
+    pub fn index_of(&self, id: &str) -> Option<u16> {
+        self.indices.get(id).copied()
+    }
+
+    pub fn get(&self, index: u16) -> Option<&RosterEntry> {
+        self.entries.get(&index)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CompactState {
+    index: u16,
+    position: [i32; 3],
+    rotation: [i16; 3],
+    last_input_seq: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum BinaryFrame {
+    /// Inputs go out unquantized so the server steps with exactly what the client predicted with.
+    Inputs(Vec<MoveInput>),
+    Snapshot(u64, Vec<CompactState>),
+    Welcome(Welcome),
+    TerrainPatch(HeightPatch),
+}
+
+fn quantize_position(v: f32) -> i32 {
+    (v * POSITION_SCALE).round() as i32
+}
+
+fn dequantize_position(q: i32) -> f32 {
+    q as f32 / POSITION_SCALE
+}
+
+fn quantize_angle(radians: f32) -> i16 {
+    let wrapped = wrap_angle(radians);
+    ((wrapped * ANGLE_SCALE).round() as i32).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+fn dequantize_angle(q: i16) -> f32 {
+    q as f32 / ANGLE_SCALE
+}
+
+fn compact(state: &PlayerState, index: u16) -> CompactState {
+    let (px, py, pz) = state.position;
+    let (rx, ry, rz) = state.rotation;
+    CompactState {
+        index,
+        position: [quantize_position(px), quantize_position(py), quantize_position(pz)],
+        rotation: [quantize_angle(rx), quantize_angle(ry), quantize_angle(rz)],
+        last_input_seq: state.last_input_seq,
+    }
+}
+
+fn expand(state: &CompactState, id: String, name: String) -> PlayerState {
+    let [px, py, pz] = state.position.map(dequantize_position);
+    let [rx, ry, rz] = state.rotation.map(dequantize_angle);
+    PlayerState { id, name, position: (px, py, pz), rotation: (rx, ry, rz), last_input_seq: state.last_input_seq }
+}
+
+fn json_frame<T: Serialize>(msg: &T) -> WsMessage {
+    WsMessage::Text(protocol::encode(msg).expect("protocol messages always serialize").into())
+}
+
+fn binary_frame(frame: &BinaryFrame) -> WsMessage {
+    let bytes = bincode::serde::encode_to_vec((PROTOCOL_VERSION, frame), bincode::config::standard())
+        .expect("binary frames always serialize");
+    WsMessage::Binary(bytes.into())
+}
+
+fn decode_binary_frame(bytes: &[u8]) -> Result<BinaryFrame, DecodeError> {
+    let ((version, frame), _): ((u16, BinaryFrame), usize) =
+        bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+            .map_err(|e| DecodeError::Binary(e.to_string()))?;
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::Version(version));
+    }
+    Ok(frame)
+}
+
+impl Codec {
+    pub fn encode_client(self, msg: &ClientMessage) -> WsMessage {
+        match (self, msg) {
+            (Codec::Binary, ClientMessage::Inputs { inputs }) => binary_frame(&BinaryFrame::Inputs(inputs.clone())),
+            _ => json_frame(msg),
+        }
+    }
+
+    /// Players missing from `roster` are left out of binary snapshots.
+    pub fn encode_server(self, msg: &ServerMessage, roster: &Roster) -> WsMessage {
+        match (self, msg) {
+            (Codec::Binary, ServerMessage::Snapshot { server_time_ms, players }) => {
+                let states =
+                    players.iter().filter_map(|state| Some(compact(state, roster.index_of(&state.id)?))).collect();
+                binary_frame(&BinaryFrame::Snapshot(*server_time_ms, states))
+            }
+            (Codec::Binary, ServerMessage::Welcome(welcome)) => binary_frame(&BinaryFrame::Welcome(welcome.clone())),
+            (Codec::Binary, ServerMessage::TerrainPatch(patch)) => {
+                binary_frame(&BinaryFrame::TerrainPatch(patch.clone()))
+            }
+            _ => json_frame(msg),
+        }
+    }
+}
+
+/// Decodes a frame from a client in either codec.
+pub fn decode_client(frame: &WsMessage) -> Result<ClientMessage, DecodeError> {
+    match frame {
+        WsMessage::Text(text) => protocol::decode(text),
+        WsMessage::Binary(bytes) => match decode_binary_frame(bytes)? {
+            BinaryFrame::Inputs(inputs) => Ok(ClientMessage::Inputs { inputs }),
+            BinaryFrame::Snapshot(..) | BinaryFrame::Welcome(_) | BinaryFrame::TerrainPatch(_) => {
+                Err(DecodeError::Binary("server frame sent by a client".to_string()))
+            }
+        },
+        _ => Err(DecodeError::Binary("not a data frame".to_string())),
+    }
+}
+
+/// Decodes a frame from the server in either codec, resolving binary player indices
+/// through `roster`. Indices the roster does not know yet are dropped.
+pub fn decode_server(frame: &WsMessage, roster: &Roster) -> Result<ServerMessage, DecodeError> {
+    match frame {
+        WsMessage::Text(text) => protocol::decode(text),
+        WsMessage::Binary(bytes) => match decode_binary_frame(bytes)? {
+            BinaryFrame::Snapshot(server_time_ms, states) => Ok(ServerMessage::Snapshot {
+                server_time_ms,
+                players: states
+                    .iter()
+                    .filter_map(|state| {
+                        let entry = roster.get(state.index)?;
+                        Some(expand(state, entry.id.clone(), entry.name.clone()))
+                    })
+                    .collect(),
+            }),
+            BinaryFrame::Welcome(welcome) => Ok(ServerMessage::Welcome(welcome)),
+            BinaryFrame::TerrainPatch(patch) => Ok(ServerMessage::TerrainPatch(patch)),
+            BinaryFrame::Inputs(_) => Err(DecodeError::Binary("inputs sent by the server".to_string())),
+        },
+        _ => Err(DecodeError::Binary("not a data frame".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editing::{Brush, TerrainEdit};
+    use crate::worldgen::{TerrainPreset, WorldParams};
+
+    fn state(id: &str, position: (f32, f32, f32), rotation: (f32, f32, f32)) -> PlayerState {
+        PlayerState { id: id.to_string(), name: String::new(), position, rotation, last_input_seq: 0 }
+    }
+
+    fn assert_close(a: (f32, f32, f32), b: (f32, f32, f32), tolerance: f32) {
+        assert!(
+            (a.0 - b.0).abs() <= tolerance && (a.1 - b.1).abs() <= tolerance && (a.2 - b.2).abs() <= tolerance,
+            "{:?} vs {:?}",
+            a,
+            b
+        );
+    }
+
+    fn roster_with(ids: &[&str]) -> Roster {
+        let mut roster = Roster::default();
+        for id in ids {
+            roster.join(id);
+            roster.rename(id, &id.to_uppercase());
+        }
+        roster
+    }
+
+    #[test]
+    fn json_round_trips_every_kind_of_message() {
+        let roster = Roster::default();
+        let client_messages = [
+            ClientMessage::Hello { name: "alice".into(), codec: Codec::Binary },
+            ClientMessage::Inputs {
+                inputs: vec![MoveInput {
+                    seq: 3,
+                    forward: 1,
+                    right: 0,
+                    up: -1,
+                    yaw: 0.2,
+                    pitch: 0.1,
+                    dt_ms: 16,
+                    fly: false,
+                }],
+            },
+            ClientMessage::Chat { text: "hi".into() },
+            ClientMessage::Ping { nonce: 7 },
+            ClientMessage::Edit(TerrainEdit {
+                brush: Brush::Flatten,
+                center: (-120.5, 33.0),
+                radius: 12.0,
+                strength: 0.25,
+            }),
+        ];
+        for msg in client_messages {
+            let frame = Codec::Json.encode_client(&msg);
+            assert!(frame.is_text());
+            assert_eq!(format!("{:?}", decode_client(&frame).unwrap()), format!("{:?}", msg));
+        }
+
+        let server_messages = [
+            ServerMessage::Snapshot {
+                server_time_ms: 50,
+                players: vec![state("a", (-250.5, 12.25, 3.0), (0.0, 4.0, 0.0))],
+            },
+            ServerMessage::Roster { players: roster_with(&["a"]).entries() },
+            ServerMessage::Chat { from: "a".into(), text: "hi".into() },
+            ServerMessage::PlayerLeft { id: "a".into() },
+            ServerMessage::Pong { nonce: 7 },
+            ServerMessage::TerrainPatch(HeightPatch { first: (-3, 40), width: 2, heights: vec![1.5, -2.0, 0.25, 9.0] }),
+        ];
+        for msg in server_messages {
+            let frame = Codec::Json.encode_server(&msg, &roster);
+            assert!(frame.is_text());
+            assert_eq!(format!("{:?}", decode_server(&frame, &roster).unwrap()), format!("{:?}", msg));
+        }
+    }
+
+    #[test]
+    fn binary_inputs_round_trip_exactly() {
+        let sent = vec![
+            MoveInput { seq: 70_000, forward: -1, right: 1, up: 0, yaw: -1.234_567, pitch: 0.5, dt_ms: 17, fly: false },
+            MoveInput { seq: 70_001, forward: 0, right: 1, up: 1, yaw: -1.3, pitch: 0.5, dt_ms: 16, fly: true },
+        ];
+        let frame = Codec::Binary.encode_client(&ClientMessage::Inputs { inputs: sent.clone() });
+        assert!(frame.is_binary());
+        match decode_client(&frame).unwrap() {
+            ClientMessage::Inputs { inputs } => assert_eq!(inputs, sent),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_snapshot_resolves_indices_through_roster() {
+        let roster = roster_with(&["first-uuid", "second-uuid"]);
+        let players = vec![
+            state("first-uuid", (1.0, 5.0, -1.0), (0.0, 0.5, 0.0)),
+            state("second-uuid", (-300.0, 40.0, -420.0), (0.3, -3.0, 0.0)),
+        ];
+        let msg = ServerMessage::Snapshot { server_time_ms: 123_456, players: players.clone() };
+        let frame = Codec::Binary.encode_server(&msg, &roster);
+        match decode_server(&frame, &roster).unwrap() {
+            ServerMessage::Snapshot { server_time_ms, players: received } => {
+                assert_eq!(server_time_ms, 123_456);
+                assert_eq!(received.len(), 2);
+                for (sent, received) in players.iter().zip(&received) {
+                    assert_eq!(received.id, sent.id);
+                    assert_eq!(received.name, sent.id.to_uppercase());
+                    assert_close(received.position, sent.position, 0.5 / POSITION_SCALE);
+                }
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn binary_snapshot_is_much_smaller_than_json() {
+        let ids: Vec<String> = (0..8).map(|i| format!("6f1c2a3e-0000-4000-8000-00000000000{}", i)).collect();
+        let roster = roster_with(&ids.iter().map(String::as_str).collect::<Vec<_>>());
+        let players = ids.iter().map(|id| state(id, (-123.456, 17.25, -321.5), (0.3, 1.7, 0.0))).collect();
+        let msg = ServerMessage::Snapshot { server_time_ms: 60_000, players };
+        let json = Codec::Json.encode_server(&msg, &roster).len();
+        let binary = Codec::Binary.encode_server(&msg, &roster).len();
+        assert!(binary * 4 < json, "binary {} bytes vs json {} bytes", binary, json);
+    }
+
+    #[test]
+    fn unknown_indices_are_dropped() {
+        let server_roster = roster_with(&["a", "b"]);
+        let client_roster = roster_with(&["a"]);
+        let players = vec![state("a", (0.0, 0.0, 0.0), (0.0, 0.0, 0.0)), state("b", (1.0, 1.0, 1.0), (0.0, 0.0, 0.0))];
+        let frame =
+            Codec::Binary.encode_server(&ServerMessage::Snapshot { server_time_ms: 0, players }, &server_roster);
+        assert!(
+            matches!(decode_server(&frame, &client_roster).unwrap(), ServerMessage::Snapshot { players, .. } if players.len() == 1)
+        );
+    }
+
+    #[test]
+    fn binary_welcomes_and_patches_round_trip() {
+        let patch = HeightPatch { first: (-3, 40), width: 3, heights: (0..300).map(|i| i as f32 * 0.37).collect() };
+        let welcome = Welcome {
+            your_id: "a".into(),
+            your_index: 4,
+            server_version: "0.1.0".into(),
+            tick_rate: 20,
+            spawn: (1.0, 20.0, -3.5),
+            codec: Codec::Binary,
+            bounds: None,
+            world: WorldParams::new(7, TerrainPreset::Islands),
+            edits: vec![patch.clone(); 4],
+            allow_fly: true,
+        };
+        let roster = Roster::default();
+        for msg in [ServerMessage::Welcome(welcome), ServerMessage::TerrainPatch(patch)] {
+            let frame = Codec::Binary.encode_server(&msg, &roster);
+            assert!(frame.is_binary());
+            assert!(frame.len() < Codec::Json.encode_server(&msg, &roster).len());
+            assert_eq!(format!("{:?}", decode_server(&frame, &roster).unwrap()), format!("{:?}", msg));
+        }
+    }
+
+    #[test]
+    fn non_snapshot_messages_stay_json_in_binary_mode() {
+        let msg = ServerMessage::Chat { from: "a".into(), text: "hi".into() };
+        assert!(Codec::Binary.encode_server(&msg, &Roster::default()).is_text());
+    }
+
+    #[test]
+    fn angles_wrap_before_quantizing() {
+        assert_eq!(quantize_angle(0.5), quantize_angle(0.5 + 2.0 * PI));
+        assert_eq!(quantize_angle(-0.5), quantize_angle(-0.5 - 6.0 * PI));
+        assert!((dequantize_angle(quantize_angle(PI - 0.001)) - (PI - 0.001)).abs() < 0.001);
+    }
+
+    #[test]
+    fn roster_reuses_freed_indices() {
+        let mut roster = Roster::default();
+        assert_eq!(roster.join("a"), Some(0));
+        assert_eq!(roster.join("b"), Some(1));
+        roster.leave("a");
+        assert_eq!(roster.join("c"), Some(0));
+        assert_eq!(roster.index_of("b"), Some(1));
+        assert_eq!(roster.join("d"), Some(2));
+    }
+
+    #[test]
+    fn roster_runs_out_of_indices() {
+        let mut roster = Roster::default();
+        for n in 0..=u16::MAX as u32 {
+            assert_eq!(roster.join(&n.to_string()), Some(n as u16));
+        }
+        assert_eq!(roster.join("one too many"), None);
+        roster.leave("1234");
+        assert_eq!(roster.join("one too many"), Some(1234));
+    }
+
+    #[test]
+    fn replaced_rosters_hand_out_the_gaps_first() {
+        let mut roster = Roster::default();
+        roster.replace(vec![
+            RosterEntry { index: 0, id: "a".into(), name: "alice".into() },
+            RosterEntry { index: 2, id: "c".into(), name: "carol".into() },
+        ]);
+        assert_eq!(roster.join("b"), Some(1));
+        assert_eq!(roster.join("d"), Some(3));
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::codec::Codec;
+
+#[derive(Debug, Parser)]
+#[command(about = "Don't tRUST multiplayer client")]
+pub struct Cli {
+    /// TOML or JSON file with any of the settings below; flags override it
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// WebSocket URL of the server, e.g. ws://192.168.1.20:8080/ws
+    #[arg(long)]
+    pub server_url: Option<String>,
+    /// Name shown to other players
+    #[arg(short, long)]
+    pub name: Option<String>,
+    #[arg(long)]
+    pub width: Option<i32>,
+    #[arg(long)]
+    pub height: Option<i32>,
+    #[arg(long)]
+    pub fps: Option<u32>,
+    /// Radians of camera rotation per pixel of mouse movement
+    #[arg(long)]
+    pub sensitivity: Option<f32>,
+    /// Wire format to ask the server for: binary, or json for debugging
+    #[arg(long)]
+    pub codec: Option<Codec>,
+    /// How many times per second to send input to the server
+    #[arg(long)]
+    pub send_rate: Option<u32>,
+    /// How far away terrain is drawn, in world units
+    #[arg(long)]
+    pub view_distance: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server_url: String,
+    pub player_name: String,
+    pub window_width: i32,
+    pub window_height: i32,
+    pub fps: u32,
+    pub mouse_sensitivity: f32,
+    pub codec: Codec,
+    pub send_rate: u32,
+    pub view_distance: f32,
+}
+
+/// Inputs per second the client may send; the low end still feels responsive, the high end
+/// is about where batching stops saving anything.
+const SEND_RATE_RANGE: std::ops::RangeInclusive<u32> = 10..=60;
+/// World units of terrain to draw around the player: at least one chunk, and no further
+/// than the streamer can keep up with while flying.
+const VIEW_DISTANCE_RANGE: std::ops::RangeInclusive<f32> = 125.0..=2000.0;
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_url: "ws://127.0.0.1:8080/ws".to_string(),
+            player_name: "Player".to_string(),
+            window_width: 1920,
+            window_height: 1080,
+            fps: 60,
+            mouse_sensitivity: 0.003,
+            codec: Codec::Binary,
+            send_rate: 30,
+            view_distance: 500.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    UnknownFormat(PathBuf),
+    Url(String, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::UnknownFormat(path) => {
+                write!(f, "{}: config files must end in .toml or .json", path.display())
+            }
+            ConfigError::Url(url, e) => write!(f, "invalid server URL {:?}: {}", url, e),
+            ConfigError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, then the `--config` file if one was given, then the remaining flags.
+    pub fn load(cli: Cli) -> Result<Config, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let parse_error = |e: &dyn fmt::Display| ConfigError::Parse(path.to_path_buf(), e.to_string());
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| parse_error(&e)),
+            Some("json") => serde_json::from_str(&text).map_err(|e| parse_error(&e)),
+            _ => Err(ConfigError::UnknownFormat(path.to_path_buf())),
+        }
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        if let Some(server_url) = cli.server_url {
+            self.server_url = server_url;
+        }
+        if let Some(name) = cli.name {
+            self.player_name = name;
+        }
+        if let Some(width) = cli.width {
+            self.window_width = width;
+        }
+        if let Some(height) = cli.height {
+            self.window_height = height;
+        }
+        if let Some(fps) = cli.fps {
+            self.fps = fps;
+        }
+        if let Some(sensitivity) = cli.sensitivity {
+            self.mouse_sensitivity = sensitivity;
+        }
+        if let Some(codec) = cli.codec {
+            self.codec = codec;
+        }
+        if let Some(send_rate) = cli.send_rate {
+            self.send_rate = send_rate;
+        }
+        if let Some(view_distance) = cli.view_distance {
+            self.view_distance = view_distance;
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.server_url()?;
+        if self.player_name.trim().is_empty() {
+            return Err(ConfigError::Invalid("player name must not be empty".to_string()));
+        }
+        if self.window_width <= 0 || self.window_height <= 0 {
+            return Err(ConfigError::Invalid(format!(
+                "window size {}x{} must be positive",
+                self.window_width, self.window_height
+            )));
+        }
+        if self.fps == 0 {
+            return Err(ConfigError::Invalid("fps must be at least 1".to_string()));
+        }
+        if !(self.mouse_sensitivity.is_finite() && self.mouse_sensitivity > 0.0) {
+            return Err(ConfigError::Invalid("mouse sensitivity must be a positive number".to_string()));
+        }
+        if !SEND_RATE_RANGE.contains(&self.send_rate) {
+            return Err(ConfigError::Invalid(format!(
+                "send rate {} Hz must be between {} and {} Hz",
+                self.send_rate,
+                SEND_RATE_RANGE.start(),
+                SEND_RATE_RANGE.end()
+            )));
+        }
+        if !VIEW_DISTANCE_RANGE.contains(&self.view_distance) {
+            return Err(ConfigError::Invalid(format!(
+                "view distance {} must be between {} and {}",
+                self.view_distance,
+                VIEW_DISTANCE_RANGE.start(),
+                VIEW_DISTANCE_RANGE.end()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn server_url(&self) -> Result<Url, ConfigError> {
+        let url_error = |e: &dyn fmt::Display| ConfigError::Url(self.server_url.clone(), e.to_string());
+        let url = Url::parse(&self.server_url).map_err(|e| url_error(&e))?;
+        match url.scheme() {
+            "ws" | "wss" => Ok(url),
+            scheme => Err(url_error(&format!("expected ws:// or wss://, got {}://", scheme))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(std::iter::once("three-dee").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config =
+            Config::load(cli(&["--server-url", "ws://10.0.0.2:9000/ws", "-n", "alice", "--fps", "144"])).unwrap();
+        assert_eq!(config.server_url().unwrap().port(), Some(9000));
+        assert_eq!(config.player_name, "alice");
+        assert_eq!(config.fps, 144);
+        assert_eq!(config.window_width, 1920);
+        assert_eq!(config.codec, Codec::Binary);
+        assert_eq!(Config::load(cli(&["--codec", "json"])).unwrap().codec, Codec::Json);
+        assert_eq!(Config::load(cli(&["--send-rate", "20"])).unwrap().send_rate, 20);
+        assert!(matches!(Config::load(cli(&["--send-rate", "500"])), Err(ConfigError::Invalid(_))));
+        assert_eq!(Config::load(cli(&["--view-distance", "900"])).unwrap().view_distance, 900.0);
+        assert!(matches!(Config::load(cli(&["--view-distance", "10"])), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_non_websocket_urls() {
+        assert!(matches!(Config::load(cli(&["--server-url", "http://example.com"])), Err(ConfigError::Url(..))));
+        assert!(matches!(Config::load(cli(&["--server-url", "not a url"])), Err(ConfigError::Url(..))));
+    }
+
+    #[test]
+    fn partial_toml_keeps_defaults() {
+        let config: Config = toml::from_str("player_name = \"bob\"\nmouse_sensitivity = 0.01\n").unwrap();
+        assert_eq!(config.player_name, "bob");
+        assert_eq!(config.mouse_sensitivity, 0.01);
+        assert_eq!(config.fps, 60);
+    }
+}
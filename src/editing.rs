@@ -0,0 +1,405 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::{CELL_SIZE, CHUNK_CELLS, ChunkCoord};
+use crate::movement::Ground;
+use crate::terrain::Heightfield;
+use crate::worldgen::TERRAIN_ORIGIN;
+
+/// Smallest and largest brush radius in world units. The largest still fits in the
+/// chunks around the one it is centred in.
+pub const BRUSH_RADIUS_RANGE: std::ops::RangeInclusive<f32> = 2.0..=40.0;
+/// How far raise and lower move the middle of the brush in one stroke at full strength.
+pub const MAX_RAISE: f32 = 2.0;
+/// Furthest from the editor, horizontally, that a stroke may land.
+pub const EDIT_REACH: f32 = 150.0;
+/// Edited heights stay within these, above `TERRAIN_ORIGIN.1`: nobody digs down to the
+/// kill height or builds towers nobody can climb.
+pub const HEIGHT_LIMITS: (f32, f32) = (-40.0, 200.0);
+
+/// What a stroke does to the ground under the brush.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Brush {
+    #[default]
+    Raise,
+    Lower,
+    /// Evens out bumps, towards the average of each sample and its neighbours.
+    Smooth,
+    /// Levels the ground towards the height under the middle of the brush.
+    Flatten,
+}
+
+impl Brush {
+    /// The brush after this one, for cycling through them with a single key.
+    pub fn next(self) -> Brush {
+        match self {
+            Brush::Raise => Brush::Lower,
+            Brush::Lower => Brush::Smooth,
+            Brush::Smooth => Brush::Flatten,
+            Brush::Flatten => Brush::Raise,
+        }
+    }
+}
+
+impl fmt::Display for Brush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Brush::Raise => write!(f, "raise"),
+            Brush::Lower => write!(f, "lower"),
+            Brush::Smooth => write!(f, "smooth"),
+            Brush::Flatten => write!(f, "flatten"),
+        }
+    }
+}
+
+/// One stroke of a brush, as a client asks the server for it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TerrainEdit {
+    pub brush: Brush,
+    /// World (x, z) of the middle of the brush.
+    pub center: (f32, f32),
+    pub radius: f32,
+    /// From 0 to 1: raise and lower move the middle by up to `MAX_RAISE`, smooth and
+    /// flatten go that fraction of the way.
+    pub strength: f32,
+}
+
+/// New heights for a rectangle of samples on the global grid, which is what an edit
+/// turns into. The server sends these rather than the edits, so every client ends up
+/// with its numbers exactly, whatever it had loaded when the edit was made.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeightPatch {
+    /// Global grid index of the first sample, counted in cells from `TERRAIN_ORIGIN`.
+    pub first: (i64, i64),
+    pub width: usize,
+    /// Above `TERRAIN_ORIGIN.1`, row by row along z.
+    pub heights: Vec<f32>,
+}
+
+impl HeightPatch {
+    pub fn depth(&self) -> usize {
+        self.heights.len() / self.width
+    }
+
+    /// Whether the patch covers any sample of the chunk at `coord`.
+    pub fn overlaps(&self, coord: ChunkCoord) -> bool {
+        self.overlap(coord).is_some()
+    }
+
+    /// Global grid index of the last sample, or as near as an `i64` gets to it.
+    pub fn last(&self) -> (i64, i64) {
+        (self.first.0.saturating_add(self.width as i64 - 1), self.first.1.saturating_add(self.depth() as i64 - 1))
+    }
+
+    /// Every chunk the patch covers a sample of, including the ones that only share its
+    /// edge samples. Parts beyond the chunk grid are left out.
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkCoord> {
+        let chunk = |sample: i64| sample.div_euclid(CHUNK_CELLS as i64).clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+        let (last_x, last_z) = self.last();
+        let xs = chunk(self.first.0.saturating_sub(1))..=chunk(last_x);
+        let zs = chunk(self.first.1.saturating_sub(1))..=chunk(last_z);
+        zs.flat_map(move |z| xs.clone().map(move |x| ChunkCoord { x, z }))
+    }
+
+    /// This patch cut down to the chunk at `coord` and laid over `older`, an earlier patch
+    /// for the same chunk, as one rectangle. Samples in it that neither covers come from
+    /// `base`. `None` if this patch misses the chunk.
+    pub fn merged_over(
+        &self,
+        older: Option<&HeightPatch>,
+        coord: ChunkCoord,
+        base: impl Fn(i64, i64) -> f32,
+    ) -> Option<HeightPatch> {
+        let (mut first_x, mut last_x, mut first_z, mut last_z) = self.overlap(coord)?;
+        if let Some(older) = older {
+            let (older_last_x, older_last_z) = older.last();
+            first_x = first_x.min(older.first.0);
+            first_z = first_z.min(older.first.1);
+            last_x = last_x.max(older_last_x);
+            last_z = last_z.max(older_last_z);
+        }
+        let heights = (first_z..=last_z)
+            .flat_map(|z| (first_x..=last_x).map(move |x| (x, z)))
+            .map(|(x, z)| {
+                self.height(x, z).or_else(|| older.and_then(|older| older.height(x, z))).unwrap_or_else(|| base(x, z))
+            })
+            .collect();
+        Some(HeightPatch { first: (first_x, first_z), width: (last_x - first_x + 1) as usize, heights })
+    }
+
+    /// What the patch sets global grid sample (x, z) to, if it covers it.
+    fn height(&self, x: i64, z: i64) -> Option<f32> {
+        let (dx, dz) = (x.checked_sub(self.first.0)?, z.checked_sub(self.first.1)?);
+        if dx < 0 || dz < 0 || dx >= self.width as i64 || dz >= self.depth() as i64 {
+            return None;
+        }
+        Some(self.heights[dz as usize * self.width + dx as usize])
+    }
+
+    /// Copies the patch into `heights`, the chunk at `coord`. Returns whether they
+    /// overlapped at all.
+    pub fn apply_to(&self, coord: ChunkCoord, heights: &mut Heightfield) -> bool {
+        let Some((first_x, last_x, first_z, last_z)) = self.overlap(coord) else { return false };
+        let (chunk_x, chunk_z) = coord.first_sample();
+        for z in first_z..=last_z {
+            for x in first_x..=last_x {
+                let height = self.heights[(z - self.first.1) as usize * self.width + (x - self.first.0) as usize];
+                heights.set_sample((x - chunk_x) as usize, (z - chunk_z) as usize, height);
+            }
+        }
+        true
+    }
+
+    /// Global grid indices of the first and last samples along x, then z, that the patch
+    /// shares with the chunk at `coord`.
+    fn overlap(&self, coord: ChunkCoord) -> Option<(i64, i64, i64, i64)> {
+        let (chunk_x, chunk_z) = coord.first_sample();
+        let cells = CHUNK_CELLS as i64;
+        let (first_x, first_z) = (self.first.0.max(chunk_x), self.first.1.max(chunk_z));
+        let (last_x, last_z) = self.last();
+        let (last_x, last_z) = (last_x.min(chunk_x + cells), last_z.min(chunk_z + cells));
+        (first_x <= last_x && first_z <= last_z).then_some((first_x, last_x, first_z, last_z))
+    }
+}
+
+impl TerrainEdit {
+    /// Checks an edit from a player standing at `editor` before anything is applied.
+    pub fn validate(&self, editor: (f32, f32, f32)) -> Result<(), String> {
+        if !BRUSH_RADIUS_RANGE.contains(&self.radius) {
+            return Err(format!(
+                "brush radius {} must be between {} and {}",
+                self.radius,
+                BRUSH_RADIUS_RANGE.start(),
+                BRUSH_RADIUS_RANGE.end()
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.strength) {
+            return Err(format!("brush strength {} must be between 0 and 1", self.strength));
+        }
+        let (dx, dz) = (self.center.0 - editor.0, self.center.1 - editor.2);
+        let distance = (dx * dx + dz * dz).sqrt();
+        if distance.is_nan() || distance > EDIT_REACH {
+            return Err(format!("edit at {:?} is out of reach of {:?}", self.center, editor));
+        }
+        Ok(())
+    }
+
+    /// Works the stroke out against the current heights, looked up by global grid index.
+    /// Returns `None` if `height` is missing any sample the brush needs.
+    pub fn apply(&self, height: impl Fn(i64, i64) -> Option<f32>) -> Option<HeightPatch> {
+        let center_x = (self.center.0 - TERRAIN_ORIGIN.0) / CELL_SIZE;
+        let center_z = (self.center.1 - TERRAIN_ORIGIN.2) / CELL_SIZE;
+        let reach = self.radius / CELL_SIZE;
+        let (first_x, last_x) = ((center_x - reach).ceil() as i64, (center_x + reach).floor() as i64);
+        let (first_z, last_z) = ((center_z - reach).ceil() as i64, (center_z + reach).floor() as i64);
+        let target = height(center_x.round() as i64, center_z.round() as i64)?;
+
+        let mut heights = Vec::with_capacity(((last_x - first_x + 1) * (last_z - first_z + 1)) as usize);
+        for z in first_z..=last_z {
+            for x in first_x..=last_x {
+                let current = height(x, z)?;
+                let distance = ((x as f32 - center_x).powi(2) + (z as f32 - center_z).powi(2)).sqrt() / reach;
+                // Full strength in the middle, fading smoothly to nothing at the rim.
+                let pull = self.strength * (1.0 - distance).max(0.0).powi(2);
+                let edited = match self.brush {
+                    Brush::Raise => current + MAX_RAISE * pull,
+                    Brush::Lower => current - MAX_RAISE * pull,
+                    Brush::Smooth => {
+                        let neighbours = height(x - 1, z)? + height(x + 1, z)? + height(x, z - 1)? + height(x, z + 1)?;
+                        current + ((current + neighbours) / 5.0 - current) * pull
+                    }
+                    Brush::Flatten => current + (target - current) * pull,
+                };
+                heights.push(edited.clamp(HEIGHT_LIMITS.0, HEIGHT_LIMITS.1));
+            }
+        }
+        Some(HeightPatch { first: (first_x, first_z), width: (last_x - first_x + 1) as usize, heights })
+    }
+}
+
+/// Where a ray from `origin` along `direction` first meets the ground, within `reach`
+/// world units. Steps a quarter of a cell at a time and then narrows the crossing down,
+/// so it can step over the tip of a sharp peak but never through a hill.
+pub fn raycast(
+    ground: &impl Ground,
+    origin: (f32, f32, f32),
+    direction: (f32, f32, f32),
+    reach: f32,
+) -> Option<(f32, f32, f32)> {
+    let length = (direction.0 * direction.0 + direction.1 * direction.1 + direction.2 * direction.2).sqrt();
+    if length == 0.0 {
+        return None;
+    }
+    let direction = (direction.0 / length, direction.1 / length, direction.2 / length);
+    let at = |t: f32| (origin.0 + direction.0 * t, origin.1 + direction.1 * t, origin.2 + direction.2 * t);
+    let below_ground = |t: f32| {
+        let (x, y, z) = at(t);
+        ground.height_at(x, z).is_some_and(|height| y <= height)
+    };
+
+    let step = CELL_SIZE / 4.0;
+    let mut previous = 0.0;
+    let mut t = step;
+    while t <= reach {
+        if below_ground(t) {
+            let (mut above, mut below) = (previous, t);
+            for _ in 0..16 {
+                let middle = (above + below) / 2.0;
+                if below_ground(middle) {
+                    below = middle;
+                } else {
+                    above = middle;
+                }
+            }
+            return Some(at(below));
+        }
+        previous = t;
+        t += step;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::SAMPLE_RANGE;
+    use crate::movement::NoGround;
+
+    /// Ground at `height(x, z)` for every global grid index.
+    fn stroke(brush: Brush, strength: f32, height: impl Fn(i64, i64) -> f32) -> HeightPatch {
+        let center = (TERRAIN_ORIGIN.0 + 10.0 * CELL_SIZE, TERRAIN_ORIGIN.2 + 20.0 * CELL_SIZE);
+        let edit = TerrainEdit { brush, center, radius: 4.0 * CELL_SIZE, strength };
+        edit.apply(|x, z| Some(height(x, z))).unwrap()
+    }
+
+    fn at(patch: &HeightPatch, x: i64, z: i64) -> f32 {
+        patch.heights[(z - patch.first.1) as usize * patch.width + (x - patch.first.0) as usize]
+    }
+
+    #[test]
+    fn raising_lifts_the_middle_most() {
+        let patch = stroke(Brush::Raise, 0.5, |_, _| 3.0);
+        assert_eq!((patch.first, patch.width, patch.depth()), ((6, 16), 9, 9));
+        assert_eq!(at(&patch, 10, 20), 3.0 + MAX_RAISE * 0.5);
+        assert!(at(&patch, 12, 20) > 3.0 && at(&patch, 12, 20) < at(&patch, 11, 20));
+        // The rim and the corners outside the circle stay put.
+        assert_eq!((at(&patch, 14, 20), at(&patch, 6, 16)), (3.0, 3.0));
+
+        let lowered = stroke(Brush::Lower, 0.5, |_, _| 3.0);
+        assert_eq!(at(&lowered, 10, 20), 3.0 - MAX_RAISE * 0.5);
+        assert_eq!(at(&stroke(Brush::Lower, 1.0, |_, _| HEIGHT_LIMITS.0), 10, 20), HEIGHT_LIMITS.0);
+    }
+
+    #[test]
+    fn flattening_levels_towards_the_middle() {
+        let ramp = |x: i64, _| x as f32;
+        let patch = stroke(Brush::Flatten, 1.0, ramp);
+        assert_eq!(at(&patch, 10, 20), 10.0);
+        for x in 7..=13 {
+            assert!((at(&patch, x, 20) - 10.0).abs() < (x as f32 - 10.0).abs() || x == 10, "sample {}", x);
+        }
+    }
+
+    #[test]
+    fn smoothing_wears_down_spikes() {
+        let spike = |x: i64, z: i64| if (x, z) == (10, 20) { 20.0 } else { 0.0 };
+        let patch = stroke(Brush::Smooth, 1.0, spike);
+        assert_eq!(at(&patch, 10, 20), 4.0);
+        assert!(at(&patch, 11, 20) > 0.0);
+        // A plane is already as smooth as it gets.
+        let plane = stroke(Brush::Smooth, 1.0, |x, z| x as f32 * 0.5 - z as f32);
+        assert!((at(&plane, 9, 19) - (9.0 * 0.5 - 19.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn strokes_need_every_sample_under_the_brush() {
+        let edit = TerrainEdit {
+            brush: Brush::Smooth,
+            center: (TERRAIN_ORIGIN.0, TERRAIN_ORIGIN.2),
+            radius: 10.0,
+            strength: 1.0,
+        };
+        assert!(edit.apply(|x, _| (x >= 0).then_some(0.0)).is_none());
+        assert!(edit.apply(|_, _| Some(0.0)).is_some());
+    }
+
+    #[test]
+    fn patches_land_in_every_chunk_they_touch() {
+        let patch = HeightPatch { first: (30, -2), width: 4, heights: (0..16).map(|i| i as f32).collect() };
+        let flat = |coord: ChunkCoord| {
+            let samples = CHUNK_CELLS + 1;
+            let size = CHUNK_CELLS as f32 * CELL_SIZE;
+            Heightfield::new(samples, samples, vec![0.0; samples * samples], coord.origin(), (size, size))
+        };
+        let (mut left, mut right) = (flat(ChunkCoord { x: 0, z: -1 }), flat(ChunkCoord { x: 1, z: -1 }));
+        assert!(patch.apply_to(ChunkCoord { x: 0, z: -1 }, &mut left));
+        assert!(patch.apply_to(ChunkCoord { x: 1, z: -1 }, &mut right));
+        // Global (32, -1) is the shared edge: column 32 on the left, column 0 on the right.
+        assert_eq!(left.sample(32, 31), 6.0);
+        assert_eq!(right.sample(0, 31), 6.0);
+        assert_eq!(right.sample(1, 30), 3.0);
+        let mut far = flat(ChunkCoord { x: 5, z: 5 });
+        assert!(!patch.apply_to(ChunkCoord { x: 5, z: 5 }, &mut far));
+        assert!(patch.overlaps(ChunkCoord { x: 1, z: 0 }) && !patch.overlaps(ChunkCoord { x: 2, z: 0 }));
+        let touched: Vec<ChunkCoord> = patch.chunks().collect();
+        assert_eq!(touched, [(0, -1), (1, -1), (0, 0), (1, 0)].map(|(x, z)| ChunkCoord { x, z }));
+    }
+
+    #[test]
+    fn merged_patches_keep_the_newest_heights() {
+        let coord = ChunkCoord { x: 0, z: 0 };
+        let older = HeightPatch { first: (2, 2), width: 2, heights: vec![1.0; 4] };
+        let newer = HeightPatch { first: (3, 3), width: 2, heights: vec![2.0; 4] };
+        let merged = newer.merged_over(Some(&older), coord, |_, _| -1.0).unwrap();
+        assert_eq!((merged.first, merged.width, merged.depth()), ((2, 2), 3, 3));
+        assert_eq!(merged.heights, [1.0, 1.0, -1.0, 1.0, 2.0, 2.0, -1.0, 2.0, 2.0]);
+
+        // Only the part inside the chunk is kept.
+        let straddling = HeightPatch { first: (-1, 0), width: 2, heights: vec![3.0; 2] };
+        let clipped = straddling.merged_over(None, coord, |_, _| 0.0).unwrap();
+        assert_eq!((clipped.first, clipped.heights), ((0, 0), vec![3.0]));
+        assert!(straddling.merged_over(None, ChunkCoord { x: 4, z: 0 }, |_, _| 0.0).is_none());
+    }
+
+    #[test]
+    fn patches_past_the_chunk_grid_only_touch_the_chunks_at_its_edge() {
+        let corner = HeightPatch { first: (*SAMPLE_RANGE.start(), *SAMPLE_RANGE.end()), width: 1, heights: vec![1.0] };
+        let touched: Vec<ChunkCoord> = corner.chunks().collect();
+        assert_eq!(touched, [ChunkCoord { x: i32::MIN, z: i32::MAX }]);
+
+        let beyond = HeightPatch { first: (i64::MAX - 1, i64::MIN), width: 4, heights: vec![1.0; 8] };
+        assert_eq!(beyond.last(), (i64::MAX, i64::MIN + 1));
+        let touched: Vec<ChunkCoord> = beyond.chunks().collect();
+        assert_eq!(touched, [ChunkCoord { x: i32::MAX, z: i32::MIN }]);
+        assert!(beyond.merged_over(None, touched[0], |_, _| 0.0).is_none());
+    }
+
+    #[test]
+    fn validation_rejects_wild_strokes() {
+        let edit = TerrainEdit { brush: Brush::Raise, center: (10.0, 10.0), radius: 8.0, strength: 0.5 };
+        assert_eq!(edit.validate((0.0, 50.0, 0.0)), Ok(()));
+        assert!(TerrainEdit { radius: 500.0, ..edit }.validate((0.0, 0.0, 0.0)).is_err());
+        assert!(TerrainEdit { strength: 3.0, ..edit }.validate((0.0, 0.0, 0.0)).is_err());
+        assert!(edit.validate((EDIT_REACH + 20.0, 0.0, 0.0)).is_err());
+        assert!(TerrainEdit { center: (f32::NAN, 0.0), ..edit }.validate((0.0, 0.0, 0.0)).is_err());
+    }
+
+    struct Flat(f32);
+
+    impl Ground for Flat {
+        fn height_at(&self, _x: f32, _z: f32) -> Option<f32> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn rays_stop_at_the_ground() {
+        let hit = raycast(&Flat(2.0), (0.0, 12.0, 0.0), (1.0, -1.0, 0.0), 50.0).unwrap();
+        assert!((hit.0 - 10.0).abs() < 1e-3 && (hit.1 - 2.0).abs() < 1e-3 && hit.2 == 0.0, "{:?}", hit);
+        assert_eq!(raycast(&Flat(2.0), (0.0, 12.0, 0.0), (1.0, 1.0, 0.0), 50.0), None);
+        assert_eq!(raycast(&Flat(2.0), (0.0, 12.0, 0.0), (1.0, -0.1, 0.0), 50.0), None);
+        assert_eq!(raycast(&NoGround, (0.0, 12.0, 0.0), (0.0, -1.0, 0.0), 50.0), None);
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use url::Url;
+
+use three_dee::bot::{BotConfig, BotReport, Script, load_inputs, run_bot};
+use three_dee::codec::Codec;
+use three_dee::config::Config;
+
+#[derive(Debug, Parser)]
+#[command(about = "Headless Don't tRUST clients that play scripted routes and report bandwidth and latency")]
+struct Cli {
+    /// WebSocket URL of the server, e.g. ws://192.168.1.20:8080/ws
+    #[arg(long, default_value_t = Config::default().server_url.parse().unwrap())]
+    server_url: Url,
+    /// How many bots to connect at once
+    #[arg(long, default_value_t = 1)]
+    bots: u32,
+    /// Route to play: walk (random walk) or circle
+    #[arg(long, default_value_t = Script::RandomWalk)]
+    script: Script,
+    /// File of recorded inputs to replay instead of the script, one JSON input per line
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// Seconds to play before disconnecting and reporting
+    #[arg(long, default_value_t = 30.0)]
+    duration: f64,
+    /// Wire format to ask the server for: binary, or json for debugging
+    #[arg(long, default_value_t = Codec::Binary)]
+    codec: Codec,
+    /// How many times per second each bot sends input to the server
+    #[arg(long, default_value_t = Config::default().send_rate)]
+    send_rate: u32,
+    /// Seed for the random walks and starting headings; bot n uses seed + n
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let script = match &cli.replay {
+        None => cli.script,
+        Some(path) => match load_inputs(path) {
+            Ok(recording) => Script::Replay(recording),
+            Err(e) => {
+                eprintln!("BOT: {}", e);
+                std::process::exit(2);
+            }
+        },
+    };
+    if !cli.duration.is_finite() || cli.duration <= 0.0 {
+        eprintln!("BOT: --duration must be a positive number of seconds");
+        std::process::exit(2);
+    }
+
+    println!("BOT: Sending {} bots to {} for {}s ({})", cli.bots, cli.server_url, cli.duration, script);
+    let bots: Vec<_> = (1..=cli.bots)
+        .map(|n| {
+            tokio::spawn(run_bot(BotConfig {
+                server_url: cli.server_url.clone(),
+                name: format!("bot-{}", n),
+                codec: cli.codec,
+                send_rate: cli.send_rate,
+                script: script.clone(),
+                seed: cli.seed + n as u64,
+                duration: Duration::from_secs_f64(cli.duration),
+            }))
+        })
+        .collect();
+    let mut reports = Vec::new();
+    for bot in bots {
+        match bot.await {
+            Ok(report) => reports.push(report),
+            Err(e) => eprintln!("BOT: A bot crashed: {}", e),
+        }
+    }
+
+    for report in &reports {
+        println!("BOT: {}", report);
+    }
+    if reports.len() > 1 {
+        println!("BOT: {}", BotReport::merge("all bots", &reports));
+    }
+    if reports.iter().any(|report| !report.welcomed) {
+        std::process::exit(1);
+    }
+}
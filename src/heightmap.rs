@@ -0,0 +1,250 @@
+use std::fmt;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::chunks::CELL_SIZE;
+use crate::editing::{HEIGHT_LIMITS, HeightPatch};
+use crate::terrain::Heightfield;
+use crate::worldgen::{MAP_SIZE_RANGE, TERRAIN_ORIGIN};
+
+/// Heightmaps with more pixels than this on a side are turned away before decoding.
+const MAX_PIXELS: usize = 8193;
+/// World units between neighbouring pixels that a heightmap may be stretched to.
+pub const HORIZONTAL_SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.25..=64.0;
+
+/// How big a heightmap comes out in the world.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightmapScale {
+    /// World units between neighbouring pixels.
+    pub horizontal: f32,
+    /// Height of a white pixel, in world units; black is the bottom of the play area.
+    pub vertical: f32,
+}
+
+impl Default for HeightmapScale {
+    /// One pixel per terrain sample, and hills about the height of the mountains preset.
+    fn default() -> HeightmapScale {
+        HeightmapScale { horizontal: CELL_SIZE, vertical: 80.0 }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(std::io::Error),
+    UnknownFormat,
+    Png(String),
+    Invalid(String),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "{}", e),
+            HeightmapError::UnknownFormat => write!(f, "heightmaps must end in .png, .raw or .r16"),
+            HeightmapError::Png(e) => write!(f, "cannot decode PNG: {}", e),
+            HeightmapError::Invalid(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Reads a grayscale PNG, or a square 16-bit RAW file, as a heightfield starting at
+/// `TERRAIN_ORIGIN`.
+pub fn load_heightmap(path: &Path, scale: HeightmapScale) -> Result<Heightfield, HeightmapError> {
+    let bytes = std::fs::read(path).map_err(HeightmapError::Io)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => decode_png(&bytes, scale),
+        Some("raw" | "r16") => decode_raw(&bytes, scale),
+        _ => Err(HeightmapError::UnknownFormat),
+    }
+}
+
+/// 8 or 16-bit grayscale, with or without alpha, which is ignored. Lower bit depths are
+/// widened to 8 bits.
+pub fn decode_png(bytes: &[u8], scale: HeightmapScale) -> Result<Heightfield, HeightmapError> {
+    let png_error = |e: png::DecodingError| HeightmapError::Png(e.to_string());
+    let mut decoder = png::Decoder::new(Cursor::new(bytes));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let (width, depth) = (reader.info().width as usize, reader.info().height as usize);
+    check_dimensions(width, depth)?;
+    let (color, bit_depth) = reader.output_color_type();
+    let channels = match color {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        other => return Err(HeightmapError::Invalid(format!("heightmaps must be grayscale, not {:?}", other))),
+    };
+    let Some(size) = reader.output_buffer_size() else {
+        return Err(HeightmapError::Invalid(format!("a {}x{} heightmap is too big to decode", width, depth)));
+    };
+    let mut pixels = vec![0; size];
+    let frame = reader.next_frame(&mut pixels).map_err(png_error)?;
+    let levels: Vec<f32> = match bit_depth {
+        png::BitDepth::Sixteen => pixels[..frame.buffer_size()]
+            .chunks(2 * channels)
+            .map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32)
+            .collect(),
+        _ => pixels[..frame.buffer_size()].chunks(channels).map(|pixel| pixel[0] as f32 / u8::MAX as f32).collect(),
+    };
+    build(width, depth, &levels, scale)
+}
+
+/// Raw little-endian 16-bit samples, row by row, as most terrain tools and DEM converters
+/// export them. There is no header, so the grid has to be square.
+pub fn decode_raw(bytes: &[u8], scale: HeightmapScale) -> Result<Heightfield, HeightmapError> {
+    let samples = bytes.len() / 2;
+    let side = samples.isqrt();
+    if !bytes.len().is_multiple_of(2) || side * side != samples {
+        return Err(HeightmapError::Invalid(format!(
+            "a RAW heightmap of {} bytes is not a square grid of 16-bit samples",
+            bytes.len()
+        )));
+    }
+    check_dimensions(side, side)?;
+    let levels: Vec<f32> =
+        bytes.chunks(2).map(|sample| u16::from_le_bytes([sample[0], sample[1]]) as f32 / u16::MAX as f32).collect();
+    build(side, side, &levels, scale)
+}
+
+/// The heightmap's heights on the terrain's own grid, every sample from `TERRAIN_ORIGIN`
+/// that it covers, to lay over the generated terrain like an edit.
+pub fn terrain_patch(heightmap: &Heightfield) -> HeightPatch {
+    let size = heightmap.size();
+    let (width, depth) = ((size.0 / CELL_SIZE) as usize + 1, (size.1 / CELL_SIZE) as usize + 1);
+    let heights = (0..depth)
+        .flat_map(|z| (0..width).map(move |x| (x, z)))
+        .map(|(x, z)| {
+            let (world_x, world_z) = (TERRAIN_ORIGIN.0 + x as f32 * CELL_SIZE, TERRAIN_ORIGIN.2 + z as f32 * CELL_SIZE);
+            heightmap.height_at(world_x, world_z).expect("samples inside the heightmap") - TERRAIN_ORIGIN.1
+        })
+        .collect();
+    HeightPatch { first: (0, 0), width, heights }
+}
+
+fn check_dimensions(width: usize, depth: usize) -> Result<(), HeightmapError> {
+    if width < 2 || depth < 2 || width > MAX_PIXELS || depth > MAX_PIXELS {
+        return Err(HeightmapError::Invalid(format!(
+            "a {}x{} heightmap, expected between 2 and {} pixels on a side",
+            width, depth, MAX_PIXELS
+        )));
+    }
+    Ok(())
+}
+
+/// `levels` run from 0 to 1, row by row.
+fn build(width: usize, depth: usize, levels: &[f32], scale: HeightmapScale) -> Result<Heightfield, HeightmapError> {
+    if !HORIZONTAL_SCALE_RANGE.contains(&scale.horizontal) {
+        return Err(HeightmapError::Invalid(format!(
+            "horizontal scale {} must be between {} and {}",
+            scale.horizontal,
+            HORIZONTAL_SCALE_RANGE.start(),
+            HORIZONTAL_SCALE_RANGE.end()
+        )));
+    }
+    if !(0.0..=HEIGHT_LIMITS.1).contains(&scale.vertical) {
+        return Err(HeightmapError::Invalid(format!(
+            "vertical scale {} must be between 0 and {}",
+            scale.vertical, HEIGHT_LIMITS.1
+        )));
+    }
+    let size = ((width - 1) as f32 * scale.horizontal, (depth - 1) as f32 * scale.horizontal);
+    if !MAP_SIZE_RANGE.contains(&size.0) || !MAP_SIZE_RANGE.contains(&size.1) {
+        return Err(HeightmapError::Invalid(format!(
+            "a {}x{} heightmap at {} units a pixel covers {}x{}, but play areas are between {} and {} units a side",
+            width,
+            depth,
+            scale.horizontal,
+            size.0,
+            size.1,
+            MAP_SIZE_RANGE.start(),
+            MAP_SIZE_RANGE.end()
+        )));
+    }
+    let heights = levels.iter().map(|level| level * scale.vertical).collect();
+    Ok(Heightfield::new(width, depth, heights, TERRAIN_ORIGIN, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_png(width: u32, height: u32, color: png::ColorType, depth: png::BitDepth, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        encoder.write_header().unwrap().write_image_data(data).unwrap();
+        bytes
+    }
+
+    const SCALE: HeightmapScale = HeightmapScale { horizontal: 10.0, vertical: 100.0 };
+
+    #[test]
+    fn reads_8_bit_grayscale_pngs() {
+        let png = encode_png(3, 2, png::ColorType::Grayscale, png::BitDepth::Eight, &[0, 51, 255, 102, 153, 204]);
+        let heights = decode_png(&png, SCALE).unwrap();
+        assert_eq!((heights.samples(), heights.size()), ((3, 2), (20.0, 10.0)));
+        assert_eq!(heights.sample(2, 0), 100.0);
+        assert!((heights.sample(1, 1) - 60.0).abs() < 1e-4);
+        let (x, _, z) = TERRAIN_ORIGIN;
+        assert_eq!(heights.height_at(x + 20.0, z), Some(TERRAIN_ORIGIN.1 + 100.0));
+    }
+
+    #[test]
+    fn reads_16_bit_pngs_and_ignores_alpha() {
+        let gray = [0u16, 65535, 32768, 1000];
+        let data: Vec<u8> = gray.iter().flat_map(|level| [level.to_be_bytes(), [0xff, 0xff]].concat()).collect();
+        let png = encode_png(2, 2, png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen, &data);
+        let heights = decode_png(&png, SCALE).unwrap();
+        assert_eq!(heights.sample(1, 0), 100.0);
+        assert!((heights.sample(1, 1) - 1000.0 / 65535.0 * 100.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn reads_square_raw_files() {
+        let bytes: Vec<u8> = (0..9u16).flat_map(|i| (i * 8000).to_le_bytes()).collect();
+        let heights = decode_raw(&bytes, SCALE).unwrap();
+        assert_eq!(heights.samples(), (3, 3));
+        assert!((heights.sample(0, 1) - 24000.0 / 65535.0 * 100.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn turns_away_bad_dimensions_and_scales() {
+        let invalid = |result: Result<Heightfield, HeightmapError>| matches!(result, Err(HeightmapError::Invalid(_)));
+        assert!(invalid(decode_raw(&[0; 12], SCALE)), "6 samples are not a square");
+        assert!(invalid(decode_raw(&[0; 9], SCALE)), "odd byte count");
+        assert!(invalid(decode_raw(&[0; 2], SCALE)), "a single sample");
+        let rgb = encode_png(2, 2, png::ColorType::Rgb, png::BitDepth::Eight, &[0; 12]);
+        assert!(invalid(decode_png(&rgb, SCALE)));
+        let line = encode_png(5, 1, png::ColorType::Grayscale, png::BitDepth::Eight, &[0; 5]);
+        assert!(invalid(decode_png(&line, SCALE)));
+        assert!(matches!(decode_png(b"not a png", SCALE), Err(HeightmapError::Png(_))));
+
+        let square = vec![0; 2 * 4 * 4];
+        assert!(decode_raw(&square, SCALE).is_ok());
+        for scale in [
+            HeightmapScale { horizontal: 0.0, ..SCALE },
+            HeightmapScale { horizontal: f32::NAN, ..SCALE },
+            HeightmapScale { vertical: -1.0, ..SCALE },
+            HeightmapScale { vertical: HEIGHT_LIMITS.1 * 2.0, ..SCALE },
+            // Too small a play area to stand in, then too big to send.
+            HeightmapScale { horizontal: 1.0, ..SCALE },
+            HeightmapScale { horizontal: 2000.0, ..SCALE },
+        ] {
+            assert!(invalid(decode_raw(&square, scale)), "{:?}", scale);
+        }
+        assert!(invalid(decode_raw(&vec![0; 2 * 2000 * 2000], SCALE)), "2000 pixels at 10 units is too wide");
+    }
+
+    #[test]
+    fn lands_on_the_terrain_grid() {
+        // 9x9 pixels two cells apart cover a 16x16 cell play area.
+        let levels: Vec<u8> = (0..81).map(|i| (i % 9 * 30) as u8).collect();
+        let png = encode_png(9, 9, png::ColorType::Grayscale, png::BitDepth::Eight, &levels);
+        let heightmap = decode_png(&png, HeightmapScale { horizontal: CELL_SIZE * 2.0, vertical: 51.0 }).unwrap();
+        let patch = terrain_patch(&heightmap);
+        assert_eq!((patch.first, patch.width, patch.depth()), ((0, 0), 17, 17));
+        // Every other sample is a pixel, and the ones between them are halfway.
+        assert!((patch.heights[16] - 48.0).abs() < 1e-3, "{}", patch.heights[16]);
+        assert!((patch.heights[17 * 5 + 3] - 9.0).abs() < 1e-3, "{}", patch.heights[17 * 5 + 3]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+
+use crate::math::{lerp_angle, lerp_vec3};
+
+/// How far in the past remote players are drawn, so there is usually a snapshot on
+/// either side of the render time.
+pub const INTERPOLATION_DELAY: f64 = 0.1;
+/// How far past the newest snapshot we keep extrapolating before freezing in place.
+pub const MAX_EXTRAPOLATION: f64 = 0.25;
+/// Remote players with no snapshot for this long (server seconds) are considered gone.
+pub const STALE_AFTER: f64 = 3.0;
+const MAX_SNAPSHOTS: usize = 32;
+/// Clock offset samples further than this from the current estimate replace it outright.
+const CLOCK_RESYNC_THRESHOLD: f64 = 1.0;
+const CLOCK_SMOOTHING: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    /// Server time in seconds.
+    pub time: f64,
+    pub position: (f32, f32, f32),
+    pub yaw: f32,
+}
+
+fn blend(a: &Snapshot, b: &Snapshot, t: f32) -> ((f32, f32, f32), f32) {
+    (lerp_vec3(a.position, b.position, t), lerp_angle(a.yaw, b.yaw, t))
+}
+
+/// Recent snapshots of one remote player, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    /// Snapshots that are not newer than the last one are dropped.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.back().is_some_and(|last| snapshot.time <= last.time) {
+            return;
+        }
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Whether the newest snapshot is more than `STALE_AFTER` older than `server_time`.
+    /// An empty buffer has not started yet and is never stale.
+    pub fn is_stale(&self, server_time: f64) -> bool {
+        self.snapshots.back().is_some_and(|last| server_time - last.time > STALE_AFTER)
+    }
+
+    /// Position and yaw at `render_time` (server seconds). Between snapshots this
+    /// interpolates; past the newest one it extrapolates along the last movement for at
+    /// most `MAX_EXTRAPOLATION` and then holds still.
+    pub fn sample(&self, render_time: f64) -> Option<((f32, f32, f32), f32)> {
+        let first = self.snapshots.front()?;
+        let last = self.snapshots.back()?;
+        if render_time <= first.time {
+            return Some((first.position, first.yaw));
+        }
+        if render_time >= last.time {
+            let Some(before_last) = self.snapshots.len().checked_sub(2).map(|i| &self.snapshots[i]) else {
+                return Some((last.position, last.yaw));
+            };
+            let ahead = (render_time - last.time).min(MAX_EXTRAPOLATION);
+            let t = 1.0 + ahead / (last.time - before_last.time);
+            return Some(blend(before_last, last, t as f32));
+        }
+
+        // Newest snapshot at or before render_time, and the one after it.
+        let after = self.snapshots.partition_point(|s| s.time <= render_time);
+        let (a, b) = (&self.snapshots[after - 1], &self.snapshots[after]);
+        let t = (render_time - a.time) / (b.time - a.time);
+        Some(blend(a, b, t as f32))
+    }
+}
+
+/// Estimates the server clock from snapshot timestamps, smoothing out network jitter.
+#[derive(Debug, Clone, Default)]
+pub struct ServerClock {
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    pub fn observe(&mut self, server_time: f64, local_time: f64) {
+        let sample = server_time - local_time;
+        self.offset = Some(match self.offset {
+            Some(offset) if (sample - offset).abs() < CLOCK_RESYNC_THRESHOLD => {
+                offset + (sample - offset) * CLOCK_SMOOTHING
+            }
+            _ => sample,
+        });
+    }
+
+    pub fn server_time(&self, local_time: f64) -> Option<f64> {
+        self.offset.map(|offset| local_time + offset)
+    }
+
+    /// The time remote players should be drawn at right now.
+    pub fn render_time(&self, local_time: f64) -> Option<f64> {
+        self.server_time(local_time).map(|time| time - INTERPOLATION_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn snap(time: f64, x: f32, yaw: f32) -> Snapshot {
+        Snapshot { time, position: (x, 5.0, -x), yaw }
+    }
+
+    fn buffer(snapshots: &[Snapshot]) -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::default();
+        for snapshot in snapshots {
+            buffer.push(*snapshot);
+        }
+        buffer
+    }
+
+    #[test]
+    fn interpolates_between_surrounding_snapshots() {
+        let buffer = buffer(&[snap(1.0, 0.0, 0.0), snap(1.1, 10.0, 1.0), snap(1.2, 30.0, 1.0)]);
+        let (position, yaw) = buffer.sample(1.05).unwrap();
+        assert!((position.0 - 5.0).abs() < 1e-4);
+        assert!((position.2 + 5.0).abs() < 1e-4);
+        assert!((yaw - 0.5).abs() < 1e-4);
+        assert!((buffer.sample(1.15).unwrap().0.0 - 20.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn yaw_takes_the_short_way_around() {
+        let buffer = buffer(&[snap(0.0, 0.0, PI - 0.1), snap(1.0, 0.0, -PI + 0.1)]);
+        let (_, yaw) = buffer.sample(0.5).unwrap();
+        assert!((yaw.rem_euclid(2.0 * PI) - PI).abs() < 1e-4, "yaw {}", yaw);
+    }
+
+    #[test]
+    fn extrapolation_is_bounded() {
+        let buffer = buffer(&[snap(1.0, 0.0, 0.0), snap(1.1, 10.0, 0.0)]);
+        let (slightly_late, _) = buffer.sample(1.15).unwrap();
+        assert!((slightly_late.0 - 15.0).abs() < 1e-3);
+        let (very_late, _) = buffer.sample(60.0).unwrap();
+        let cap = 10.0 + 100.0 * MAX_EXTRAPOLATION as f32;
+        assert!((very_late.0 - cap).abs() < 1e-3);
+    }
+
+    #[test]
+    fn holds_the_edges_with_too_few_snapshots() {
+        let buffer = buffer(&[snap(1.0, 3.0, 0.2)]);
+        assert_eq!(buffer.sample(0.0).unwrap().0.0, 3.0);
+        assert_eq!(buffer.sample(2.0).unwrap().0.0, 3.0);
+        assert!(SnapshotBuffer::default().sample(1.0).is_none());
+    }
+
+    #[test]
+    fn drops_stale_and_duplicate_snapshots() {
+        let buffer = buffer(&[snap(1.0, 0.0, 0.0), snap(2.0, 10.0, 0.0), snap(1.5, 99.0, 0.0), snap(2.0, 99.0, 0.0)]);
+        assert_eq!(buffer.snapshots.back().unwrap().position.0, 10.0);
+        assert!((buffer.sample(1.5).unwrap().0.0 - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn goes_stale_after_the_last_snapshot() {
+        let buffer = buffer(&[snap(1.0, 0.0, 0.0), snap(2.0, 10.0, 0.0)]);
+        assert!(!buffer.is_stale(2.0 + STALE_AFTER));
+        assert!(buffer.is_stale(2.1 + STALE_AFTER));
+        assert!(!SnapshotBuffer::default().is_stale(1000.0));
+    }
+
+    #[test]
+    fn buffer_is_bounded() {
+        let snapshots: Vec<Snapshot> = (0..100).map(|i| snap(i as f64, i as f32, 0.0)).collect();
+        let buffer = buffer(&snapshots);
+        assert_eq!(buffer.snapshots.len(), MAX_SNAPSHOTS);
+        assert_eq!(buffer.snapshots.back().unwrap().time, 99.0);
+    }
+
+    #[test]
+    fn clock_smooths_jitter_and_resyncs_on_jumps() {
+        let mut clock = ServerClock::default();
+        assert!(clock.render_time(0.0).is_none());
+        clock.observe(100.0, 10.0);
+        assert_eq!(clock.server_time(10.0), Some(100.0));
+        clock.observe(100.2, 10.1);
+        let smoothed = clock.server_time(10.1).unwrap();
+        assert!(smoothed > 100.1 && smoothed < 100.2);
+        clock.observe(500.0, 11.0);
+        assert_eq!(clock.render_time(11.0), Some(500.0 - INTERPOLATION_DELAY));
+    }
+}
@@ -0,0 +1,20 @@
+//! Game logic shared by the client and the server: the protocol, terrain generation and
+//! collision, the character controller and the world rules. Nothing here depends on raylib,
+//! so the server and the tests build and run on machines without a display.
+
+pub mod bot;
+pub mod chunks;
+pub mod codec;
+pub mod config;
+pub mod editing;
+pub mod heightmap;
+pub mod interpolation;
+pub mod lod;
+pub mod math;
+pub mod movement;
+pub mod net;
+pub mod protocol;
+pub mod terrain;
+pub mod world;
+pub mod world_file;
+pub mod worldgen;
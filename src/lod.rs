@@ -0,0 +1,129 @@
+use crate::chunks::{CHUNK_CELLS, CHUNK_SIZE};
+use crate::terrain::{Heightfield, MeshData};
+
+/// Cells per mesh quad at each level of detail, finest first. Each divides `CHUNK_CELLS`.
+pub const LOD_STEPS: [usize; 4] = [1, 2, 4, 8];
+/// Furthest chunk distance from the player drawn at each level but the coarsest, which
+/// takes everything further out.
+const LOD_DISTANCES: [i32; 3] = [1, 3, 6];
+/// How far skirts reach below the lowest sample of their chunk, so even a flat chunk
+/// gets walls with some height to them.
+const SKIRT_DROP: f32 = 1.0;
+
+/// The level to draw a chunk `distance` chunks away from the player's at.
+pub fn level_for(distance: i32) -> usize {
+    LOD_DISTANCES.iter().position(|&furthest| distance <= furthest).unwrap_or(LOD_DISTANCES.len())
+}
+
+/// Chunks to keep loaded around the player to see `view_distance` world units away.
+pub fn radius_for(view_distance: f32) -> i32 {
+    (view_distance / CHUNK_SIZE).ceil() as i32
+}
+
+/// The mesh to draw a chunk with at `level`, skirts included, colored by `color` at each
+/// sample.
+///
+/// Neighbours drawn at different levels disagree about the heights along their shared
+/// edge between the coarser one's samples, leaving cracks. Every skirt reaches below the
+/// lowest sample of its chunk, and the shared edge samples are the same on both sides,
+/// so whichever edge is higher hangs a skirt over the gap.
+pub fn chunk_mesh(heights: &Heightfield, level: usize, color: impl Fn(usize, usize) -> [u8; 4]) -> MeshData {
+    let step = LOD_STEPS[level];
+    debug_assert_eq!(heights.samples(), (CHUNK_CELLS + 1, CHUNK_CELLS + 1));
+    let mut mesh = heights.mesh(step, &color);
+    heights.add_skirts(&mut mesh, step, heights.lowest() - SKIRT_DROP, &color);
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::ChunkCoord;
+    use crate::worldgen::{Generator, TerrainPreset, WorldParams};
+
+    fn mountains() -> Generator {
+        Generator::new(WorldParams::new(11, TerrainPreset::Mountains))
+    }
+
+    /// (x or z along the edge, height) for every vertex of `mesh` on the plane `axis` = `at`.
+    fn edge_profile(mesh: &MeshData, axis: usize, at: f32) -> Vec<(f32, f32)> {
+        mesh.positions.chunks(3).filter(|p| p[axis] == at).map(|p| (p[2 - axis], p[1])).collect()
+    }
+
+    /// Height of the highest `profile` vertex at `along`, interpolating between the
+    /// nearest vertices either side.
+    fn surface_along(profile: &[(f32, f32)], along: f32) -> f32 {
+        let mut top: Vec<(f32, f32)> = Vec::new();
+        for &(position, height) in profile {
+            match top.iter_mut().find(|(p, _)| *p == position) {
+                Some(existing) => existing.1 = existing.1.max(height),
+                None => top.push((position, height)),
+            }
+        }
+        top.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let right = top.iter().position(|&(p, _)| p >= along).unwrap();
+        if top[right].0 == along {
+            return top[right].1;
+        }
+        let ((p0, h0), (p1, h1)) = (top[right - 1], top[right]);
+        h0 + (h1 - h0) * (along - p0) / (p1 - p0)
+    }
+
+    #[test]
+    fn nearby_chunks_get_the_finest_meshes() {
+        let levels: Vec<usize> = (0..=8).map(level_for).collect();
+        assert_eq!(levels, [0, 0, 1, 1, 2, 2, 2, 3, 3]);
+        assert_eq!(level_for(1000), LOD_STEPS.len() - 1);
+        for step in LOD_STEPS {
+            assert!(CHUNK_CELLS.is_multiple_of(step));
+        }
+    }
+
+    #[test]
+    fn view_distance_covers_whole_chunks() {
+        assert_eq!(radius_for(CHUNK_SIZE), 1);
+        assert_eq!(radius_for(CHUNK_SIZE + 1.0), 2);
+        assert_eq!(radius_for(500.0), 4);
+    }
+
+    #[test]
+    fn coarser_levels_have_fewer_triangles() {
+        let generator = mountains();
+        let heights = generator.chunk(ChunkCoord { x: 0, z: 0 });
+        let counts: Vec<usize> =
+            (0..LOD_STEPS.len()).map(|level| chunk_mesh(&heights, level, |_, _| [255; 4]).vertex_count()).collect();
+        for (level, count) in counts.iter().enumerate() {
+            let quads = CHUNK_CELLS / LOD_STEPS[level];
+            assert_eq!(*count, quads * quads * 6 + 4 * quads * 6);
+        }
+        assert!(counts.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", counts);
+    }
+
+    #[test]
+    fn skirts_close_the_crack_between_levels() {
+        let generator = mountains();
+        let (west, east) = (ChunkCoord { x: 2, z: 1 }, ChunkCoord { x: 3, z: 1 });
+        let fine = chunk_mesh(&generator.chunk(west), 0, |_, _| [255; 4]);
+        let coarse = chunk_mesh(&generator.chunk(east), LOD_STEPS.len() - 1, |_, _| [255; 4]);
+        // The fine chunk's east edge is the coarse chunk's west edge.
+        let (fine_edge, coarse_edge) = (edge_profile(&fine, 0, CHUNK_SIZE), edge_profile(&coarse, 0, 0.0));
+        let lowest = |profile: &[(f32, f32)]| profile.iter().map(|&(_, h)| h).fold(f32::INFINITY, f32::min);
+
+        let mut gaps = 0;
+        for cell in 0..=CHUNK_CELLS {
+            let along = cell as f32 * CHUNK_SIZE / CHUNK_CELLS as f32;
+            let (fine_top, coarse_top) = (surface_along(&fine_edge, along), surface_along(&coarse_edge, along));
+            if fine_top != coarse_top {
+                gaps += 1;
+            }
+            // Whichever surface is higher has a skirt reaching down past the other one.
+            let (high, low, skirt_bottom) = if fine_top > coarse_top {
+                (fine_top, coarse_top, lowest(&fine_edge))
+            } else {
+                (coarse_top, fine_top, lowest(&coarse_edge))
+            };
+            assert!(skirt_bottom < low && low <= high, "crack at {}: {} over {}", along, high, low);
+        }
+        assert!(gaps > 0, "the levels should disagree somewhere along rough terrain");
+    }
+}
@@ -1,293 +1,268 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::CString;
 use std::sync::Arc;
-use rand::Rng;
 
-use tokio::sync::{mpsc, Mutex as TokioMutex};
+use tokio::sync::{Mutex as TokioMutex, broadcast, mpsc};
 
 use raylib::consts::MouseButton;
-use raylib::ffi::{Color, ColorFromHSV, IsKeyDown, IsKeyReleased, KeyboardKey};
+use raylib::ffi::{ColorFromHSV, IsKeyDown, KeyboardKey};
 use raylib::{
-    consts::MaterialMapIndex::*,
-    core::math::*, // RaylibThread is in prelude, texture::* also generally covered
-    ffi::{
-        DrawModel, DrawModelEx, GenImagePerlinNoise, GenMeshHeightmap, LoadModel,
-        LoadModelFromMesh, LoadTextureFromImage, SetConfigFlags, UnloadImage,
-    },
-    prelude::*, // Imports RaylibThread
+    core::math::*,
+    ffi::{DrawModelEx, LoadModel, SetConfigFlags, rlSetClipPlanes},
+    prelude::*,
 };
 
-use serde::{Deserialize, Serialize};
+use clap::Parser;
 
-use futures_util::{SinkExt, StreamExt};
-// Use the specific version of tokio-tungstenite the compiler is using if known, or a recent one
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
-use url::Url; // Keep this if you still want to parse URLs, but connect_async will take &str
+mod terrain_mesh;
 
+use three_dee::chunks::{CHUNK_SIZE, ChunkCoord, ChunkManager, GeneratedChunk, StreamRequest, UNLOAD_MARGIN};
+use three_dee::config::{Cli, Config};
+use three_dee::editing::{BRUSH_RADIUS_RANGE, Brush, EDIT_REACH, HeightPatch, TerrainEdit, raycast};
+use three_dee::interpolation::{ServerClock, Snapshot, SnapshotBuffer};
+use three_dee::math::{Basis, Quat};
+use three_dee::movement::{Ground, MoveInput, PLAYER_HEIGHT, Predictor};
+use three_dee::net::{ConnectionStatus, InputBatcher, NetEvent};
+use three_dee::protocol::{PlayerState, ServerMessage};
+use three_dee::worldgen::WorldParams;
+use three_dee::{chunks, lod, net, worldgen};
 
-// --- WebSocket and Game State Structures ---
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct PlayerState {
-    id: String,
-    position: (f32, f32, f32),
-    rotation: (f32, f32, f32),
-}
+use terrain_mesh::ChunkModels;
 
+// --- WebSocket and Game State Structures ---
 struct GameState {
+    connection_status: ConnectionStatus,
     local_player_id: Option<String>,
     other_players: HashMap<String, PlayerState>,
+    remote_snapshots: HashMap<String, SnapshotBuffer>,
+    server_clock: ServerClock,
+    predictor: Predictor,
+    /// The world we should be standing on; the main loop rebuilds the terrain when it changes.
+    world: WorldParams,
+    /// The edit log from the last welcome, until the main loop lays it over the terrain.
+    edit_log: Option<Vec<HeightPatch>>,
+    /// Patches the server has sent since the main loop last looked, oldest first.
+    new_patches: Vec<HeightPatch>,
+    /// Whether the server lets us fly; F does nothing otherwise.
+    allow_fly: bool,
     join_messages: VecDeque<String>,
 }
 
 const MAX_JOIN_MESSAGES: usize = 5;
-const FPS: u32 = 60;
-const NOISE_SIZE: raylib::ffi::Vector2 = raylib::ffi::Vector2 { x: 128.0, y: 128.0 };
-const MAP_SIZE: raylib::ffi::Vector2 = raylib::ffi::Vector2 { x: 500.0, y: 500.0 };
-const MAP_SCALE: f32 = MAP_SIZE.x * 0.05;
-const PLAYER_HEIGHT: f32 = 5.0;
-
-fn real_vec3_add(v1: Vector3, v2: Vector3) -> Vector3 {
-    Vector3 { x: v1.x + v2.x, y: v1.y + v2.y, z: v1.z + v2.z }
-}
-
-fn real_vec3_sub(v1: Vector3, v2: Vector3) -> Vector3 {
-    Vector3 { x: v1.x - v2.x, y: v1.y - v2.y, z: v1.z - v2.z }
-}
-
-fn get_closest_vertex_height(
-    world_pos: Vector3,
-    terrain_origin: Vector3,
-    vertices: &[Vector3],
-    width: usize,
-    depth: usize,
-) -> Option<f32> {
-    if width == 0 || depth == 0 || vertices.is_empty() {
-        return None;
+/// The sea surface, seen from above.
+const WATER_COLOR: raylib::prelude::Color = raylib::prelude::Color::new(30, 90, 160, 150);
+/// Laid over the whole screen while the camera is under water.
+const UNDERWATER_TINT: raylib::prelude::Color = raylib::prelude::Color::new(10, 50, 110, 140);
+/// Brush size when the client starts, and how much one notch of the mouse wheel changes it.
+const START_BRUSH_RADIUS: f32 = 10.0;
+const BRUSH_RADIUS_STEP: f32 = 2.0;
+/// Strength of every stroke; holding the button down keeps painting.
+const BRUSH_STRENGTH: f32 = 0.25;
+/// Seconds between strokes while the button is held.
+const STROKE_INTERVAL: f64 = 0.1;
+const BRUSH_COLOR: raylib::prelude::Color = raylib::prelude::Color::new(255, 240, 80, 255);
+
+fn push_feed_message(gs: &mut GameState, msg: String) {
+    println!("CLIENT: {}", msg);
+    gs.join_messages.push_back(msg);
+    if gs.join_messages.len() > MAX_JOIN_MESSAGES {
+        gs.join_messages.pop_front();
     }
-    let grid_cell_width = MAP_SIZE.x / (width.saturating_sub(1) as f32).max(1.0);
-    let grid_cell_depth = MAP_SIZE.y / (depth.saturating_sub(1) as f32).max(1.0);
-
-    let local_x_float = (world_pos.x - terrain_origin.x) / grid_cell_width;
-    let local_z_float = (world_pos.z - terrain_origin.z) / grid_cell_depth;
+}
 
-    let local_x = local_x_float.round() as usize;
-    let local_z = local_z_float.round() as usize;
+fn apply_snapshot(
+    gs: &mut GameState,
+    server_time_ms: u64,
+    all_states_update: Vec<PlayerState>,
+    local_time: f64,
+    ground: &impl Ground,
+) {
+    let Some(local_id) = gs.local_player_id.clone() else { return };
+    let server_time = server_time_ms as f64 / 1000.0;
+    gs.server_clock.observe(server_time, local_time);
 
-    if local_x >= width || local_z >= depth {
-        return None;
+    for state in all_states_update {
+        if state.id == local_id {
+            gs.predictor.reconcile(state.last_input_seq, state.position, ground);
+        } else {
+            if !gs.other_players.contains_key(&state.id) {
+                let join_msg = format!("Player {} joined", state.display_name());
+                push_feed_message(gs, join_msg);
+            }
+            gs.remote_snapshots.entry(state.id.clone()).or_default().push(Snapshot {
+                time: server_time,
+                position: state.position,
+                yaw: state.rotation.1,
+            });
+            gs.other_players.insert(state.id.clone(), state);
+        }
     }
+}
 
-    let index = local_z * width + local_x;
-    if index < vertices.len() {
-        Some(vertices[index].y + terrain_origin.y)
-    } else {
-        None
+/// Forgets a remote player and says so in the feed, unless they were already gone.
+fn despawn_player(gs: &mut GameState, id: &str, how: &str) {
+    gs.remote_snapshots.remove(id);
+    if let Some(state) = gs.other_players.remove(id) {
+        push_feed_message(gs, format!("Player {} {}", state.display_name(), how));
     }
 }
 
-fn check_collision(
-    position: Vector3,
-    terrain_origin: Vector3,
-    vertices: &[Vector3],
-    width: usize,
-    depth: usize,
-) -> bool {
-    if let Some(ground_height) =
-        get_closest_vertex_height(position, terrain_origin, vertices, width, depth)
-    {
-        position.y < ground_height + PLAYER_HEIGHT
-    } else {
-        false
+/// Drops remote players the server has not mentioned for `STALE_AFTER`, whether they
+/// vanished without a leave message or the server stopped sending altogether.
+fn despawn_stale_players(gs: &mut GameState, local_time: f64) {
+    let Some(server_time) = gs.server_clock.server_time(local_time) else { return };
+    let stale: Vec<String> = gs
+        .remote_snapshots
+        .iter()
+        .filter(|(_, buffer)| buffer.is_stale(server_time))
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in stale {
+        despawn_player(gs, &id, "timed out");
     }
 }
 
-fn adjust_position(
-    position: Vector3,
-    terrain_origin: Vector3,
-    vertices: &[Vector3],
-    width: usize,
-    depth: usize,
-) -> Vector3 {
-    let mut adjusted_pos = position;
-    if let Some(ground_height) =
-        get_closest_vertex_height(position, terrain_origin, vertices, width, depth)
-    {
-        if position.y < ground_height + PLAYER_HEIGHT {
-            adjusted_pos.y = ground_height + PLAYER_HEIGHT;
+/// `local_time` is the client clock in seconds, used to line snapshots up with frames.
+fn handle_net_event(gs: &mut GameState, event: NetEvent, local_time: f64, ground: &impl Ground) {
+    match event {
+        NetEvent::Status(status) => {
+            if status != ConnectionStatus::Connected {
+                // Until the next welcome we have no id and no idea where anyone else is.
+                gs.local_player_id = None;
+                gs.other_players.clear();
+                gs.remote_snapshots.clear();
+                gs.server_clock = ServerClock::default();
+            }
+            gs.connection_status = status;
+        }
+        NetEvent::Message(ServerMessage::Welcome(welcome)) => {
+            println!(
+                "CLIENT: Server v{} assigned my ID: {} ({} Hz)",
+                welcome.server_version, welcome.your_id, welcome.tick_rate
+            );
+            gs.local_player_id = Some(welcome.your_id);
+            gs.predictor.reset(welcome.spawn);
+            gs.predictor.set_bounds(welcome.bounds);
+            gs.world = welcome.world;
+            // Patches from before this welcome are either in its log or from another server.
+            gs.edit_log = Some(welcome.edits);
+            gs.new_patches.clear();
+            gs.allow_fly = welcome.allow_fly;
         }
+        NetEvent::Message(ServerMessage::TerrainPatch(patch)) => gs.new_patches.push(patch),
+        NetEvent::Message(ServerMessage::Snapshot { server_time_ms, players }) => {
+            apply_snapshot(gs, server_time_ms, players, local_time, ground)
+        }
+        NetEvent::Message(ServerMessage::Chat { from, text }) => {
+            let sender = match gs.other_players.get(&from) {
+                Some(state) => state.display_name(),
+                None => format!("{}...", from.chars().take(6).collect::<String>()),
+            };
+            push_feed_message(gs, format!("{}: {}", sender, text));
+        }
+        NetEvent::Message(ServerMessage::PlayerLeft { id }) => despawn_player(gs, &id, "left"),
+        // Names already arrive inside the player states.
+        NetEvent::Message(ServerMessage::Roster { .. } | ServerMessage::Pong { .. } | ServerMessage::Unknown) => {}
     }
-    adjusted_pos
 }
 
-fn get_movement_vector(rl: &RaylibHandle, camera: &Camera3D, current_yaw: f32) -> Vector3 {
-    let mut move_dir = Vector3::zero();
-    let mut forward = camera.target - camera.position;
-    forward.y = 0.0;
-
-    if forward.length() < 0.0001 { 
-        forward = Vector3::new(current_yaw.sin(), 0.0, current_yaw.cos() * -1.0);
-    }
-    // Only normalize if length is not zero, to avoid NaN issues with Vector3::zero().normalize()
-    if forward.length() > 0.0001 { // Check again after potential modification
-        Vector3::normalize(&mut forward);
-    }
-
+fn key_axis(rl: &RaylibHandle, positive: KeyboardKey, negative: KeyboardKey) -> i8 {
+    rl.is_key_down(positive) as i8 - rl.is_key_down(negative) as i8
+}
 
-    let mut right = forward.cross(Vector3::up());
-    if right.length() > 0.0001 { // Check before normalizing
-       Vector3::normalize(&mut right);
+/// This frame's keys and view angles; `Predictor::apply` fills in the sequence number
+/// and frame time. SPACE jumps, or climbs while flying, where LEFT_SHIFT sinks.
+fn read_move_input(rl: &RaylibHandle, yaw: f32, pitch: f32, fly: bool) -> MoveInput {
+    MoveInput {
+        forward: key_axis(rl, KeyboardKey::KEY_W, KeyboardKey::KEY_S),
+        right: key_axis(rl, KeyboardKey::KEY_D, KeyboardKey::KEY_A),
+        up: key_axis(rl, KeyboardKey::KEY_SPACE, KeyboardKey::KEY_LEFT_SHIFT),
+        yaw,
+        pitch,
+        fly,
+        ..MoveInput::default()
     }
+}
 
-
-    if rl.is_key_down(KeyboardKey::KEY_W) {
-        move_dir = real_vec3_add(move_dir, forward);
-    }
-    if rl.is_key_down(KeyboardKey::KEY_S) {
-        move_dir = real_vec3_sub(move_dir, forward);
-    }
-    if rl.is_key_down(KeyboardKey::KEY_D) {
-        move_dir = real_vec3_add(move_dir, right);
-    }
-    if rl.is_key_down(KeyboardKey::KEY_A) {
-        move_dir = real_vec3_sub(move_dir, right);
+/// Lays the edits the server has sent since the last frame over the terrain and rebuilds
+/// the models of the chunks they changed.
+fn apply_terrain_edits(gs: &mut GameState, terrain: &mut ChunkManager, models: &mut ChunkModels) {
+    let mut changed = HashSet::new();
+    if let Some(log) = gs.edit_log.take() {
+        changed.extend(terrain.replace_edits(log));
     }
-    if rl.is_key_down(KeyboardKey::KEY_SPACE) {
-        move_dir.y += 1.0;
+    for patch in gs.new_patches.drain(..) {
+        changed.extend(terrain.apply_patch(patch));
     }
-    if rl.is_key_down(KeyboardKey::KEY_LEFT_SHIFT) {
-        move_dir.y -= 1.0;
-    }
-
-    if move_dir.length() > 0.0001 {
-        Vector3::normalize(&mut move_dir);
+    for coord in changed {
+        if let Some(heights) = terrain.chunk(coord) {
+            models.refresh(coord, heights);
+        }
     }
-    move_dir
 }
 
-async fn connect_and_manage_websocket(
-    mut local_player_state_rx: mpsc::UnboundedReceiver<PlayerState>,
-    server_updates_tx: mpsc::UnboundedSender<Vec<PlayerState>>,
-    player_id_confirmation_tx: mpsc::UnboundedSender<String>,
-    game_state_accessor: Arc<TokioMutex<GameState>>,
+/// Points the chunk streamer at the player, uploads whatever it has generated since the
+/// last frame, drops chunks left behind and redraws chunks whose level of detail changed.
+/// The ground right around the player is generated on the spot if the streamer has not
+/// got there yet, as prediction can't wait.
+fn stream_terrain(
+    terrain: &mut ChunkManager,
+    models: &mut ChunkModels,
+    requests: &mpsc::UnboundedSender<StreamRequest>,
+    generated: &mut mpsc::UnboundedReceiver<GeneratedChunk>,
+    last_request: &mut Option<StreamRequest>,
+    position: (f32, f32, f32),
+    radius: i32,
 ) {
-    let url_str = "ws://127.0.0.1:8080/ws"; // Use &str directly
-
-    match connect_async(url_str).await { // CORRECTED: Pass &str
-        Ok((ws_stream, _)) => {
-            println!("CLIENT: Successfully connected to WebSocket server.");
-            let (mut write, mut read) = ws_stream.split();
-
-            let send_task = tokio::spawn(async move {
-                while let Some(player_state) = local_player_state_rx.recv().await {
-                    if let Ok(json_state) = serde_json::to_string(&player_state) {
-                        // CORRECTED: Use .into() for WsMessage::Text
-                        if write.send(WsMessage::Text(json_state.into())).await.is_err() {
-                            eprintln!("CLIENT: Failed to send player state to server.");
-                            break;
-                        }
-                    }
-                }
-                println!("CLIENT: Send task finished.");
-            });
-
-            let receive_task = tokio::spawn(async move {
-                let mut local_id_determined = false;
-                loop {
-                    tokio::select! {
-                        Some(msg_result) = read.next() => {
-                            match msg_result {
-                                Ok(WsMessage::Text(text)) => {
-                                    if let Ok(all_player_states) = serde_json::from_str::<Vec<PlayerState>>(&text) {
-                                        if !local_id_determined {
-                                            let mut gs_lock = game_state_accessor.lock().await;
-                                            if gs_lock.local_player_id.is_none() {
-                                                for r_state in &all_player_states {
-                                                    let is_initial_pos = r_state.position.0.abs() < 0.1 &&
-                                                                           (r_state.position.1 - 5.0).abs() < 0.1 &&
-                                                                           r_state.position.2.abs() < 0.1;
-                                                    if !gs_lock.other_players.contains_key(&r_state.id) {
-                                                        if is_initial_pos {
-                                                            println!("CLIENT: Deduced my ID by initial position: {}", r_state.id);
-                                                            gs_lock.local_player_id = Some(r_state.id.clone());
-                                                            let _ = player_id_confirmation_tx.send(r_state.id.clone());
-                                                            local_id_determined = true;
-                                                            break; 
-                                                        }
-                                                        if gs_lock.local_player_id.is_none() { 
-                                                            println!("CLIENT: Tentatively deduced my ID (new unknown): {}", r_state.id);
-                                                            gs_lock.local_player_id = Some(r_state.id.clone());
-                                                            let _ = player_id_confirmation_tx.send(r_state.id.clone());
-                                                        }
-                                                    }
-                                                }
-                                                if gs_lock.local_player_id.is_some() {
-                                                    local_id_determined = true;
-                                                } else if all_player_states.len() == 1 {
-                                                    println!("CLIENT: Deduced my ID (only player): {}", all_player_states[0].id);
-                                                    gs_lock.local_player_id = Some(all_player_states[0].id.clone());
-                                                    let _ = player_id_confirmation_tx.send(all_player_states[0].id.clone());
-                                                    local_id_determined = true;
-                                                }
-                                            } else {
-                                                local_id_determined = true;
-                                            }
-                                        }
-
-                                        if local_id_determined {
-                                            if server_updates_tx.send(all_player_states.clone()).is_err() {
-                                                eprintln!("CLIENT: Receiver for server updates dropped.");
-                                                break; 
-                                            }
-                                        } else {
-                                             println!("CLIENT: Waiting to determine local player ID from server broadcast...");
-                                        }
-                                    } else {
-                                        eprintln!("CLIENT: Failed to parse server message into Vec<PlayerState>: {}", text);
-                                    }
-                                }
-                                Ok(WsMessage::Close(_)) => {
-                                    println!("CLIENT: WebSocket connection closed by server.");
-                                    break; 
-                                }
-                                Err(e) => {
-                                    eprintln!("CLIENT: WebSocket read error: {}", e);
-                                    break; 
-                                }
-                                _ => { /* Ignore other message types */ }
-                            }
-                        }
-                        else => { 
-                            println!("CLIENT: WebSocket read stream ended.");
-                            break;
-                        }
-                    }
-                }
-                println!("CLIENT: Receive task finished.");
-            });
-
-            tokio::select! {
-                _ = send_task => {},
-                _ = receive_task => {},
+    let request =
+        StreamRequest { world: terrain.world(), center: ChunkCoord::containing(position.0, position.2), radius };
+    let keep = |coord: ChunkCoord| coord.worth_keeping(request.center, request.radius);
+    if *last_request != Some(request) {
+        let _ = requests.send(request);
+        *last_request = Some(request);
+        terrain.retain(keep);
+        models.retain(keep);
+        models.recenter(request.center);
+    }
+    while let Ok(chunk) = generated.try_recv() {
+        if chunk.world == terrain.world() && keep(chunk.coord) {
+            // The terrain lays the edits over the chunk, so the model is built from its copy.
+            terrain.insert(chunk.coord, chunk.heights);
+            if let Some(heights) = terrain.chunk(chunk.coord) {
+                models.insert(chunk.coord, heights, &chunk.biomes);
             }
-            println!("CLIENT: WebSocket connection handler finished.");
-        }
-        Err(e) => {
-            eprintln!("CLIENT: Failed to connect to WebSocket: {}", e);
         }
     }
+    terrain.load_around(position.0, position.2, 1);
 }
 
 #[tokio::main]
 async fn main() {
-    unsafe { SetConfigFlags(ConfigFlags::FLAG_WINDOW_RESIZABLE as u32) };
-    let (mut window_x, mut window_y) = (1920, 1080);
-    let (mut rl, thread) = raylib::init()
-        .size(window_x, window_y)
-        .title("Multiplayer Client")
-        .build();
+    let config = match Config::load(Cli::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("CLIENT: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // Already checked by Config::load.
+    let server_url = config.server_url().expect("validated server URL");
 
+    unsafe { SetConfigFlags(ConfigFlags::FLAG_WINDOW_RESIZABLE as u32) };
+    let (mut window_x, mut window_y) = (config.window_width, config.window_height);
+    let (mut rl, thread) = raylib::init().size(window_x, window_y).title("Multiplayer Client").build();
+
+    // Draw everything streamed in, including the diagonal corners of the loaded square
+    // and chunks kept past the view distance, and never less than raylib's default so
+    // short view distances still show the sun.
+    let view_radius = lod::radius_for(config.view_distance);
+    let far_plane = ((view_radius + UNLOAD_MARGIN + 1) as f32 * CHUNK_SIZE * std::f32::consts::SQRT_2).max(1000.0);
+    unsafe { rlSetClipPlanes(0.01, far_plane as f64) };
+
+    // Until the server says otherwise we start where it usually spawns players.
+    let start_position = (-250.0, PLAYER_HEIGHT + 20.0, -250.0);
     let mut camera = Camera3D::perspective(
-        Vector3 { x: -250.0, y: PLAYER_HEIGHT + 20.0, z: -250.0 },
+        Vector3 { x: start_position.0, y: start_position.1, z: start_position.2 },
         Vector3 { x: -100.0, y: PLAYER_HEIGHT + 12.0, z: -100.0 },
         Vector3::up(),
         45.0,
@@ -295,233 +270,261 @@ async fn main() {
     let mut yaw: f32 = 0.0;
     let mut pitch: f32 = 0.0;
 
-    rl.set_target_fps(FPS);
+    rl.set_target_fps(config.fps);
+
+    // Until a server says otherwise, the world has no edge.
+    let predictor = Predictor::new(start_position);
 
     let game_state = Arc::new(TokioMutex::new(GameState {
+        connection_status: ConnectionStatus::Connecting,
         local_player_id: None,
         other_players: HashMap::new(),
+        remote_snapshots: HashMap::new(),
+        server_clock: ServerClock::default(),
+        predictor,
+        world: worldgen::DEFAULT_WORLD,
+        edit_log: None,
+        new_patches: Vec::new(),
+        allow_fly: false,
         join_messages: VecDeque::with_capacity(MAX_JOIN_MESSAGES + 1),
     }));
 
-    let (local_update_tx, local_update_rx) = mpsc::unbounded_channel::<PlayerState>();
-    let (server_update_tx, mut server_update_rx) = mpsc::unbounded_channel::<Vec<PlayerState>>();
-    let (player_id_confirmation_tx, mut player_id_confirmation_rx) = mpsc::unbounded_channel::<String>();
-
-    let game_state_clone_ws = game_state.clone();
-    tokio::spawn(connect_and_manage_websocket(
-        local_update_rx,
-        server_update_tx,
-        player_id_confirmation_tx,
-        game_state_clone_ws,
+    let (local_input_tx, local_input_rx) = broadcast::channel::<Vec<MoveInput>>(net::INPUT_QUEUE_CAPACITY);
+    let (edits_tx, edits_rx) = mpsc::unbounded_channel::<TerrainEdit>();
+    let (net_event_tx, mut net_event_rx) = mpsc::unbounded_channel::<NetEvent>();
+
+    tokio::spawn(net::run_connection_supervisor(
+        server_url,
+        config.player_name.clone(),
+        config.codec,
+        local_input_rx,
+        edits_rx,
+        net_event_tx,
+        Arc::default(),
     ));
 
     let player_model_path = CString::new("./src/Soldier1.glb").expect("CString for player model failed");
-    let mut player_model: raylib::ffi::Model;
-
-    let noise_image: raylib::ffi::Image;
-    let noise_texture: raylib::ffi::Texture2D;
-    let mut terrain_mesh: raylib::ffi::Mesh;
-    let mut terrain_model: raylib::ffi::Model;
-    let terrain_vertices_vec: Vec<Vector3>;
+    let player_model: raylib::ffi::Model;
 
     unsafe {
         player_model = LoadModel(player_model_path.as_ptr());
         if player_model.meshCount == 0 {
             eprintln!("CLIENT: Failed to load player model!");
         }
-
-        noise_image = GenImagePerlinNoise(NOISE_SIZE.x as i32, NOISE_SIZE.y as i32, 0, 0, MAP_SCALE);
-        noise_texture = LoadTextureFromImage(noise_image);
-        terrain_mesh = GenMeshHeightmap(noise_image, raylib::ffi::Vector3{ x: MAP_SIZE.x, y: MAP_SCALE, z: MAP_SIZE.y });
-        terrain_model = LoadModelFromMesh(terrain_mesh);
-        
-        if terrain_model.materialCount > 0 && !terrain_model.materials.is_null() {
-             if !(*terrain_model.materials.add(0)).maps.is_null() {
-                (*(*terrain_model.materials.add(0)).maps.wrapping_add(MATERIAL_MAP_ALBEDO as usize)).texture = noise_texture;
-            }
-        }
-
-        if terrain_model.meshCount > 0 && !terrain_model.meshes.is_null() { // Check mesh pointer
-            let raw_vertices_ptr = (*terrain_model.meshes).vertices; 
-            let vertex_count = (*terrain_model.meshes).vertexCount as usize;
-            if !raw_vertices_ptr.is_null() && vertex_count > 0 { // Check vertices pointer
-                let vert_slice: &[f32] = std::slice::from_raw_parts(raw_vertices_ptr, vertex_count * 3);
-                
-                let mut temp_verts = Vec::with_capacity(vertex_count);
-                for i in 0..vertex_count {
-                    temp_verts.push(Vector3::new(
-                        vert_slice[i * 3],
-                        vert_slice[i * 3 + 1],
-                        vert_slice[i * 3 + 2],
-                    ));
-                }
-                terrain_vertices_vec = temp_verts;
-            } else {
-                eprintln!("CLIENT: Terrain mesh vertices are null or count is zero.");
-                terrain_vertices_vec = Vec::new(); // Initialize to empty to prevent crash
-            }
-        } else {
-            eprintln!("CLIENT: Terrain model has no meshes or mesh pointer is null.");
-            terrain_vertices_vec = Vec::new(); // Initialize to empty
-        }
-        UnloadImage(noise_image);
     }
-    let terrain_position = raylib::ffi::Vector3 {x: -MAP_SIZE.x, y: 0.0, z: -MAP_SIZE.y};
-    let terrain_color_val: raylib::ffi::Color = unsafe { ColorFromHSV(130.0, 1.0, 1.0) };
 
+    // Collision and drawing share the streamed-in chunks, which start over whenever a
+    // server describes another world.
+    let mut terrain = ChunkManager::new(worldgen::DEFAULT_WORLD);
+    let mut chunk_models = ChunkModels::default();
+    let (stream_tx, stream_rx) = mpsc::unbounded_channel::<StreamRequest>();
+    let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel::<GeneratedChunk>();
+    tokio::spawn(chunks::run_streamer(stream_rx, chunk_tx));
+    let mut last_stream_request: Option<StreamRequest> = None;
+
+    let mut input_batcher = InputBatcher::new(config.send_rate);
+    let mut fly = false;
+    // E switches to sculpting: the left button paints with the brush under the crosshair,
+    // B picks the next brush and the mouse wheel resizes it.
+    let mut editing = false;
+    let mut brush = Brush::default();
+    let mut brush_radius = START_BRUSH_RADIUS;
+    let mut next_stroke = 0.0;
 
     rl.disable_cursor();
-    let mut player_speed = 50.0;
 
     while !rl.window_should_close() {
         let dt = rl.get_frame_time();
+        let now = rl.get_time();
 
-        if rl.is_cursor_on_screen() && rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT) {
+        if rl.is_key_pressed(KeyboardKey::KEY_F) {
+            fly = !fly;
+        }
+        if rl.is_key_pressed(KeyboardKey::KEY_E) {
+            editing = !editing;
         }
-        if rl.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+        if editing {
+            if rl.is_key_pressed(KeyboardKey::KEY_B) {
+                brush = brush.next();
+            }
+            let wheel = rl.get_mouse_wheel_move();
+            brush_radius = (brush_radius + wheel * BRUSH_RADIUS_STEP)
+                .clamp(*BRUSH_RADIUS_RANGE.start(), *BRUSH_RADIUS_RANGE.end());
         }
-        
+
         if rl.is_cursor_hidden() {
             let mouse_delta = rl.get_mouse_delta();
-            let sensitivity = 0.003;
-            yaw += mouse_delta.x * sensitivity;
-            pitch -= mouse_delta.y * sensitivity;
+            yaw += mouse_delta.x * config.mouse_sensitivity;
+            pitch -= mouse_delta.y * config.mouse_sensitivity;
 
             let pitch_limit = std::f32::consts::FRAC_PI_2 - 0.01;
             pitch = pitch.clamp(-pitch_limit, pitch_limit);
         }
-        
-        let camera_forward_vector = Vector3 {
-            x: pitch.cos() * yaw.sin(),
-            y: pitch.sin(),
-            z: pitch.cos() * yaw.cos() * -1.0,
-        };
-        let movement_input = get_movement_vector(&rl, &camera, yaw);
-        // player_speed += 10.0;
-        let desired_position = camera.position + movement_input * player_speed * dt;
-        
-        let mut new_position = desired_position;
-        let terrain_grid_width = NOISE_SIZE.x as usize;
-        let terrain_grid_depth = NOISE_SIZE.y as usize;
-
-        if !terrain_vertices_vec.is_empty() { // Only do collision if terrain vertices exist
-            if check_collision(desired_position, terrain_position.into(), &terrain_vertices_vec, terrain_grid_width, terrain_grid_depth) {
-                new_position = adjust_position(desired_position, terrain_position.into(), &terrain_vertices_vec, terrain_grid_width, terrain_grid_depth);
-                if movement_input.y < 0.0 {
-                     let mut horizontal_movement = movement_input;
-                     horizontal_movement.y = 0.0;
-                     if horizontal_movement.length() > 0.0001 {
-                        Vector3::normalize(&mut horizontal_movement);
-                     }
-                     let corrected_horizontal_pos = camera.position + horizontal_movement * player_speed * dt;
-                     new_position = adjust_position(corrected_horizontal_pos, terrain_position.into(), &terrain_vertices_vec, terrain_grid_width, terrain_grid_depth);
-                }
-            }
-        }
-        camera.position = new_position;
-        camera.target = camera.position + camera_forward_vector;
 
-
-        if let Ok(confirmed_id) = player_id_confirmation_rx.try_recv() {
-            let mut gs = game_state.lock().await;
-            if gs.local_player_id.is_none() {
-                println!("MAIN_LOOP: Received and set local player ID: {}", confirmed_id);
-                gs.local_player_id = Some(confirmed_id);
-            }
-        }
-
-        while let Ok(all_states_update) = server_update_rx.try_recv() {
+        let (forward_x, forward_y, forward_z) = Basis::view(yaw, pitch).forward;
+        let camera_forward_vector = Vector3::new(forward_x, forward_y, forward_z);
+        let swimming;
+        {
             let mut gs = game_state.lock().await;
-            let mut current_other_players = HashMap::new();
-            if let Some(local_id) = &gs.local_player_id.clone() {
-                for state in all_states_update {
-                    if &state.id != local_id {
-                        if !gs.other_players.contains_key(&state.id) {
-                            let join_msg = format!("Player {}... joined", state.id.chars().take(6).collect::<String>());
-                            println!("CLIENT: {}", join_msg);
-                            gs.join_messages.push_back(join_msg);
-                            if gs.join_messages.len() > MAX_JOIN_MESSAGES {
-                                gs.join_messages.pop_front();
-                            }
-                        }
-                        current_other_players.insert(state.id.clone(), state);
-                    }
+            while let Ok(event) = net_event_rx.try_recv() {
+                handle_net_event(&mut gs, event, now, &terrain);
+                if gs.world != terrain.world() {
+                    println!("CLIENT: Switching to {} terrain with seed {}", gs.world.preset, gs.world.seed);
+                    terrain = ChunkManager::new(gs.world);
+                    chunk_models.retain(|_| false);
                 }
-                gs.other_players = current_other_players;
             }
+            fly &= gs.allow_fly;
+            let move_input = read_move_input(&rl, yaw, pitch, fly);
+            apply_terrain_edits(&mut gs, &mut terrain, &mut chunk_models);
+            stream_terrain(
+                &mut terrain,
+                &mut chunk_models,
+                &stream_tx,
+                &mut chunk_rx,
+                &mut last_stream_request,
+                gs.predictor.position(),
+                view_radius,
+            );
+            despawn_stale_players(&mut gs, now);
+            // Move right away; the server gets the same input and corrects us if it disagrees.
+            // Falling and sliding are movement too, even with no keys held.
+            if input_batcher.wants(&move_input, gs.predictor.moves_by_itself(&terrain)) {
+                let applied = gs.predictor.apply(move_input, dt, &terrain);
+                input_batcher.queue(applied);
+            }
+            if let Some(batch) = input_batcher.take_due(now)
+                && gs.local_player_id.is_some()
+            {
+                let _ = local_input_tx.send(batch);
+            }
+            let (x, y, z) = gs.predictor.position();
+            camera.position = Vector3::new(x, y, z);
+            swimming = !fly && gs.predictor.is_swimming(&terrain);
         }
+        camera.target = camera.position + camera_forward_vector;
 
+        // Edits go to the server, and the terrain only changes once its patch comes back.
+        let brush_target = if editing {
+            let origin = (camera.position.x, camera.position.y, camera.position.z);
+            let direction = (camera_forward_vector.x, camera_forward_vector.y, camera_forward_vector.z);
+            raycast(&terrain, origin, direction, EDIT_REACH)
+        } else {
+            None
+        };
+        if let Some((x, _, z)) = brush_target
+            && rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT)
+            && now >= next_stroke
         {
-            let gs = game_state.lock().await;
-            if let Some(local_id) = &gs.local_player_id {
-                let local_player_state = PlayerState {
-                    id: local_id.clone(),
-                    position: (camera.position.x, camera.position.y, camera.position.z),
-                    rotation: (pitch, yaw, 0.0),
-                };
-                let _ = local_update_tx.send(local_player_state);
-            }
+            next_stroke = now + STROKE_INTERVAL;
+            let _ =
+                edits_tx.send(TerrainEdit { brush, center: (x, z), radius: brush_radius, strength: BRUSH_STRENGTH });
         }
-        
+
         let mut d = rl.begin_drawing(&thread);
         d.clear_background(raylib::prelude::Color::SKYBLUE);
         let mut orange_toggle: bool = false;
-        
+
         unsafe {
-	        if IsKeyDown(71) {
-	            orange_toggle = !orange_toggle;
+            if IsKeyDown(71) {
+                orange_toggle = !orange_toggle;
             }
         }
 
         {
             let mut d3 = d.begin_mode3D(camera);
-            unsafe {
-                DrawModel(terrain_model, terrain_position, 1.0, terrain_color_val);
-            }
-            // CORRECTED: Use Ok() for try_lock() result
+            chunk_models.draw();
             if let Ok(locked_gs) = game_state.try_lock() {
-                for player_state in locked_gs.other_players.values() {
-                    let mut pos = Vector3 {
-                        x: player_state.position.0,
-                        y: player_state.position.1 - PLAYER_HEIGHT,
-                        z: player_state.position.2,
-                    };
-                    let rot_axis = Vector3::up();
-                    let rot_angle_rad = player_state.rotation.1;
+                let render_time = locked_gs.server_clock.render_time(now);
+                for (player_id, player_state) in locked_gs.other_players.iter() {
+                    let (position, yaw) = render_time
+                        .and_then(|time| locked_gs.remote_snapshots.get(player_id)?.sample(time))
+                        .unwrap_or((player_state.position, player_state.rotation.1));
+                    let pos = Vector3 { x: position.0, y: position.1 - PLAYER_HEIGHT, z: position.2 };
+                    // Bodies stay upright, so only the yaw of the orientation is drawn.
+                    let ((axis_x, axis_y, axis_z), rot_angle_rad) = Quat::from_euler((0.0, yaw, 0.0)).to_axis_angle();
+                    let rot_axis = Vector3::new(axis_x, axis_y, axis_z);
                     let rot_angle_deg = rot_angle_rad.to_degrees();
                     let model_scale = raylib::ffi::Vector3 { x: 50.0, y: 50.0, z: 50.0 };
-
-                    // if player_model.meshCount > 0 {
-                         unsafe {
-                            // println! ("{:3.2} {:3.2} {:3.2}", pos.x, pos.y, pos.z);
-                            // println! ("{:3.2} {:3.2} {:3.2}", rot_axis.x, rot_axis.y, rot_axis.z);
-                            // pos = Vector3{x: rng.random_range(-50..-450) as f32, y: rng.random_range(5..20) as f32, z: rng.random_range(-50..-450) as f32 };
-                            
-                            DrawModelEx(player_model, pos.into(), rot_axis.into(), rot_angle_deg, model_scale, ColorFromHSV(0.0 as f32, 1.0, 0.0));
-                        }
-                        d3.draw_sphere(pos, 1.0, raylib::prelude::Color::RED);
-                    // }
+                    unsafe {
+                        DrawModelEx(
+                            player_model,
+                            pos.into(),
+                            rot_axis.into(),
+                            rot_angle_deg,
+                            model_scale,
+                            ColorFromHSV(0.0, 1.0, 0.0),
+                        );
+                    }
+                    d3.draw_sphere(pos, 1.0, raylib::prelude::Color::RED);
                 }
             }
-             d3.draw_sphere_ex(Vector3{x: 0.0, y: 150.0, z: -800.0}, 15.0, 10, 10, raylib::prelude::Color::YELLOW);
-             if !orange_toggle {
-                d3.draw_sphere_ex(Vector3{x: 0.0, y: 150.0, z: -800.0}, 12.0, 10, 10, raylib::prelude::Color::ORANGE);
-             }
+            d3.draw_sphere_ex(Vector3 { x: 0.0, y: 150.0, z: -800.0 }, 15.0, 10, 10, raylib::prelude::Color::YELLOW);
+            if !orange_toggle {
+                d3.draw_sphere_ex(
+                    Vector3 { x: 0.0, y: 150.0, z: -800.0 },
+                    12.0,
+                    10,
+                    10,
+                    raylib::prelude::Color::ORANGE,
+                );
+            }
+            if let Some((x, y, z)) = brush_target {
+                d3.draw_circle_3D(
+                    Vector3::new(x, y + 0.2, z),
+                    brush_radius,
+                    Vector3::new(1.0, 0.0, 0.0),
+                    90.0,
+                    BRUSH_COLOR,
+                );
+                d3.draw_sphere(Vector3::new(x, y, z), 0.5, BRUSH_COLOR);
+            }
+            // Last, so everything under the surface shows through it.
+            if let Some(sea_level) = terrain.world().sea_level {
+                let center = Vector3::new(camera.position.x, sea_level, camera.position.z);
+                d3.draw_plane(center, Vector2::new(far_plane * 2.0, far_plane * 2.0), WATER_COLOR);
+            }
+        }
+
+        if terrain.world().sea_level.is_some_and(|level| camera.position.y < level) {
+            d.draw_rectangle(0, 0, d.get_render_width(), d.get_render_height(), UNDERWATER_TINT);
         }
 
         window_x = d.get_render_width();
         window_y = d.get_render_height();
         d.draw_text(&format!("Screen: {}x{}", window_x, window_y), 10, 10, 20, raylib::prelude::Color::LIME);
-        d.draw_text(&format!("Pos: {:.1}, {:.1}, {:.1}", camera.position.x, camera.position.y, camera.position.z), 10, 40, 20, raylib::prelude::Color::RED);
+        let mode = match (fly, swimming) {
+            (true, _) => " (flying, F to walk)",
+            (false, true) => " (swimming)",
+            (false, false) => "",
+        };
+        d.draw_text(
+            &format!("Pos: {:.1}, {:.1}, {:.1}{}", camera.position.x, camera.position.y, camera.position.z, mode),
+            10,
+            40,
+            20,
+            raylib::prelude::Color::RED,
+        );
         d.draw_fps(10, 70);
+        if editing {
+            let help =
+                format!("Editing: {} brush, radius {:.0} (B: next brush, wheel: resize, E: stop)", brush, brush_radius);
+            d.draw_text(&help, 120, 70, 20, BRUSH_COLOR);
+        }
 
-        // CORRECTED: Use Ok() for try_lock() result
         if let Ok(locked_gs) = game_state.try_lock() {
-            let mut y_offset = 100;
+            let status_color = match locked_gs.connection_status {
+                ConnectionStatus::Connected => raylib::prelude::Color::LIME,
+                ConnectionStatus::Failed => raylib::prelude::Color::RED,
+                _ => raylib::prelude::Color::ORANGE,
+            };
+            d.draw_text(&locked_gs.connection_status.to_string(), 10, 100, 20, status_color);
+
+            let mut y_offset = 130;
             for msg in locked_gs.join_messages.iter() {
                 d.draw_text(msg, 10, y_offset, 20, raylib::prelude::Color::YELLOW);
                 y_offset += 25;
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,317 @@
+//! Angles, view bases and orientations, free of raylib so the server can use them too.
+//!
+//! Yaw turns clockwise seen from above, starting from -Z, and pitch tilts the view up
+//! towards +Y. `PlayerState.rotation` stores `(pitch, yaw, roll)` in that convention.
+
+use std::f32::consts::PI;
+
+pub type Vec3 = (f32, f32, f32);
+
+/// Wraps an angle into `[-PI, PI)`.
+pub fn wrap_angle(radians: f32) -> f32 {
+    (radians + PI).rem_euclid(2.0 * PI) - PI
+}
+
+pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Interpolates between two angles along the shorter way around the circle. The result is
+/// not wrapped, so `t = 0` gives back `a` exactly.
+pub fn lerp_angle(a: f32, b: f32, t: f32) -> f32 {
+    a + wrap_angle(b - a) * t
+}
+
+pub fn lerp_vec3(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    (lerp(a.0, b.0, t), lerp(a.1, b.1, t), lerp(a.2, b.2, t))
+}
+
+pub fn dot(a: Vec3, b: Vec3) -> f32 {
+    a.0 * b.0 + a.1 * b.1 + a.2 * b.2
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    (a.1 * b.2 - a.2 * b.1, a.2 * b.0 - a.0 * b.2, a.0 * b.1 - a.1 * b.0)
+}
+
+/// Scales `v` to unit length; vectors too short to have a direction are returned as-is.
+pub fn normalized(v: Vec3) -> Vec3 {
+    let length = dot(v, v).sqrt();
+    if length > 0.0001 { (v.0 / length, v.1 / length, v.2 / length) } else { v }
+}
+
+/// Unit axes of a view, all perpendicular to each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Basis {
+    pub forward: Vec3,
+    pub right: Vec3,
+    pub up: Vec3,
+}
+
+impl Basis {
+    /// The view of a camera looking along `yaw` and `pitch`.
+    pub fn view(yaw: f32, pitch: f32) -> Self {
+        let (yaw_sin, yaw_cos) = yaw.sin_cos();
+        let (pitch_sin, pitch_cos) = pitch.sin_cos();
+        Self {
+            forward: (pitch_cos * yaw_sin, pitch_sin, -pitch_cos * yaw_cos),
+            right: (yaw_cos, 0.0, yaw_sin),
+            up: (-pitch_sin * yaw_sin, pitch_cos, pitch_sin * yaw_cos),
+        }
+    }
+
+    /// The view along `yaw` with the pitch levelled out, for walking and swimming.
+    pub fn flat(yaw: f32) -> Self {
+        Self::view(yaw, 0.0)
+    }
+}
+
+/// A rotation as a unit quaternion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quat {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quat {
+    pub const IDENTITY: Quat = Quat { w: 1.0, x: 0.0, y: 0.0, z: 0.0 };
+
+    /// Turns `angle` radians counter-clockwise about the unit `axis` (right-handed).
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Self {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        Self { w: cos, x: axis.0 * sin, y: axis.1 * sin, z: axis.2 * sin }
+    }
+
+    /// The orientation of a `(pitch, yaw, roll)` rotation: roll about the view axis first,
+    /// then pitch, then yaw.
+    pub fn from_euler((pitch, yaw, roll): Vec3) -> Self {
+        Self::from_axis_angle((0.0, 1.0, 0.0), -yaw)
+            * Self::from_axis_angle((1.0, 0.0, 0.0), pitch)
+            * Self::from_axis_angle((0.0, 0.0, -1.0), roll)
+    }
+
+    /// Back to `(pitch, yaw, roll)`, with yaw and roll in `[-PI, PI)`. Straight up or down
+    /// the yaw and roll blur into one, and all of the turn is reported as yaw.
+    pub fn to_euler(self) -> Vec3 {
+        let Basis { forward, right, up } = self.basis();
+        let pitch = forward.1.clamp(-1.0, 1.0).asin();
+        if forward.1.abs() > 0.9999 {
+            return (pitch, wrap_angle(right.2.atan2(right.0)), 0.0);
+        }
+        let yaw = forward.0.atan2(-forward.2);
+        let roll = (-right.1).atan2(up.1);
+        (pitch, wrap_angle(yaw), wrap_angle(roll))
+    }
+
+    /// The axis and angle (in `[0, 2 * PI]`) of this rotation, as raylib's model drawing
+    /// takes them. The identity turns zero radians about +Y.
+    pub fn to_axis_angle(self) -> (Vec3, f32) {
+        let sin = (1.0 - self.w * self.w).max(0.0).sqrt();
+        if sin < 0.0001 {
+            return ((0.0, 1.0, 0.0), 0.0);
+        }
+        ((self.x / sin, self.y / sin, self.z / sin), 2.0 * self.w.clamp(-1.0, 1.0).acos())
+    }
+
+    pub fn rotate(self, v: Vec3) -> Vec3 {
+        let axis = (self.x, self.y, self.z);
+        let t = cross(axis, v);
+        let t = (2.0 * t.0, 2.0 * t.1, 2.0 * t.2);
+        let u = cross(axis, t);
+        (v.0 + self.w * t.0 + u.0, v.1 + self.w * t.1 + u.1, v.2 + self.w * t.2 + u.2)
+    }
+
+    /// The view axes of something facing this way.
+    pub fn basis(self) -> Basis {
+        Basis {
+            forward: self.rotate((0.0, 0.0, -1.0)),
+            right: self.rotate((1.0, 0.0, 0.0)),
+            up: self.rotate((0.0, 1.0, 0.0)),
+        }
+    }
+
+    pub fn dot(self, other: Quat) -> f32 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn normalized(self) -> Self {
+        let length = self.dot(self).sqrt();
+        Self { w: self.w / length, x: self.x / length, y: self.y / length, z: self.z / length }
+    }
+
+    /// Spherical interpolation along the shorter arc, at a constant angular speed.
+    pub fn slerp(self, other: Quat, t: f32) -> Self {
+        let (other, cos) = match self.dot(other) {
+            cos if cos < 0.0 => (Quat { w: -other.w, x: -other.x, y: -other.y, z: -other.z }, -cos),
+            cos => (other, cos),
+        };
+        // Nearly parallel: the arc is a straight line and the sines below would vanish.
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            let sin = angle.sin();
+            (((1.0 - t) * angle).sin() / sin, (t * angle).sin() / sin)
+        };
+        Quat {
+            w: self.w * a + other.w * b,
+            x: self.x * a + other.x * b,
+            y: self.y * a + other.y * b,
+            z: self.z * a + other.z * b,
+        }
+        .normalized()
+    }
+}
+
+impl std::ops::Mul for Quat {
+    type Output = Quat;
+
+    /// Applies `rhs` first, then `self`.
+    fn mul(self, rhs: Quat) -> Quat {
+        Quat {
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const CASES: usize = 1000;
+    const EPSILON: f32 = 1e-4;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a.0 - b.0).abs() < EPSILON && (a.1 - b.1).abs() < EPSILON && (a.2 - b.2).abs() < EPSILON
+    }
+
+    fn same_angle(a: f32, b: f32) -> bool {
+        wrap_angle(a - b).abs() < EPSILON
+    }
+
+    /// Pitch stays clear of straight up and down, where yaw and roll cannot be told apart.
+    fn random_rotation(rng: &mut StdRng) -> Vec3 {
+        (rng.random_range(-1.5..1.5), rng.random_range(-3.0 * PI..3.0 * PI), rng.random_range(-PI..PI))
+    }
+
+    #[test]
+    fn wrapped_angles_stay_in_range_and_point_the_same_way() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..CASES {
+            let angle = rng.random_range(-100.0..100.0f32);
+            let wrapped = wrap_angle(angle);
+            assert!((-PI..PI).contains(&wrapped), "{angle} wrapped to {wrapped}");
+            assert!((wrapped.sin() - angle.sin()).abs() < EPSILON && (wrapped.cos() - angle.cos()).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn angle_lerps_take_the_short_way_round() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..CASES {
+            let (a, b) = (rng.random_range(-10.0..10.0f32), rng.random_range(-10.0..10.0f32));
+            assert_eq!(lerp_angle(a, b, 0.0), a);
+            assert!(same_angle(lerp_angle(a, b, 1.0), b));
+            let t = rng.random_range(0.0..1.0f32);
+            let step = lerp_angle(a, b, t) - a;
+            assert!(step.abs() <= PI * t + EPSILON, "{a} -> {b} moved {step} at {t}");
+        }
+        assert!((lerp_angle(PI - 0.1, -PI + 0.1, 0.5) - PI).abs() < EPSILON);
+    }
+
+    #[test]
+    fn view_bases_are_orthonormal_and_right_handed() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..CASES {
+            let Basis { forward, right, up } = Basis::view(rng.random_range(-10.0..10.0), rng.random_range(-1.5..1.5));
+            for axis in [forward, right, up] {
+                assert!((dot(axis, axis) - 1.0).abs() < EPSILON);
+            }
+            assert!(dot(forward, right).abs() < EPSILON && dot(forward, up).abs() < EPSILON);
+            assert!(dot(right, up).abs() < EPSILON);
+            assert!(close(cross(right, up), (-forward.0, -forward.1, -forward.2)));
+            assert!(right.1 == 0.0 && up.1 > 0.0);
+        }
+        let Basis { forward, right, .. } = Basis::flat(PI / 2.0);
+        assert!(close(forward, (1.0, 0.0, 0.0)) && close(right, (0.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn orientations_match_the_view_basis() {
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..CASES {
+            let (pitch, yaw, _) = random_rotation(&mut rng);
+            let view = Basis::view(yaw, pitch);
+            let turned = Quat::from_euler((pitch, yaw, 0.0)).basis();
+            assert!(
+                close(turned.forward, view.forward) && close(turned.right, view.right) && close(turned.up, view.up)
+            );
+        }
+    }
+
+    #[test]
+    fn euler_angles_survive_a_round_trip() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..CASES {
+            let rotation = random_rotation(&mut rng);
+            let (pitch, yaw, roll) = Quat::from_euler(rotation).to_euler();
+            assert!((pitch - rotation.0).abs() < 1e-3, "{rotation:?} came back with pitch {pitch}");
+            assert!(wrap_angle(yaw - rotation.1).abs() < 1e-3 && wrap_angle(roll - rotation.2).abs() < 1e-3);
+            assert!((-PI..PI).contains(&yaw) && (-PI..PI).contains(&roll));
+        }
+        let (pitch, _, roll) = Quat::from_euler((PI / 2.0, 1.0, 0.0)).to_euler();
+        assert!((pitch - PI / 2.0).abs() < 1e-3 && roll == 0.0);
+    }
+
+    #[test]
+    fn axis_angles_rebuild_the_same_rotation() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..CASES {
+            let orientation = Quat::from_euler(random_rotation(&mut rng));
+            let (axis, angle) = orientation.to_axis_angle();
+            assert!((0.0..=2.0 * PI + EPSILON).contains(&angle));
+            let rebuilt = Quat::from_axis_angle(axis, angle);
+            assert!((rebuilt.dot(orientation).abs() - 1.0).abs() < EPSILON);
+        }
+        assert_eq!(Quat::IDENTITY.to_axis_angle(), ((0.0, 1.0, 0.0), 0.0));
+        let (axis, angle) = Quat::from_euler((0.0, -1.0, 0.0)).to_axis_angle();
+        assert!(close(axis, (0.0, 1.0, 0.0)) && (angle - 1.0).abs() < EPSILON);
+    }
+
+    #[test]
+    fn rotations_preserve_lengths_and_compose_in_order() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..CASES {
+            let (a, b) = (Quat::from_euler(random_rotation(&mut rng)), Quat::from_euler(random_rotation(&mut rng)));
+            let v = (rng.random_range(-5.0..5.0), rng.random_range(-5.0..5.0), rng.random_range(-5.0..5.0));
+            assert!((dot(a.rotate(v), a.rotate(v)).sqrt() - dot(v, v).sqrt()).abs() < 1e-3);
+            let (both, one_by_one) = ((a * b).rotate(v), a.rotate(b.rotate(v)));
+            assert!((both.0 - one_by_one.0).abs() < 1e-3 && (both.1 - one_by_one.1).abs() < 1e-3);
+            assert!((both.2 - one_by_one.2).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn slerps_run_between_the_ends_along_the_short_arc() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..CASES {
+            let (a, b) = (Quat::from_euler(random_rotation(&mut rng)), Quat::from_euler(random_rotation(&mut rng)));
+            assert!((a.slerp(b, 0.0).dot(a).abs() - 1.0).abs() < EPSILON);
+            assert!((a.slerp(b, 1.0).dot(b).abs() - 1.0).abs() < EPSILON);
+            // Halfway is equally far from both ends, whichever sign `b` came in with.
+            let middle = a.slerp(b, 0.5);
+            assert!((middle.dot(a).abs() - middle.dot(b).abs()).abs() < 1e-3);
+            assert!(middle.dot(a).abs() >= a.dot(b).abs() - EPSILON);
+        }
+        let (a, b) = (Quat::from_euler((0.0, PI - 0.1, 0.0)), Quat::from_euler((0.0, -PI + 0.1, 0.0)));
+        let (_, yaw, _) = a.slerp(b, 0.5).to_euler();
+        assert!(same_angle(yaw, PI));
+    }
+}
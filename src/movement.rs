@@ -0,0 +1,730 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::math::{Basis, normalized};
+use crate::world::WorldBounds;
+
+pub const PLAYER_SPEED: f32 = 50.0;
+/// Eye height above the ground.
+pub const PLAYER_HEIGHT: f32 = 5.0;
+/// Longest frame a single input may cover; the server clamps anything longer.
+pub const MAX_INPUT_DT_MS: u16 = 100;
+/// Steepest slope, in radians from level, that players can walk up.
+pub const MAX_WALKABLE_SLOPE: f32 = std::f32::consts::FRAC_PI_4;
+/// How fast players slide down slopes steeper than `MAX_WALKABLE_SLOPE`.
+pub const SLIDE_SPEED: f32 = 30.0;
+/// Downward acceleration while airborne, in units per second squared.
+pub const GRAVITY: f32 = 60.0;
+/// Upward speed a jump starts with; about four units of height.
+pub const JUMP_SPEED: f32 = 22.0;
+pub const TERMINAL_SPEED: f32 = 120.0;
+/// Jumping still works this long after walking off a ledge.
+pub const COYOTE_TIME: f32 = 0.1;
+/// Fraction of the gap to the wanted horizontal velocity closed per second in the air.
+pub const AIR_CONTROL: f32 = 2.0;
+/// Landings slower than this are harmless; roughly a fall of 17 units.
+pub const SAFE_LANDING_SPEED: f32 = 45.0;
+pub const FALL_DAMAGE_PER_SPEED: f32 = 2.0;
+/// Top speed in the water, in any direction.
+pub const SWIM_SPEED: f32 = 20.0;
+/// Fraction of the gap to the wanted velocity closed per second in the water, which also
+/// brakes players diving in from a height.
+pub const WATER_DRAG: f32 = 4.0;
+/// Upward speed per unit of depth a swimmer floats back up with, up to `FLOAT_SPEED`.
+pub const BUOYANCY: f32 = 2.0;
+pub const FLOAT_SPEED: f32 = 6.0;
+/// Players swim while their eye is below this far above the water. Floating players
+/// settle halfway there, eyes just out of the water.
+pub const SURFACE_BAND: f32 = 1.0;
+/// How close to eye height above the ground still counts as standing on it.
+const GROUND_CONTACT: f32 = 0.05;
+const INPUT_HISTORY: usize = 128;
+
+/// One frame of player input. This is exactly what goes over the wire, so both sides
+/// step with the same numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MoveInput {
+    pub seq: u32,
+    /// -1, 0 or 1 along the camera's forward axis (flattened onto the ground plane).
+    pub forward: i8,
+    pub right: i8,
+    /// Jumps while walking; climbs or sinks while flying or swimming.
+    pub up: i8,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub dt_ms: u16,
+    /// Spectator/debug movement without gravity, as before there was a controller.
+    #[serde(default)]
+    pub fly: bool,
+}
+
+impl MoveInput {
+    pub fn dt(&self) -> f32 {
+        self.dt_ms as f32 / 1000.0
+    }
+}
+
+/// Terrain as far as movement is concerned.
+pub trait Ground {
+    /// Height of the ground under (x, z), or `None` off the map.
+    fn height_at(&self, x: f32, z: f32) -> Option<f32>;
+
+    /// Unit surface normal at (x, z). Grounds that do not know treat themselves as flat.
+    fn normal_at(&self, _x: f32, _z: f32) -> Option<(f32, f32, f32)> {
+        None
+    }
+
+    /// Height of the water surface, if there is water; players below it swim.
+    fn water_level(&self) -> Option<f32> {
+        None
+    }
+}
+
+/// Open air everywhere, for when there is no terrain loaded.
+pub struct NoGround;
+
+impl Ground for NoGround {
+    fn height_at(&self, _x: f32, _z: f32) -> Option<f32> {
+        None
+    }
+}
+
+fn keep_above_ground(position: (f32, f32, f32), ground: &impl Ground) -> (f32, f32, f32) {
+    match ground.height_at(position.0, position.2) {
+        Some(ground_height) if position.1 < ground_height + PLAYER_HEIGHT => {
+            (position.0, ground_height + PLAYER_HEIGHT, position.2)
+        }
+        _ => position,
+    }
+}
+
+fn is_on_ground(position: (f32, f32, f32), ground: &impl Ground) -> bool {
+    ground.height_at(position.0, position.2).is_some_and(|height| position.1 <= height + PLAYER_HEIGHT + GROUND_CONTACT)
+}
+
+/// Level direction straight down the slope at (x, z), if it is too steep to walk on.
+fn downhill_if_steep(x: f32, z: f32, ground: &impl Ground) -> Option<(f32, f32, f32)> {
+    let normal = ground.normal_at(x, z)?;
+    (normal.1 < MAX_WALKABLE_SLOPE.cos()).then(|| normalized((normal.0, 0.0, normal.2)))
+}
+
+/// Drops the uphill part of a move from `start` onto a slope too steep to walk, and adds
+/// the slide down if `start` itself is on one. Also says whether any of that happened.
+fn slope_adjusted(
+    start: (f32, f32, f32),
+    moved: (f32, f32, f32),
+    dt: f32,
+    ground: &impl Ground,
+) -> ((f32, f32, f32), bool) {
+    let mut end = moved;
+    let mut on_slope = false;
+    if let Some(downhill) = downhill_if_steep(moved.0, moved.2, ground) {
+        let uphill = -((moved.0 - start.0) * downhill.0 + (moved.2 - start.2) * downhill.2);
+        if uphill > 0.0 {
+            end.0 += downhill.0 * uphill;
+            end.2 += downhill.2 * uphill;
+            on_slope = true;
+        }
+    }
+    if let Some(downhill) = downhill_if_steep(start.0, start.2, ground) {
+        end.0 += downhill.0 * SLIDE_SPEED * dt;
+        end.2 += downhill.2 * SLIDE_SPEED * dt;
+        on_slope = true;
+    }
+    (end, on_slope)
+}
+
+/// Keeps a flying player who started on the ground from skimming up slopes that are too
+/// steep to walk.
+fn limit_slope(start: (f32, f32, f32), moved: (f32, f32, f32), dt: f32, ground: &impl Ground) -> (f32, f32, f32) {
+    if !is_on_ground(start, ground) {
+        return moved;
+    }
+    let (end, on_slope) = slope_adjusted(start, moved, dt, ground);
+    match ground.height_at(end.0, end.2) {
+        // Stay on the surface rather than hovering where the climb was cut short.
+        Some(height) if on_slope => (end.0, height + PLAYER_HEIGHT, end.2),
+        _ => keep_above_ground(end, ground),
+    }
+}
+
+/// Advances `position` by one flying input: straight along the view, clear of the ground,
+/// no gravity.
+pub fn fly(position: (f32, f32, f32), input: &MoveInput, ground: &impl Ground) -> (f32, f32, f32) {
+    let Basis { forward, right, .. } = Basis::flat(input.yaw);
+    let (f, r, u) = (input.forward.signum() as f32, input.right.signum() as f32, input.up.signum() as f32);
+    let move_dir = normalized((forward.0 * f + right.0 * r, u, forward.2 * f + right.2 * r));
+
+    let distance = PLAYER_SPEED * input.dt();
+    let desired =
+        (position.0 + move_dir.0 * distance, position.1 + move_dir.1 * distance, position.2 + move_dir.2 * distance);
+    let adjusted = keep_above_ground(desired, ground);
+    if adjusted == desired || move_dir.1 >= 0.0 {
+        return limit_slope(position, adjusted, input.dt(), ground);
+    }
+
+    // Pressing down into the ground: keep the full speed along the ground instead.
+    let horizontal = normalized((move_dir.0, 0.0, move_dir.2));
+    let slid = (position.0 + horizontal.0 * distance, position.1, position.2 + horizontal.2 * distance);
+    limit_slope(position, keep_above_ground(slid, ground), input.dt(), ground)
+}
+
+/// Damage for touching down at `speed` units per second.
+pub fn fall_damage(speed: f32) -> f32 {
+    (speed - SAFE_LANDING_SPEED).max(0.0) * FALL_DAMAGE_PER_SPEED
+}
+
+/// What `Controller::step` reports when an airborne player touches down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Landing {
+    /// Downward speed at impact.
+    pub speed: f32,
+    pub damage: f32,
+}
+
+/// A walking and swimming character: velocity, gravity, buoyancy, jumps and landings.
+/// Deterministic, so the client can predict with it and the server can check the result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Controller {
+    pub position: (f32, f32, f32),
+    pub velocity: (f32, f32, f32),
+    pub grounded: bool,
+    /// Time left to jump after leaving the ground without jumping.
+    coyote: f32,
+}
+
+impl Controller {
+    /// Starts airborne and at rest; the first steps drop it onto whatever is below.
+    pub fn new(position: (f32, f32, f32)) -> Controller {
+        Controller { position, velocity: (0.0, 0.0, 0.0), grounded: false, coyote: 0.0 }
+    }
+
+    /// Advances by one input, returning the landing if this step touched down.
+    pub fn step(&mut self, input: &MoveInput, ground: &impl Ground) -> Option<Landing> {
+        if input.fly {
+            *self = Controller::new(fly(self.position, input, ground));
+            return None;
+        }
+        if let Some(sea_level) = ground.water_level().filter(|&level| self.position.1 < level + SURFACE_BAND) {
+            self.swim(input, sea_level, ground);
+            return None;
+        }
+
+        let dt = input.dt();
+        let Basis { forward, right, .. } = Basis::flat(input.yaw);
+        let (f, r) = (input.forward.signum() as f32, input.right.signum() as f32);
+        let wish = normalized((forward.0 * f + right.0 * r, 0.0, forward.2 * f + right.2 * r));
+        let wish = (wish.0 * PLAYER_SPEED, wish.2 * PLAYER_SPEED);
+        if self.grounded {
+            (self.velocity.0, self.velocity.2) = wish;
+        } else {
+            let blend = (AIR_CONTROL * dt).min(1.0);
+            self.velocity.0 += (wish.0 - self.velocity.0) * blend;
+            self.velocity.2 += (wish.1 - self.velocity.2) * blend;
+        }
+
+        if input.up > 0 && (self.grounded || self.coyote > 0.0) {
+            self.velocity.1 = JUMP_SPEED;
+            self.grounded = false;
+            self.coyote = 0.0;
+        } else if !self.grounded {
+            self.velocity.1 = (self.velocity.1 - GRAVITY * dt).max(-TERMINAL_SPEED);
+            self.coyote = (self.coyote - dt).max(0.0);
+        }
+
+        let start = self.position;
+        let moved = (start.0 + self.velocity.0 * dt, start.1 + self.velocity.1 * dt, start.2 + self.velocity.2 * dt);
+        if self.grounded {
+            self.walk(start, moved, dt, ground);
+            None
+        } else {
+            self.fall(moved, ground)
+        }
+    }
+
+    /// Whether a step with no keys held would still move the player: falling, swimming
+    /// or sliding down a slope too steep to stand on.
+    pub fn moves_by_itself(&self, ground: &impl Ground) -> bool {
+        !self.grounded || downhill_if_steep(self.position.0, self.position.2, ground).is_some()
+    }
+
+    /// Whether the player is in the water, going by where the last step left them.
+    pub fn is_swimming(&self, ground: &impl Ground) -> bool {
+        ground.water_level().is_some_and(|level| self.position.1 < level + SURFACE_BAND)
+    }
+
+    /// Moves through the water at swimming speed: SPACE rises, LEFT_SHIFT dives, and
+    /// otherwise the player floats back up to the surface. Water breaks any fall.
+    fn swim(&mut self, input: &MoveInput, sea_level: f32, ground: &impl Ground) {
+        let dt = input.dt();
+        let Basis { forward, right, .. } = Basis::flat(input.yaw);
+        let (f, r) = (input.forward.signum() as f32, input.right.signum() as f32);
+        let wish = normalized((forward.0 * f + right.0 * r, 0.0, forward.2 * f + right.2 * r));
+        let rise = match input.up.signum() {
+            0 => ((sea_level + SURFACE_BAND / 2.0 - self.position.1) * BUOYANCY).clamp(-FLOAT_SPEED, FLOAT_SPEED),
+            up => up as f32 * SWIM_SPEED,
+        };
+        let wish = (wish.0 * SWIM_SPEED, rise, wish.2 * SWIM_SPEED);
+        let blend = (WATER_DRAG * dt).min(1.0);
+        self.velocity.0 += (wish.0 - self.velocity.0) * blend;
+        self.velocity.1 += (wish.1 - self.velocity.1) * blend;
+        self.velocity.2 += (wish.2 - self.velocity.2) * blend;
+
+        let moved = (
+            self.position.0 + self.velocity.0 * dt,
+            self.position.1 + self.velocity.1 * dt,
+            self.position.2 + self.velocity.2 * dt,
+        );
+        self.position = keep_above_ground(moved, ground);
+        if self.position.1 > moved.1 {
+            // Swimming into the seabed.
+            self.velocity.1 = self.velocity.1.max(0.0);
+        }
+        self.grounded = false;
+        self.coyote = 0.0;
+    }
+
+    /// Follows the surface down gentle slopes; anything steeper than walkable is a ledge.
+    fn walk(&mut self, start: (f32, f32, f32), moved: (f32, f32, f32), dt: f32, ground: &impl Ground) {
+        let (end, on_slope) = slope_adjusted(start, moved, dt, ground);
+        let travelled = ((end.0 - start.0).powi(2) + (end.2 - start.2).powi(2)).sqrt();
+        let step_down = travelled * MAX_WALKABLE_SLOPE.tan() + GROUND_CONTACT;
+        match ground.height_at(end.0, end.2) {
+            Some(height) if on_slope || end.1 - (height + PLAYER_HEIGHT) <= step_down => {
+                self.position = (end.0, height + PLAYER_HEIGHT, end.2);
+            }
+            _ => {
+                self.position = end;
+                self.grounded = false;
+                self.coyote = COYOTE_TIME;
+            }
+        }
+    }
+
+    fn fall(&mut self, moved: (f32, f32, f32), ground: &impl Ground) -> Option<Landing> {
+        self.position = moved;
+        let height = ground.height_at(moved.0, moved.2)? + PLAYER_HEIGHT;
+        if moved.1 > height {
+            return None;
+        }
+        self.position.1 = height;
+        if self.velocity.1 > 0.0 {
+            // Jumped into rising ground: carry on upwards over it.
+            return None;
+        }
+        let speed = -self.velocity.1;
+        self.velocity.1 = 0.0;
+        self.grounded = true;
+        Some(Landing { speed, damage: fall_damage(speed) })
+    }
+}
+
+fn step_within(controller: &mut Controller, input: &MoveInput, ground: &impl Ground, bounds: Option<&WorldBounds>) {
+    controller.step(input, ground);
+    if let Some(bounds) = bounds {
+        bounds.enforce(controller);
+    }
+}
+
+/// Client-side prediction: runs local inputs immediately and keeps the ones the server
+/// has not acknowledged yet so they can be replayed on top of its answer.
+#[derive(Debug, Clone)]
+pub struct Predictor {
+    controller: Controller,
+    bounds: Option<WorldBounds>,
+    /// State right after the newest input the server has acknowledged.
+    acknowledged: Controller,
+    next_seq: u32,
+    /// Unacknowledged inputs, each with the state it led to.
+    history: VecDeque<(MoveInput, Controller)>,
+}
+
+impl Predictor {
+    pub fn new(position: (f32, f32, f32)) -> Predictor {
+        let controller = Controller::new(position);
+        Predictor {
+            controller,
+            bounds: None,
+            acknowledged: controller,
+            next_seq: 1,
+            history: VecDeque::with_capacity(INPUT_HISTORY),
+        }
+    }
+
+    /// Keeps predictions inside `bounds` the way the server does; `None` for a world
+    /// without edges.
+    pub fn set_bounds(&mut self, bounds: Option<WorldBounds>) {
+        self.bounds = bounds;
+    }
+
+    pub fn position(&self) -> (f32, f32, f32) {
+        self.controller.position
+    }
+
+    /// Whether the player is standing on something; airborne players keep simulating
+    /// (and sending inputs) even with no keys held.
+    pub fn is_grounded(&self) -> bool {
+        self.controller.grounded
+    }
+
+    pub fn is_swimming(&self, ground: &impl Ground) -> bool {
+        self.controller.is_swimming(ground)
+    }
+
+    pub fn moves_by_itself(&self, ground: &impl Ground) -> bool {
+        self.controller.moves_by_itself(ground)
+    }
+
+    /// Starts over from a server-chosen position, e.g. after a (re)connect.
+    pub fn reset(&mut self, position: (f32, f32, f32)) {
+        self.controller = Controller::new(position);
+        self.acknowledged = self.controller;
+        self.history.clear();
+    }
+
+    /// Steps the local player with this frame's input and returns the input to send.
+    pub fn apply(&mut self, mut input: MoveInput, dt: f32, ground: &impl Ground) -> MoveInput {
+        input.seq = self.next_seq;
+        input.dt_ms = (dt * 1000.0).round().clamp(0.0, MAX_INPUT_DT_MS as f32) as u16;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        step_within(&mut self.controller, &input, ground, self.bounds.as_ref());
+        if self.history.len() == INPUT_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((input, self.controller));
+        input
+    }
+
+    /// Takes the server's position after input `last_input_seq` and replays everything
+    /// sent since on top of it. The server only sends its position, so velocity and the
+    /// like come from what we predicted for that input.
+    pub fn reconcile(&mut self, last_input_seq: u32, server_position: (f32, f32, f32), ground: &impl Ground) {
+        while let Some((_, state)) = self.history.front().filter(|(input, _)| input.seq <= last_input_seq) {
+            self.acknowledged = *state;
+            self.history.pop_front();
+        }
+        let mut controller = Controller { position: server_position, ..self.acknowledged };
+        for (input, state) in self.history.iter_mut() {
+            step_within(&mut controller, input, ground, self.bounds.as_ref());
+            *state = controller;
+        }
+        self.controller = controller;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Flat(f32);
+
+    impl Ground for Flat {
+        fn height_at(&self, _x: f32, _z: f32) -> Option<f32> {
+            Some(self.0)
+        }
+    }
+
+    /// Rises `rise` units per unit of -z, so walking forward at yaw 0 goes uphill.
+    struct Slope {
+        rise: f32,
+    }
+
+    impl Ground for Slope {
+        fn height_at(&self, _x: f32, z: f32) -> Option<f32> {
+            Some(-z * self.rise)
+        }
+
+        fn normal_at(&self, _x: f32, _z: f32) -> Option<(f32, f32, f32)> {
+            let length = (1.0 + self.rise * self.rise).sqrt();
+            Some((0.0, 1.0 / length, self.rise / length))
+        }
+    }
+
+    /// A tenth of a second of flying, which keeps gravity out of tests about steering.
+    fn input(forward: i8, right: i8, up: i8, yaw: f32) -> MoveInput {
+        MoveInput { forward, right, up, yaw, dt_ms: 100, fly: true, ..MoveInput::default() }
+    }
+
+    fn walk(forward: i8, right: i8, up: i8) -> MoveInput {
+        MoveInput { fly: false, ..input(forward, right, up, 0.0) }
+    }
+
+    /// A controller that has dropped onto `ground` under (x, z) and come to rest.
+    fn standing(x: f32, z: f32, ground: &impl Ground) -> Controller {
+        let mut controller = Controller::new((x, 100.0, z));
+        while !controller.grounded {
+            controller.step(&walk(0, 0, 0), ground);
+        }
+        controller
+    }
+
+    fn assert_close(a: (f32, f32, f32), b: (f32, f32, f32)) {
+        assert!((a.0 - b.0).abs() < 1e-3 && (a.1 - b.1).abs() < 1e-3 && (a.2 - b.2).abs() < 1e-3, "{:?} vs {:?}", a, b);
+    }
+
+    #[test]
+    fn walks_forward_along_yaw() {
+        assert_close(fly((0.0, 50.0, 0.0), &input(1, 0, 0, 0.0), &NoGround), (0.0, 50.0, -5.0));
+        let quarter_turn = std::f32::consts::FRAC_PI_2;
+        assert_close(fly((0.0, 50.0, 0.0), &input(1, 0, 0, quarter_turn), &NoGround), (5.0, 50.0, 0.0));
+        assert_close(fly((0.0, 50.0, 0.0), &input(0, 1, 0, 0.0), &NoGround), (5.0, 50.0, 0.0));
+    }
+
+    #[test]
+    fn diagonal_moves_are_not_faster() {
+        let moved = fly((0.0, 50.0, 0.0), &input(1, 1, 0, 0.0), &NoGround);
+        assert!(((moved.0 * moved.0 + moved.2 * moved.2).sqrt() - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn stays_eye_height_above_ground() {
+        let ground = Flat(10.0);
+        assert_close(fly((0.0, 15.0, 0.0), &input(0, 0, -1, 0.0), &ground), (0.0, 15.0, 0.0));
+        // Pressing down while walking keeps full walking speed along the ground.
+        assert_close(fly((0.0, 15.0, 0.0), &input(1, 0, -1, 0.0), &ground), (0.0, 15.0, -5.0));
+    }
+
+    #[test]
+    fn walks_up_gentle_slopes() {
+        let ground = Slope { rise: 0.5 };
+        let moved = fly((0.0, PLAYER_HEIGHT, 0.0), &input(1, 0, 0, 0.0), &ground);
+        assert_close(moved, (0.0, 2.5 + PLAYER_HEIGHT, -5.0));
+    }
+
+    #[test]
+    fn slides_down_slopes_too_steep_to_climb() {
+        let ground = Slope { rise: 2.0 };
+        let start = (0.0, PLAYER_HEIGHT, 0.0);
+        // Walking straight at the cliff only slides back down it.
+        let slide = SLIDE_SPEED * 0.1;
+        assert_close(fly(start, &input(1, 0, 0, 0.0), &ground), (0.0, PLAYER_HEIGHT - 2.0 * slide, slide));
+        // Walking along it still works, while sliding down.
+        assert_close(fly(start, &input(0, 1, 0, 0.0), &ground), (5.0, PLAYER_HEIGHT - 2.0 * slide, slide));
+        // Nothing stops a player in the air from moving over it.
+        let flying = (0.0, 100.0, 0.0);
+        assert_close(fly(flying, &input(1, 0, 0, 0.0), &ground), (0.0, 100.0, -5.0));
+    }
+
+    #[test]
+    fn falls_and_lands_on_the_ground() {
+        let ground = Flat(10.0);
+        let mut controller = Controller::new((0.0, 12.0 + PLAYER_HEIGHT, 0.0));
+        let landing = (0..20).find_map(|_| controller.step(&walk(0, 0, 0), &ground)).unwrap();
+        assert!(controller.grounded);
+        assert_close(controller.position, (0.0, 10.0 + PLAYER_HEIGHT, 0.0));
+        assert_eq!(controller.velocity.1, 0.0);
+        assert!(landing.speed > 0.0 && landing.damage == 0.0, "{:?}", landing);
+    }
+
+    #[test]
+    fn hard_landings_hurt() {
+        assert_eq!(fall_damage(SAFE_LANDING_SPEED), 0.0);
+        assert_eq!(fall_damage(SAFE_LANDING_SPEED + 10.0), 10.0 * FALL_DAMAGE_PER_SPEED);
+
+        let mut controller = Controller::new((0.0, 200.0, 0.0));
+        let landing = (0..100).find_map(|_| controller.step(&walk(0, 0, 0), &Flat(0.0))).unwrap();
+        assert!(landing.damage > 0.0, "{:?}", landing);
+    }
+
+    #[test]
+    fn jumps_only_from_the_ground() {
+        let ground = Flat(0.0);
+        let mut controller = standing(0.0, 0.0, &ground);
+        controller.step(&walk(0, 0, 1), &ground);
+        assert!(!controller.grounded);
+        assert!(controller.position.1 > PLAYER_HEIGHT);
+        let rising = controller.velocity.1;
+        // No double jumps: the second press changes nothing but gravity.
+        controller.step(&walk(0, 0, 1), &ground);
+        assert!((controller.velocity.1 - (rising - GRAVITY * 0.1)).abs() < 1e-4);
+        let landing = (0..20).find_map(|_| controller.step(&walk(0, 0, 0), &ground));
+        assert!(landing.is_some_and(|landing| landing.damage == 0.0));
+    }
+
+    /// Ground at 20 for z > -2, and a sheer drop to 0 beyond.
+    struct Ledge;
+
+    impl Ground for Ledge {
+        fn height_at(&self, _x: f32, z: f32) -> Option<f32> {
+            Some(if z > -2.0 { 20.0 } else { 0.0 })
+        }
+    }
+
+    #[test]
+    fn jumping_still_works_just_after_walking_off_a_ledge() {
+        let short_step = |up| MoveInput { dt_ms: 30, ..walk(1, 0, up) };
+        let mut controller = standing(0.0, 0.0, &Ledge);
+        controller.step(&short_step(0), &Ledge);
+        assert!(controller.grounded);
+        controller.step(&short_step(0), &Ledge);
+        assert!(!controller.grounded, "walked off at {:?}", controller.position);
+        let mut late = controller;
+
+        controller.step(&short_step(1), &Ledge);
+        assert_eq!(controller.velocity.1, JUMP_SPEED);
+
+        for _ in 0..4 {
+            late.step(&short_step(0), &Ledge);
+        }
+        late.step(&short_step(1), &Ledge);
+        assert!(late.velocity.1 < 0.0, "jumped {:?} after coyote time", late.velocity);
+    }
+
+    #[test]
+    fn air_control_is_limited() {
+        let mut controller = Controller::new((0.0, 100.0, 0.0));
+        controller.step(&walk(0, 1, 0), &Flat(0.0));
+        assert!((controller.velocity.0 - PLAYER_SPEED * AIR_CONTROL * 0.1).abs() < 1e-3, "{:?}", controller.velocity);
+
+        let mut grounded = standing(0.0, 0.0, &Flat(0.0));
+        grounded.step(&walk(0, 1, 0), &Flat(0.0));
+        assert_eq!(grounded.velocity.0, PLAYER_SPEED);
+    }
+
+    #[test]
+    fn walks_along_slopes_and_slides_off_steep_ones() {
+        let gentle = Slope { rise: 0.5 };
+        let mut controller = standing(0.0, 0.0, &gentle);
+        // Downhill too: gentle slopes do not launch the player into the air.
+        controller.step(&walk(-1, 0, 0), &gentle);
+        assert!(controller.grounded);
+        assert_close(controller.position, (0.0, -2.5 + PLAYER_HEIGHT, 5.0));
+
+        let steep = Slope { rise: 2.0 };
+        let mut controller = standing(0.0, 0.0, &steep);
+        let start = controller.position;
+        controller.step(&walk(1, 0, 0), &steep);
+        assert!(controller.position.2 > start.2 && controller.position.1 < start.1, "{:?}", controller.position);
+    }
+
+    #[test]
+    fn only_standing_on_walkable_ground_keeps_still_without_keys() {
+        assert!(!standing(0.0, 0.0, &Flat(0.0)).moves_by_itself(&Flat(0.0)));
+        assert!(!standing(0.0, 0.0, &Slope { rise: 0.5 }).moves_by_itself(&Slope { rise: 0.5 }));
+        assert!(Controller::new((0.0, 50.0, 0.0)).moves_by_itself(&Flat(0.0)));
+
+        let steep = Slope { rise: 2.0 };
+        let mut controller = standing(0.0, 0.0, &steep);
+        assert!(controller.grounded && controller.moves_by_itself(&steep));
+        let start = controller.position;
+        controller.step(&walk(0, 0, 0), &steep);
+        assert!(controller.position.2 > start.2, "{:?}", controller.position);
+    }
+
+    /// A flat seabed at 0 under water up to `SEA`.
+    struct Sea;
+
+    const SEA: f32 = 20.0;
+
+    impl Ground for Sea {
+        fn height_at(&self, _x: f32, _z: f32) -> Option<f32> {
+            Some(0.0)
+        }
+
+        fn water_level(&self) -> Option<f32> {
+            Some(SEA)
+        }
+    }
+
+    #[test]
+    fn swimmers_float_up_to_the_surface() {
+        let mut controller = Controller::new((0.0, 8.0, 0.0));
+        assert!(controller.is_swimming(&Sea));
+        for _ in 0..100 {
+            assert_eq!(controller.step(&walk(0, 0, 0), &Sea), None);
+        }
+        assert!(controller.is_swimming(&Sea) && !controller.grounded);
+        // Eyes just out of the water.
+        let eye = controller.position.1;
+        assert!(eye > SEA && eye < SEA + SURFACE_BAND, "floating at {}", eye);
+        assert!(controller.velocity.1.abs() < 0.1, "{:?}", controller.velocity);
+    }
+
+    #[test]
+    fn swimming_is_slower_than_walking() {
+        let mut controller = Controller::new((0.0, 10.0, 0.0));
+        for _ in 0..30 {
+            controller.step(&walk(1, 1, 0), &Sea);
+        }
+        let speed = (controller.velocity.0.powi(2) + controller.velocity.2.powi(2)).sqrt();
+        assert!((speed - SWIM_SPEED).abs() < 0.1, "swimming at {}", speed);
+    }
+
+    #[test]
+    fn swimmers_dive_down_to_the_seabed_and_climb_back() {
+        let mut controller = Controller::new((0.0, 15.0, 0.0));
+        for _ in 0..50 {
+            controller.step(&walk(0, 0, -1), &Sea);
+        }
+        assert_close(controller.position, (0.0, PLAYER_HEIGHT, 0.0));
+        assert!(controller.velocity.1 >= 0.0);
+        // Swimming up hard enough carries the player out of the water for a moment.
+        let highest = (0..20).map(|_| {
+            controller.step(&walk(0, 0, 1), &Sea);
+            controller.position.1
+        });
+        assert!(highest.fold(0.0, f32::max) > SEA + SURFACE_BAND + 1.0);
+    }
+
+    #[test]
+    fn water_breaks_any_fall() {
+        let mut controller = Controller::new((0.0, 300.0, 0.0));
+        for _ in 0..100 {
+            assert_eq!(controller.step(&walk(0, 0, 0), &Sea), None);
+            assert!(controller.position.1 >= PLAYER_HEIGHT);
+        }
+        assert!(controller.is_swimming(&Sea));
+        // Without water the same drop lands, and hurts.
+        assert!(!Controller::new((0.0, 300.0, 0.0)).is_swimming(&Flat(0.0)));
+    }
+
+    #[test]
+    fn replays_unacknowledged_inputs_on_top_of_server_state() {
+        let mut predictor = Predictor::new((0.0, 50.0, 0.0));
+        let sent: Vec<MoveInput> = (0..5).map(|_| predictor.apply(input(1, 0, 0, 0.0), 0.1, &NoGround)).collect();
+        assert_eq!(sent.iter().map(|i| i.seq).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_close(predictor.position(), (0.0, 50.0, -25.0));
+
+        // The server agrees up to input 3: nothing visibly changes.
+        predictor.reconcile(3, (0.0, 50.0, -15.0), &NoGround);
+        assert_close(predictor.position(), (0.0, 50.0, -25.0));
+
+        // The server moved us elsewhere after input 4: we follow, keeping input 5 on top.
+        predictor.reconcile(4, (100.0, 50.0, 0.0), &NoGround);
+        assert_close(predictor.position(), (100.0, 50.0, -5.0));
+    }
+
+    #[test]
+    fn reconciling_mid_jump_keeps_the_jump_going() {
+        let ground = Flat(0.0);
+        let mut predictor = Predictor::new((0.0, PLAYER_HEIGHT, 0.0));
+        predictor.apply(walk(0, 0, 0), 0.1, &ground);
+        assert!(predictor.is_grounded());
+        predictor.apply(walk(0, 0, 1), 0.1, &ground);
+        predictor.apply(walk(0, 0, 0), 0.1, &ground);
+        let predicted = predictor.position();
+
+        // The server saw the jump start exactly where we did.
+        predictor.reconcile(2, (0.0, PLAYER_HEIGHT + JUMP_SPEED * 0.1, 0.0), &ground);
+        assert_close(predictor.position(), predicted);
+        assert!(!predictor.is_grounded());
+    }
+
+    #[test]
+    fn quantizes_and_clamps_frame_time() {
+        let mut predictor = Predictor::new((0.0, 0.0, 0.0));
+        assert_eq!(predictor.apply(MoveInput::default(), 0.0166, &NoGround).dt_ms, 17);
+        assert_eq!(predictor.apply(MoveInput::default(), 3.0, &NoGround).dt_ms, MAX_INPUT_DT_MS);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut predictor = Predictor::new((0.0, 0.0, 0.0));
+        for _ in 0..INPUT_HISTORY * 2 {
+            predictor.apply(input(1, 0, 0, 0.0), 0.016, &NoGround);
+        }
+        assert_eq!(predictor.history.len(), INPUT_HISTORY);
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
+use url::Url;
+
+use crate::codec::{Codec, Roster, decode_server};
+use crate::editing::TerrainEdit;
+use crate::movement::MoveInput;
+use crate::protocol::{ClientMessage, ServerMessage};
+
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+/// Batches the game may queue while the socket is stalled before the oldest are dropped.
+pub const INPUT_QUEUE_CAPACITY: usize = 8;
+/// View changes smaller than this (radians) with no keys held are not worth sending.
+const VIEW_DEADBAND: f32 = 0.002;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Reconnecting { retry_at: Instant },
+    Failed,
+}
+
+impl fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionStatus::Connecting => write!(f, "Connecting..."),
+            ConnectionStatus::Connected => write!(f, "Connected"),
+            ConnectionStatus::Reconnecting { retry_at } => {
+                let remaining = retry_at.saturating_duration_since(Instant::now());
+                write!(f, "Reconnecting in {}s", remaining.as_secs_f32().ceil() as u64)
+            }
+            ConnectionStatus::Failed => write!(f, "Connection failed"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetEvent {
+    Status(ConnectionStatus),
+    Message(ServerMessage),
+}
+
+/// Running totals of the WebSocket frames a client sent and received, across reconnects.
+/// Bytes are payload bytes, without WebSocket or TCP framing.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent_bytes: AtomicU64,
+    sent_frames: AtomicU64,
+    received_bytes: AtomicU64,
+    received_frames: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficTotals {
+    pub sent_bytes: u64,
+    pub sent_frames: u64,
+    pub received_bytes: u64,
+    pub received_frames: u64,
+}
+
+impl Traffic {
+    fn record_sent(&self, frame: &WsMessage) {
+        self.sent_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        self.sent_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_received(&self, frame: &WsMessage) {
+        self.received_bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+        self.received_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> TrafficTotals {
+        TrafficTotals {
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            sent_frames: self.sent_frames.load(Ordering::Relaxed),
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+            received_frames: self.received_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Wait before the `attempt`th retry: the window doubles from `BACKOFF_BASE` up to
+/// `BACKOFF_MAX`, and `jitter` (0..=1) picks a point in its upper half so that clients
+/// dropped together do not all come back together.
+pub fn backoff_delay(attempt: u32, jitter: f32) -> Duration {
+    let window = BACKOFF_BASE.saturating_mul(1 << attempt.min(16)).min(BACKOFF_MAX);
+    window.mul_f32(0.5 + 0.5 * jitter.clamp(0.0, 1.0))
+}
+
+/// Collects the inputs of rendered frames and lets them out as one batch per network
+/// tick, whatever the frame rate.
+#[derive(Debug, Clone)]
+pub struct InputBatcher {
+    interval: f64,
+    next_send: f64,
+    pending: Vec<MoveInput>,
+    last_view: (f32, f32),
+}
+
+impl InputBatcher {
+    pub fn new(send_rate: u32) -> InputBatcher {
+        InputBatcher {
+            interval: 1.0 / send_rate.max(1) as f64,
+            next_send: 0.0,
+            pending: Vec::new(),
+            last_view: (0.0, 0.0),
+        }
+    }
+
+    /// Whether an input is worth simulating and sending at all. One with no keys held
+    /// only matters when the view turned, or when the player `moves_by_itself` (see
+    /// `Controller::moves_by_itself`), e.g. by falling or sliding off a steep slope.
+    /// Flying players stay put without keys, so for them that does not count.
+    pub fn wants(&self, input: &MoveInput, moves_by_itself: bool) -> bool {
+        let keys_held = input.forward != 0 || input.right != 0 || input.up != 0;
+        keys_held
+            || (moves_by_itself && !input.fly)
+            || (input.yaw - self.last_view.0).abs() > VIEW_DEADBAND
+            || (input.pitch - self.last_view.1).abs() > VIEW_DEADBAND
+    }
+
+    pub fn queue(&mut self, input: MoveInput) {
+        self.last_view = (input.yaw, input.pitch);
+        self.pending.push(input);
+    }
+
+    /// Everything queued since the last batch, at most once per send interval.
+    /// `now` is the game clock in seconds.
+    pub fn take_due(&mut self, now: f64) -> Option<Vec<MoveInput>> {
+        if now < self.next_send {
+            return None;
+        }
+        // Keep to the schedule across uneven frames, but never try to catch up on a backlog.
+        self.next_send += self.interval;
+        if self.next_send <= now {
+            self.next_send = now + self.interval;
+        }
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+/// Runs a single connection until it drops and returns whether the server welcomed us.
+async fn connect_and_manage_websocket(
+    server_url: &Url,
+    player_name: &str,
+    preferred_codec: Codec,
+    local_input_rx: &mut broadcast::Receiver<Vec<MoveInput>>,
+    edits_rx: &mut mpsc::UnboundedReceiver<TerrainEdit>,
+    events_tx: &mpsc::UnboundedSender<NetEvent>,
+    traffic: &Traffic,
+) -> bool {
+    let ws_stream = match connect_async(server_url.as_str()).await {
+        Ok((ws_stream, _)) => ws_stream,
+        Err(e) => {
+            eprintln!("CLIENT: Failed to connect to WebSocket: {}", e);
+            return false;
+        }
+    };
+    println!("CLIENT: Successfully connected to WebSocket server at {}.", server_url);
+    let (mut write, mut read) = ws_stream.split();
+
+    let hello =
+        Codec::Json.encode_client(&ClientMessage::Hello { name: player_name.to_string(), codec: preferred_codec });
+    traffic.record_sent(&hello);
+    if write.send(hello).await.is_err() {
+        eprintln!("CLIENT: Failed to introduce myself to the server.");
+        return false;
+    }
+
+    // Inputs and edits sent before the welcome would be applied by nobody, so they are dropped.
+    let mut session_codec: Option<Codec> = None;
+    let mut roster = Roster::default();
+    loop {
+        tokio::select! {
+            batch = local_input_rx.recv() => {
+                let inputs = match batch {
+                    Ok(inputs) => inputs,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("CLIENT: Dropped {} input batches while the socket was stalled.", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if let Some(codec) = session_codec {
+                    let frame = codec.encode_client(&ClientMessage::Inputs { inputs });
+                    traffic.record_sent(&frame);
+                    if write.send(frame).await.is_err() {
+                        eprintln!("CLIENT: Failed to send inputs to server.");
+                        break;
+                    }
+                }
+            }
+            edit = edits_rx.recv() => {
+                let Some(edit) = edit else { break };
+                if let Some(codec) = session_codec {
+                    let frame = codec.encode_client(&ClientMessage::Edit(edit));
+                    traffic.record_sent(&frame);
+                    if write.send(frame).await.is_err() {
+                        eprintln!("CLIENT: Failed to send a terrain edit to server.");
+                        break;
+                    }
+                }
+            }
+            msg_result = read.next() => {
+                if let Some(Ok(frame)) = &msg_result {
+                    traffic.record_received(frame);
+                }
+                match msg_result {
+                    Some(Ok(frame @ (WsMessage::Text(_) | WsMessage::Binary(_)))) => match decode_server(&frame, &roster) {
+                        Ok(ServerMessage::Unknown) => {
+                            eprintln!("CLIENT: Skipping unknown server message: {}", frame);
+                        }
+                        Ok(msg) => {
+                            match &msg {
+                                ServerMessage::Welcome(welcome) => {
+                                    session_codec = Some(welcome.codec);
+                                    let _ = events_tx.send(NetEvent::Status(ConnectionStatus::Connected));
+                                }
+                                ServerMessage::Roster { players } => roster.replace(players.clone()),
+                                _ => {}
+                            }
+                            if events_tx.send(NetEvent::Message(msg)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("CLIENT: Failed to parse server message ({}): {}", e, frame);
+                        }
+                    },
+                    Some(Ok(WsMessage::Close(_))) | None => {
+                        println!("CLIENT: WebSocket connection closed by server.");
+                        break;
+                    }
+                    Some(Err(e)) => {
+                        eprintln!("CLIENT: WebSocket read error: {}", e);
+                        break;
+                    }
+                    Some(Ok(_)) => { /* Ignore other message types */ }
+                }
+            }
+        }
+    }
+    println!("CLIENT: WebSocket connection handler finished.");
+    session_codec.is_some()
+}
+
+/// Keeps the client connected for as long as the game runs, reconnecting with backoff
+/// and introducing itself again after every drop. Gives up after
+/// `MAX_RECONNECT_ATTEMPTS` attempts in a row that never got a welcome.
+///
+/// `local_input_rx` should come from a `broadcast` channel of `INPUT_QUEUE_CAPACITY`:
+/// with a single receiver it is a bounded queue that drops the oldest batches when full,
+/// so a stalled socket can neither block the game nor grow memory. Everything sent and
+/// received is counted in `traffic`.
+pub async fn run_connection_supervisor(
+    server_url: Url,
+    player_name: String,
+    codec: Codec,
+    mut local_input_rx: broadcast::Receiver<Vec<MoveInput>>,
+    mut edits_rx: mpsc::UnboundedReceiver<TerrainEdit>,
+    events_tx: mpsc::UnboundedSender<NetEvent>,
+    traffic: Arc<Traffic>,
+) {
+    let mut failed_attempts = 0;
+    loop {
+        let _ = events_tx.send(NetEvent::Status(ConnectionStatus::Connecting));
+        let welcomed = connect_and_manage_websocket(
+            &server_url,
+            &player_name,
+            codec,
+            &mut local_input_rx,
+            &mut edits_rx,
+            &events_tx,
+            &traffic,
+        )
+        .await;
+        if events_tx.is_closed() {
+            return;
+        }
+
+        failed_attempts = if welcomed { 0 } else { failed_attempts + 1 };
+        if failed_attempts >= MAX_RECONNECT_ATTEMPTS {
+            eprintln!("CLIENT: Giving up after {} failed connection attempts.", failed_attempts);
+            let _ = events_tx.send(NetEvent::Status(ConnectionStatus::Failed));
+            return;
+        }
+
+        let delay = backoff_delay(failed_attempts, rand::rng().random());
+        println!("CLIENT: Reconnecting in {:.1}s.", delay.as_secs_f32());
+        let _ = events_tx.send(NetEvent::Status(ConnectionStatus::Reconnecting { retry_at: Instant::now() + delay }));
+        tokio::time::sleep(delay).await;
+        // Whatever the game queued while we were away is stale now.
+        local_input_rx = local_input_rx.resubscribe();
+        while edits_rx.try_recv().is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff_delay(0, 1.0), BACKOFF_BASE);
+        assert_eq!(backoff_delay(1, 1.0), BACKOFF_BASE * 2);
+        assert_eq!(backoff_delay(3, 1.0), BACKOFF_BASE * 8);
+        assert_eq!(backoff_delay(40, 1.0), BACKOFF_MAX);
+    }
+
+    #[test]
+    fn jitter_stays_in_upper_half_of_window() {
+        for attempt in 0..12 {
+            let window = backoff_delay(attempt, 1.0);
+            assert_eq!(backoff_delay(attempt, 0.0), window / 2);
+            let jittered = backoff_delay(attempt, 0.37);
+            assert!(jittered >= window / 2 && jittered <= window);
+        }
+    }
+
+    fn walk(seq: u32) -> MoveInput {
+        MoveInput { seq, forward: 1, ..MoveInput::default() }
+    }
+
+    #[test]
+    fn batches_frames_at_the_send_rate() {
+        let mut batcher = InputBatcher::new(20);
+        let mut batches = Vec::new();
+        // Two seconds at 60 fps.
+        for frame in 0..120 {
+            batcher.queue(walk(frame));
+            if let Some(batch) = batcher.take_due(frame as f64 / 60.0) {
+                batches.push(batch);
+            }
+        }
+        assert!((39..=41).contains(&batches.len()), "{} batches", batches.len());
+        let sent: Vec<u32> = batches.concat().iter().map(|input| input.seq).collect();
+        assert_eq!(sent, (0..sent.len() as u32).collect::<Vec<_>>());
+        // A long hitch sends one batch, not a burst of catch-up ones.
+        batcher.queue(walk(500));
+        assert!(batcher.take_due(10.0).is_some());
+        batcher.queue(walk(501));
+        assert!(batcher.take_due(10.01).is_none());
+    }
+
+    #[test]
+    fn idle_inputs_inside_the_deadband_are_skipped() {
+        let mut batcher = InputBatcher::new(20);
+        let idle = MoveInput { yaw: 1.0, pitch: 0.2, ..MoveInput::default() };
+        assert!(batcher.wants(&idle, false));
+        batcher.queue(idle);
+        assert!(!batcher.wants(&MoveInput { yaw: 1.0005, ..idle }, false));
+        assert!(batcher.wants(&MoveInput { yaw: 1.01, ..idle }, false));
+        assert!(batcher.wants(&MoveInput { forward: 1, ..idle }, false));
+        // Falling or sliding keeps idle inputs coming, except while flying.
+        assert!(batcher.wants(&idle, true));
+        assert!(!batcher.wants(&MoveInput { fly: true, ..idle }, true));
+        assert!(batcher.take_due(0.0).is_some());
+        assert!(batcher.take_due(1.0).is_none());
+    }
+
+    #[tokio::test]
+    async fn queue_drops_oldest_batches_when_full() {
+        let (tx, mut rx) = broadcast::channel(INPUT_QUEUE_CAPACITY);
+        for seq in 0..INPUT_QUEUE_CAPACITY as u32 + 3 {
+            tx.send(vec![walk(seq)]).unwrap();
+        }
+        assert!(matches!(rx.recv().await, Err(broadcast::error::RecvError::Lagged(3))));
+        assert_eq!(rx.recv().await.unwrap()[0].seq, 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_nothing_is_listening() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("ws://{}/ws", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let (_local_tx, local_rx) = broadcast::channel(INPUT_QUEUE_CAPACITY);
+        let (_edits_tx, edits_rx) = mpsc::unbounded_channel();
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        tokio::time::pause();
+        let traffic = Arc::new(Traffic::default());
+        run_connection_supervisor(url, "bot".into(), Codec::Binary, local_rx, edits_rx, events_tx, traffic.clone())
+            .await;
+        assert_eq!(traffic.totals(), TrafficTotals::default());
+
+        let mut reconnects = 0;
+        let mut last = None;
+        while let Ok(NetEvent::Status(status)) = events_rx.try_recv() {
+            if matches!(status, ConnectionStatus::Reconnecting { .. }) {
+                reconnects += 1;
+            }
+            last = Some(status);
+        }
+        assert_eq!(reconnects, MAX_RECONNECT_ATTEMPTS - 1);
+        assert_eq!(last, Some(ConnectionStatus::Failed));
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::editing::{HeightPatch, TerrainEdit};
+use crate::math::Quat;
+use crate::movement::MoveInput;
+use crate::world::WorldBounds;
+use crate::worldgen::WorldParams;
+
+/// Bumped whenever a change to the messages below would confuse an older peer.
+pub const PROTOCOL_VERSION: u16 = 13;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub id: String,
+    /// Filled in by the server from the client's `Hello`; clients leave it empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub position: (f32, f32, f32),
+    pub rotation: (f32, f32, f32),
+    /// Sequence number of the last input the server applied to this player; its owner
+    /// replays anything newer on top of `position`.
+    #[serde(default)]
+    pub last_input_seq: u32,
+}
+
+impl PlayerState {
+    pub fn display_name(&self) -> String {
+        if self.name.is_empty() {
+            format!("{}...", self.id.chars().take(6).collect::<String>())
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// Which way the player faces, from the `(pitch, yaw, roll)` in `rotation`.
+    pub fn orientation(&self) -> Quat {
+        Quat::from_euler(self.rotation)
+    }
+}
+
+/// First frame the server sends on a new connection, before any player states.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    pub your_id: String,
+    /// Short stand-in for `your_id` in binary frames.
+    pub your_index: u16,
+    pub server_version: String,
+    pub tick_rate: u32,
+    /// Where the server put you; movement is predicted from here.
+    pub spawn: (f32, f32, f32),
+    /// Encoding both sides use from this frame on; the `Hello` asking for it is always JSON.
+    pub codec: Codec,
+    /// Where the world ends and what happens there, or `None` if it goes on forever.
+    pub bounds: Option<WorldBounds>,
+    /// Clients generate the terrain from this, so they stand on the server's world.
+    pub world: WorldParams,
+    /// The edited heights of every chunk touched so far, one patch per chunk, to lay over the generated terrain.
+    pub edits: Vec<HeightPatch>,
+    /// Whether the server honours `fly` in inputs; when it does not, they are walked.
+    pub allow_fly: bool,
+}
+
+/// Maps the compact player indices used by binary frames back to ids and names.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RosterEntry {
+    pub index: u16,
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Sent once right after connecting, always as JSON.
+    Hello {
+        name: String,
+        #[serde(default)]
+        codec: Codec,
+    },
+    /// The frames of movement since the last send, oldest first.
+    Inputs {
+        inputs: Vec<MoveInput>,
+    },
+    Chat {
+        text: String,
+    },
+    Ping {
+        nonce: u64,
+    },
+    /// A brush stroke; the server checks it, applies it and sends everyone the result.
+    Edit(TerrainEdit),
+    /// Anything this build does not know about; receivers log it and move on.
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome(Welcome),
+    /// `server_time_ms` counts from server start; clients use it to space snapshots out.
+    Snapshot {
+        server_time_ms: u64,
+        players: Vec<PlayerState>,
+    },
+    /// Everyone currently connected; resent whenever that changes.
+    Roster {
+        players: Vec<RosterEntry>,
+    },
+    Chat {
+        from: String,
+        text: String,
+    },
+    /// Sent once when a player disconnects, just before the roster without them.
+    PlayerLeft {
+        id: String,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// New heights after someone's edit, to apply on top of everything before it.
+    TerrainPatch(HeightPatch),
+    #[serde(other)]
+    Unknown,
+}
+
+/// Every frame on the wire is `{"v": PROTOCOL_VERSION, "type": ..., ...}`.
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    v: u16,
+    #[serde(flatten)]
+    msg: T,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Version(u16),
+    Json(serde_json::Error),
+    Binary(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Version(v) => write!(f, "protocol version {} (expected {})", v, PROTOCOL_VERSION),
+            DecodeError::Json(e) => write!(f, "{}", e),
+            DecodeError::Binary(e) => write!(f, "bad binary frame: {}", e),
+        }
+    }
+}
+
+pub fn encode<T: Serialize>(msg: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&Envelope { v: PROTOCOL_VERSION, msg })
+}
+
+pub fn decode<T: DeserializeOwned>(text: &str) -> Result<T, DecodeError> {
+    let envelope: Envelope<T> = serde_json::from_str(text).map_err(DecodeError::Json)?;
+    if envelope.v != PROTOCOL_VERSION {
+        return Err(DecodeError::Version(envelope.v));
+    }
+    Ok(envelope.msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_inputs_round_trip() {
+        let input = MoveInput { seq: 42, forward: 1, right: -1, up: 0, yaw: 1.5, pitch: -0.25, dt_ms: 16, fly: false };
+        let text = encode(&ClientMessage::Inputs { inputs: vec![input] }).unwrap();
+        match decode::<ClientMessage>(&text).unwrap() {
+            ClientMessage::Inputs { inputs } => assert_eq!(inputs, vec![input]),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trips() {
+        let players = vec![PlayerState {
+            id: "a".into(),
+            name: "alice".into(),
+            position: (0.0, 5.0, 0.0),
+            rotation: (0.0, 0.0, 0.0),
+            last_input_seq: 7,
+        }];
+        let text = encode(&ServerMessage::Snapshot { server_time_ms: 1500, players }).unwrap();
+        assert!(matches!(
+            decode::<ServerMessage>(&text).unwrap(),
+            ServerMessage::Snapshot { server_time_ms: 1500, players } if players[0].name == "alice" && players[0].last_input_seq == 7
+        ));
+    }
+
+    #[test]
+    fn players_face_where_they_look() {
+        let player = PlayerState {
+            id: "a".into(),
+            name: String::new(),
+            position: (0.0, 5.0, 0.0),
+            rotation: (0.3, 2.0, 0.0),
+            last_input_seq: 0,
+        };
+        let (facing, looking) = (player.orientation().basis().forward, crate::math::Basis::view(2.0, 0.3).forward);
+        assert!(
+            (facing.0 - looking.0).abs() < 1e-5
+                && (facing.1 - looking.1).abs() < 1e-5
+                && (facing.2 - looking.2).abs() < 1e-5
+        );
+    }
+
+    #[test]
+    fn unknown_type_decodes_as_unknown() {
+        let text = format!(r#"{{"v":{},"type":"emote","name":"wave"}}"#, PROTOCOL_VERSION);
+        assert!(matches!(decode::<ServerMessage>(&text).unwrap(), ServerMessage::Unknown));
+        assert!(matches!(decode::<ClientMessage>(&text).unwrap(), ClientMessage::Unknown));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let text = r#"{"v":99,"type":"pong","nonce":1}"#;
+        assert!(matches!(decode::<ServerMessage>(text), Err(DecodeError::Version(99))));
+    }
+}
@@ -0,0 +1,1246 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex as TokioMutex, broadcast, watch};
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::Message as WsMessage;
+use uuid::Uuid;
+
+use three_dee::chunks::{CELL_SIZE, ChunkCoord, ChunkManager};
+use three_dee::codec::{Codec, Roster, decode_client};
+use three_dee::editing::HeightPatch;
+use three_dee::heightmap::{self, HeightmapScale, load_heightmap};
+use three_dee::movement::{Controller, Ground, Landing, MAX_INPUT_DT_MS, MoveInput, PLAYER_HEIGHT};
+use three_dee::protocol::{ClientMessage, PlayerState, ServerMessage, Welcome};
+use three_dee::terrain::Heightfield;
+use three_dee::world::{EdgeBehavior, WorldBounds};
+use three_dee::world_file::{WorldFile, load_world, save_world};
+use three_dee::worldgen::{TERRAIN_ORIGIN, TerrainPreset, WorldParams};
+
+const SERVER_ADDR: &str = "127.0.0.1:8080";
+const WS_PATH: &str = "/ws";
+const TICK_RATE: u32 = 20;
+const BROADCAST_CAPACITY: usize = 16;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_NAME_LEN: usize = 24;
+/// Longest chat message relayed, in characters; longer ones are dropped rather than cut.
+const MAX_CHAT_LEN: usize = 200;
+/// More inputs than this in one frame is more than any sane frame rate produces between sends.
+const MAX_INPUT_BATCH: usize = 64;
+/// How far above the ground in the middle of the play area players appear.
+const SPAWN_DROP: f32 = 15.0;
+/// Chunks generated around each player before their inputs are applied, enough for as
+/// far as their time budget lets one batch of inputs take them.
+const PLAYER_CHUNK_RADIUS: i32 = 1;
+/// How often a hosted world file is written, if anyone has edited the world since.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Game time a player can bank while their inputs are on the way, so late or bunched-up
+/// batches still go through whole.
+const MAX_BANKED_TIME: Duration = Duration::from_millis(500);
+/// What each brush stroke costs out of a connection's edit budget; clients send one every
+/// tenth of a second while the button is held.
+const EDIT_INTERVAL: Duration = Duration::from_millis(100);
+/// Strokes a connection can bank for a burst, on top of one every `EDIT_INTERVAL`.
+const MAX_BANKED_EDITS: u32 = 10;
+/// How far heights along opposite edges of the play area may differ for it to tile.
+const SEAM_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Parser)]
+#[command(about = "Don't tRUST multiplayer server")]
+struct Cli {
+    /// Keeps play to the play area, with this at its edge: wall, wrap or respawn. Without
+    /// it the world goes on forever
+    #[arg(long)]
+    edge: Option<EdgeBehavior>,
+    /// Terrain seed sent to every client; a random one when not given
+    #[arg(long)]
+    seed: Option<u32>,
+    /// Shape of the terrain: plains, mountains or islands
+    #[arg(long, default_value_t = TerrainPreset::Plains)]
+    preset: TerrainPreset,
+    /// Height of the water surface; only islands have water unless this is given
+    #[arg(long)]
+    sea_level: Option<f32>,
+    /// World file to host, which replaces the seed, preset and sea level. Created if it
+    /// does not exist yet, and saved as players edit the terrain and on Ctrl-C
+    #[arg(long)]
+    world: Option<PathBuf>,
+    /// Grayscale PNG or square 16-bit RAW heightmap to build the play area from, with the
+    /// seed's terrain carrying on around it
+    #[arg(long)]
+    heightmap: Option<PathBuf>,
+    /// World units between neighbouring heightmap pixels
+    #[arg(long, default_value_t = HeightmapScale::default().horizontal)]
+    horizontal_scale: f32,
+    /// Height of a white heightmap pixel in world units
+    #[arg(long, default_value_t = HeightmapScale::default().vertical)]
+    vertical_scale: f32,
+    /// Let players fly; without this their fly inputs are walked like any other
+    #[arg(long)]
+    allow_fly: bool,
+}
+
+struct Player {
+    state: PlayerState,
+    controller: Controller,
+    budget: TimeBudget,
+}
+
+/// How much time a player may still spend, such as game time to simulate. The server's
+/// clock fills it, and every input's frame time comes out of it, so however long clients
+/// say their frames took they cannot get ahead of real time.
+struct TimeBudget {
+    banked: Duration,
+    max: Duration,
+    filled_at: Instant,
+}
+
+impl TimeBudget {
+    /// Starts full, for what a client sends as soon as it is welcomed.
+    fn new(max: Duration, now: Instant) -> TimeBudget {
+        TimeBudget { banked: max, max, filled_at: now }
+    }
+
+    /// Adds the time that has passed since the last fill, up to the budget's `max`.
+    fn fill(&mut self, now: Instant) {
+        self.banked = (self.banked + now.saturating_duration_since(self.filled_at)).min(self.max);
+        self.filled_at = now;
+    }
+
+    /// Takes all of `cost` out of the budget, or nothing if there is not that much left.
+    fn try_spend(&mut self, cost: Duration) -> bool {
+        let Some(left) = self.banked.checked_sub(cost) else { return false };
+        self.banked = left;
+        true
+    }
+
+    /// Takes up to `dt_ms` out of the budget and returns how much it could.
+    fn spend(&mut self, dt_ms: u16) -> u16 {
+        let spent = dt_ms.min(u16::try_from(self.banked.as_millis()).unwrap_or(u16::MAX));
+        self.banked -= Duration::from_millis(spent.into());
+        spent
+    }
+}
+
+struct ServerState {
+    players: HashMap<String, Player>,
+    roster: Roster,
+    /// Only the chunks players are standing near; generated as they move.
+    terrain: ChunkManager,
+    /// Chunks being generated outside the lock, so nobody generates them twice.
+    pending_chunks: HashSet<ChunkCoord>,
+    /// Told whenever generated chunks are added to `terrain`.
+    chunks_added: watch::Sender<()>,
+    /// `None` for a world without edges.
+    bounds: Option<WorldBounds>,
+    spawn: (f32, f32, f32),
+    /// Off unless the server was started with `--allow-fly`.
+    allow_fly: bool,
+}
+
+impl ServerState {
+    /// A world with `edits` already made to it, such as the ones from a world file.
+    /// Without an `edge` the world has no bounds.
+    fn new(edge: Option<EdgeBehavior>, world: WorldParams, edits: Vec<HeightPatch>) -> ServerState {
+        let mut terrain = ChunkManager::new(world);
+        terrain.replace_edits(edits);
+        let (x, z) = spawn_point(world);
+        terrain.load_around(x, z, PLAYER_CHUNK_RADIUS);
+        let ground = terrain.height_at(x, z).unwrap_or(TERRAIN_ORIGIN.1);
+        let spawn = (x, ground + PLAYER_HEIGHT + SPAWN_DROP, z);
+        ServerState {
+            players: HashMap::new(),
+            roster: Roster::default(),
+            terrain,
+            pending_chunks: HashSet::new(),
+            chunks_added: watch::Sender::new(()),
+            bounds: edge.map(|edge| WorldBounds::around(TERRAIN_ORIGIN, world.size, spawn, edge)),
+            spawn,
+            allow_fly: false,
+        }
+    }
+
+    /// Forgets chunks no player is near any more. Spawn stays loaded for whoever joins next.
+    fn unload_distant_chunks(&mut self) {
+        let spawn = ChunkCoord::containing(self.spawn.0, self.spawn.2);
+        let centers: Vec<ChunkCoord> = self
+            .players
+            .values()
+            .map(|player| ChunkCoord::containing(player.controller.position.0, player.controller.position.2))
+            .chain([spawn])
+            .collect();
+        self.terrain.retain(|coord| centers.iter().any(|&center| coord.worth_keeping(center, PLAYER_CHUNK_RADIUS)));
+    }
+}
+
+type Shared = Arc<TokioMutex<ServerState>>;
+
+/// Makes sure the chunks within `PLAYER_CHUNK_RADIUS` of world (x, z) are loaded. Missing
+/// ones are generated on the blocking pool without holding the lock, and chunks another
+/// connection is already generating are waited for rather than generated twice.
+async fn load_chunks_around(shared: &Shared, x: f32, z: f32) {
+    loop {
+        let (claimed, generator, mut chunks_added) = {
+            let mut state = shared.lock().await;
+            let missing = state.terrain.missing_around(x, z, PLAYER_CHUNK_RADIUS);
+            if missing.is_empty() {
+                return;
+            }
+            let claimed: Vec<ChunkCoord> =
+                missing.into_iter().filter(|&coord| state.pending_chunks.insert(coord)).collect();
+            (claimed, state.terrain.generator().clone(), state.chunks_added.subscribe())
+        };
+        if claimed.is_empty() {
+            let _ = chunks_added.changed().await;
+            continue;
+        }
+        let coords = claimed.clone();
+        let generated = tokio::task::spawn_blocking(move || {
+            coords.into_iter().map(|coord| (coord, generator.chunk(coord))).collect()
+        })
+        .await;
+        let mut state = shared.lock().await;
+        for coord in &claimed {
+            state.pending_chunks.remove(coord);
+        }
+        let generated: Vec<(ChunkCoord, Heightfield)> = match generated {
+            Ok(generated) => generated,
+            // The generator would only panic again, so leave the chunks missing.
+            Err(e) => {
+                eprintln!("SERVER: Failed to generate chunks: {}", e);
+                state.chunks_added.send_replace(());
+                return;
+            }
+        };
+        for (coord, heights) in generated {
+            state.terrain.insert(coord, heights);
+        }
+        state.chunks_added.send_replace(());
+    }
+}
+
+/// Whether the play area repeats across its width and depth, so wrapping around its edge
+/// does not step onto a cliff: it is a whole number of cells across, and the heights along
+/// opposite edges match.
+fn tiles(terrain: &mut ChunkManager) -> bool {
+    let size = terrain.world().size;
+    let cells = (size.0 / CELL_SIZE, size.1 / CELL_SIZE);
+    if cells.0.fract() != 0.0 || cells.1.fract() != 0.0 {
+        return false;
+    }
+    let (width, depth) = (cells.0 as i64, cells.1 as i64);
+    let mut height = |x: i64, z: i64| {
+        terrain.load_around(TERRAIN_ORIGIN.0 + x as f32 * CELL_SIZE, TERRAIN_ORIGIN.2 + z as f32 * CELL_SIZE, 0);
+        terrain.sample(x, z).expect("the chunk was just loaded")
+    };
+    let matches = |a: f32, b: f32| (a - b).abs() <= SEAM_TOLERANCE;
+    (0..=depth).all(|z| matches(height(0, z), height(width, z)))
+        && (0..=width).all(|x| matches(height(x, 0), height(x, depth)))
+}
+
+/// Middle of the play area, where players drop in.
+fn spawn_point(world: WorldParams) -> (f32, f32) {
+    (TERRAIN_ORIGIN.0 + world.size.0 / 2.0, TERRAIN_ORIGIN.2 + world.size.1 / 2.0)
+}
+
+/// `world` with `heightmap` for its play area, sized to fit, and the generated terrain
+/// carrying on around it. The heightmap goes out to clients as the first edit.
+fn imported_world(mut world: WorldParams, heightmap: &Heightfield) -> (WorldParams, Vec<HeightPatch>) {
+    world.size = heightmap.size();
+    (world, vec![heightmap::terrain_patch(heightmap)])
+}
+
+/// One broadcast, already encoded for each codec so every connection can just pick.
+#[derive(Clone)]
+struct Frames {
+    json: WsMessage,
+    binary: WsMessage,
+}
+
+impl Frames {
+    fn new(msg: &ServerMessage, roster: &Roster) -> Frames {
+        Frames { json: Codec::Json.encode_server(msg, roster), binary: Codec::Binary.encode_server(msg, roster) }
+    }
+
+    fn pick(self, codec: Codec) -> WsMessage {
+        match codec {
+            Codec::Json => self.json,
+            Codec::Binary => self.binary,
+        }
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars().filter(|c| !c.is_control()).take(MAX_NAME_LEN).collect::<String>().trim().to_string()
+}
+
+/// The chat message to relay for `text`: without control characters or surrounding
+/// whitespace, or why it is dropped instead.
+fn sanitize_chat(text: &str) -> Result<String, &'static str> {
+    if text.chars().count() > MAX_CHAT_LEN {
+        return Err("too long");
+    }
+    let text = text.chars().filter(|c| !c.is_control()).collect::<String>().trim().to_string();
+    if text.is_empty() {
+        return Err("empty");
+    }
+    Ok(text)
+}
+
+/// Runs one client input through the shared character controller and world bounds.
+/// Inputs arriving late or twice are ignored, over-long frames are clamped and then cut
+/// down to what is left of the player's time budget, and unless `allow_fly` flying
+/// inputs are walked. Inputs with no time left are acknowledged without moving anyone,
+/// so their sender snaps back to where the server has them.
+fn apply_input(
+    player: &mut Player,
+    mut input: MoveInput,
+    ground: &impl Ground,
+    bounds: Option<&WorldBounds>,
+    allow_fly: bool,
+) -> Option<Landing> {
+    if input.seq <= player.state.last_input_seq {
+        return None;
+    }
+    player.state.rotation = (input.pitch, input.yaw, 0.0);
+    player.state.last_input_seq = input.seq;
+    input.dt_ms = player.budget.spend(input.dt_ms.min(MAX_INPUT_DT_MS));
+    if input.dt_ms == 0 {
+        return None;
+    }
+    input.fly &= allow_fly;
+    let landing = player.controller.step(&input, ground);
+    if bounds.is_some_and(|bounds| bounds.enforce(&mut player.controller)) {
+        println!("SERVER: {} fell out of the world", player.state.display_name());
+    }
+    player.state.position = player.controller.position;
+    landing
+}
+
+fn broadcast_roster(state: &ServerState, frames_tx: &broadcast::Sender<Frames>) {
+    let roster = ServerMessage::Roster { players: state.roster.entries() };
+    let _ = frames_tx.send(Frames::new(&roster, &state.roster));
+}
+
+async fn broadcast_loop(shared: Shared, frames_tx: broadcast::Sender<Frames>) {
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / TICK_RATE as f64));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let started = Instant::now();
+    loop {
+        interval.tick().await;
+        let frames = {
+            let mut state = shared.lock().await;
+            state.unload_distant_chunks();
+            if state.players.is_empty() {
+                continue;
+            }
+            let snapshot = ServerMessage::Snapshot {
+                server_time_ms: started.elapsed().as_millis() as u64,
+                players: state.players.values().map(|player| player.state.clone()).collect(),
+            };
+            Frames::new(&snapshot, &state.roster)
+        };
+        // Nobody subscribed is not an error, the next client will pick up the next tick.
+        let _ = frames_tx.send(frames);
+    }
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, shared: Shared, frames_tx: broadcast::Sender<Frames>) {
+    // The callback signature is fixed by tungstenite, so the large error type is not ours to shrink.
+    #[allow(clippy::result_large_err)]
+    let check_path = |request: &Request, response: Response| {
+        if request.uri().path() == WS_PATH {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(Some(format!("Expected {}", WS_PATH)));
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            Err(error)
+        }
+    };
+    let ws_stream = match accept_hdr_async(stream, check_path).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            eprintln!("SERVER: WebSocket handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+    let (mut write, mut read) = ws_stream.split();
+
+    let (name, codec) = match tokio::time::timeout(HELLO_TIMEOUT, read.next()).await {
+        Ok(Some(Ok(frame))) => match decode_client(&frame) {
+            Ok(ClientMessage::Hello { name, codec }) => (sanitize_name(&name), codec),
+            _ => {
+                eprintln!("SERVER: {} did not open with a hello", addr);
+                return;
+            }
+        },
+        _ => {
+            eprintln!("SERVER: {} never said hello", addr);
+            return;
+        }
+    };
+
+    let player_id = Uuid::new_v4().to_string();
+    // Subscribing under the same lock as copying the edit log means every edit is either
+    // in the welcome or comes after it as a patch, never both or neither.
+    let (your_index, spawn, bounds, world, edits, allow_fly, mut frames_rx) = {
+        let mut state = shared.lock().await;
+        let Some(index) = state.roster.join(&player_id) else {
+            eprintln!("SERVER: Turning {} away, no free player slots", addr);
+            return;
+        };
+        state.roster.rename(&player_id, &name);
+        let edits = state.terrain.edits().cloned().collect();
+        (index, state.spawn, state.bounds, state.terrain.world(), edits, state.allow_fly, frames_tx.subscribe())
+    };
+
+    let welcome = ServerMessage::Welcome(Welcome {
+        your_id: player_id.clone(),
+        your_index,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        tick_rate: TICK_RATE,
+        spawn,
+        codec,
+        bounds,
+        world,
+        edits,
+        allow_fly,
+    });
+    if write.send(codec.encode_server(&welcome, &Roster::default())).await.is_err() {
+        eprintln!("SERVER: Failed to welcome {}", addr);
+        shared.lock().await.roster.leave(&player_id);
+        return;
+    }
+
+    println!("SERVER: {} connected as {:?} ({}, {} codec)", addr, name, player_id, codec);
+    {
+        let mut state = shared.lock().await;
+        let spawn = state.spawn;
+        let player_state =
+            PlayerState { id: player_id.clone(), name, position: spawn, rotation: (0.0, 0.0, 0.0), last_input_seq: 0 };
+        let player = Player {
+            state: player_state,
+            controller: Controller::new(spawn),
+            budget: TimeBudget::new(MAX_BANKED_TIME, Instant::now()),
+        };
+        state.players.insert(player_id.clone(), player);
+        broadcast_roster(&state, &frames_tx);
+    }
+
+    let mut edit_budget = TimeBudget::new(EDIT_INTERVAL * MAX_BANKED_EDITS, Instant::now());
+    loop {
+        tokio::select! {
+            msg_result = read.next() => {
+                match msg_result {
+                    Some(Ok(frame @ (WsMessage::Text(_) | WsMessage::Binary(_)))) => {
+                        match decode_client(&frame) {
+                            Ok(ClientMessage::Hello { name, .. }) => {
+                                let name = sanitize_name(&name);
+                                println!("SERVER: {} is now called {:?}", player_id, name);
+                                let mut state = shared.lock().await;
+                                state.roster.rename(&player_id, &name);
+                                if let Some(player) = state.players.get_mut(&player_id) {
+                                    player.state.name = name;
+                                }
+                                broadcast_roster(&state, &frames_tx);
+                            }
+                            Ok(ClientMessage::Inputs { inputs }) => {
+                                let position = shared.lock().await.players.get(&player_id).map(|player| player.controller.position);
+                                let Some((x, _, z)) = position else { continue };
+                                load_chunks_around(&shared, x, z).await;
+                                let mut state = shared.lock().await;
+                                let ServerState { players, terrain, bounds, allow_fly, .. } = &mut *state;
+                                if let Some(player) = players.get_mut(&player_id) {
+                                    player.budget.fill(Instant::now());
+                                    for input in inputs.into_iter().take(MAX_INPUT_BATCH) {
+                                        if let Some(landing) = apply_input(player, input, terrain, bounds.as_ref(), *allow_fly)
+                                            && landing.damage > 0.0
+                                        {
+                                            // Nothing has health yet; this is where fall damage will land.
+                                            println!(
+                                                "SERVER: {} hit the ground at {:.0} u/s ({:.0} damage)",
+                                                player.state.display_name(),
+                                                landing.speed,
+                                                landing.damage
+                                            );
+                                        }
+                                    }
+                                }
+                            }
+                            Ok(ClientMessage::Edit(edit)) => {
+                                edit_budget.fill(Instant::now());
+                                if !edit_budget.try_spend(EDIT_INTERVAL) {
+                                    eprintln!("SERVER: Dropped edit from {}: editing too fast", player_id);
+                                    continue;
+                                }
+                                let editor = shared.lock().await.players.get(&player_id).map(|player| player.controller.position);
+                                let Some(editor) = editor else { continue };
+                                if let Err(reason) = edit.validate(editor) {
+                                    eprintln!("SERVER: Rejected edit from {}: {}", player_id, reason);
+                                    continue;
+                                }
+                                load_chunks_around(&shared, edit.center.0, edit.center.1).await;
+                                if let Some(patch) = shared.lock().await.terrain.apply_edit(&edit) {
+                                    let _ = frames_tx.send(Frames::new(&ServerMessage::TerrainPatch(patch), &Roster::default()));
+                                }
+                            }
+                            Ok(ClientMessage::Chat { text }) => {
+                                let text = match sanitize_chat(&text) {
+                                    Ok(text) => text,
+                                    Err(reason) => {
+                                        eprintln!("SERVER: Dropped chat from {}: {}", player_id, reason);
+                                        continue;
+                                    }
+                                };
+                                let chat = ServerMessage::Chat { from: player_id.clone(), text };
+                                let _ = frames_tx.send(Frames::new(&chat, &Roster::default()));
+                            }
+                            Ok(ClientMessage::Ping { nonce }) => {
+                                let pong = codec.encode_server(&ServerMessage::Pong { nonce }, &Roster::default());
+                                if write.send(pong).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(ClientMessage::Unknown) => {
+                                eprintln!("SERVER: Skipping unknown message from {}", player_id);
+                            }
+                            Err(e) => eprintln!("SERVER: Bad message from {}: {}", player_id, e),
+                        }
+                    }
+                    Some(Ok(WsMessage::Close(_))) | None => break,
+                    Some(Err(e)) => {
+                        eprintln!("SERVER: Read error from {}: {}", player_id, e);
+                        break;
+                    }
+                    Some(Ok(_)) => { /* Ignore other message types */ }
+                }
+            }
+            frame_result = frames_rx.recv() => {
+                match frame_result {
+                    Ok(frames) => {
+                        if write.send(frames.pick(codec)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        eprintln!("SERVER: {} lagged behind by {} ticks", player_id, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    let mut state = shared.lock().await;
+    state.roster.leave(&player_id);
+    if let Some(player) = state.players.remove(&player_id) {
+        println!("SERVER: {} ({}) disconnected", player.state.display_name(), player_id);
+    }
+    let left = ServerMessage::PlayerLeft { id: player_id };
+    let _ = frames_tx.send(Frames::new(&left, &Roster::default()));
+    broadcast_roster(&state, &frames_tx);
+}
+
+/// Writes the world in `shared` to `path`, logging how that went.
+async fn save(shared: &Shared, path: &Path) {
+    // Copy the log out so players are not kept waiting while the play area is generated.
+    let (world, edits) = {
+        let state = shared.lock().await;
+        (state.terrain.world(), state.terrain.edits().cloned().collect::<Vec<_>>())
+    };
+    let count = edits.len();
+    match save_world(path, &WorldFile::new(world, edits)) {
+        Ok(()) => println!("SERVER: Saved {} ({} edited chunks)", path.display(), count),
+        Err(e) => eprintln!("SERVER: Failed to save {}: {}", path.display(), e),
+    }
+}
+
+/// Saves the world every `AUTOSAVE_INTERVAL` if its terrain has been edited since `saved_revision`.
+async fn autosave_loop(shared: Shared, path: PathBuf, mut saved_revision: u64) {
+    let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let revision = shared.lock().await.terrain.revision();
+        if revision != saved_revision {
+            save(&shared, &path).await;
+            saved_revision = revision;
+        }
+    }
+}
+
+async fn run_server(listener: TcpListener, shared: Shared) {
+    let (frames_tx, _) = broadcast::channel::<Frames>(BROADCAST_CAPACITY);
+    tokio::spawn(broadcast_loop(shared.clone(), frames_tx.clone()));
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(handle_connection(stream, addr, shared.clone(), frames_tx.clone()));
+            }
+            Err(e) => eprintln!("SERVER: Failed to accept connection: {}", e),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let listener = match TcpListener::bind(SERVER_ADDR).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("SERVER: Failed to bind {}: {}", SERVER_ADDR, e);
+            return;
+        }
+    };
+    let (world, edits) = match &cli.world {
+        Some(path) if path.exists() => match load_world(path) {
+            Ok(file) => {
+                if cli.heightmap.is_some() {
+                    eprintln!("SERVER: Ignoring --heightmap, {} already holds a world", path.display());
+                }
+                let (world, count) = (file.world, file.edits.len());
+                match file.edit_log() {
+                    Ok(edits) => {
+                        println!("SERVER: Loaded {} ({} edited chunks)", path.display(), count);
+                        (world, edits)
+                    }
+                    Err(e) => {
+                        eprintln!("SERVER: Cannot host {}: {}", path.display(), e);
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                eprintln!("SERVER: Failed to load {}: {}", path.display(), e);
+                return;
+            }
+        },
+        _ => {
+            let mut world = WorldParams::new(cli.seed.unwrap_or_else(rand::random), cli.preset);
+            if let Some(sea_level) = cli.sea_level {
+                world.sea_level = Some(sea_level);
+            }
+            let scale = HeightmapScale { horizontal: cli.horizontal_scale, vertical: cli.vertical_scale };
+            match &cli.heightmap {
+                None => (world, Vec::new()),
+                Some(path) => match load_heightmap(path, scale) {
+                    Ok(heightmap) => {
+                        let (width, depth) = heightmap.samples();
+                        println!("SERVER: Imported {} ({}x{} pixels)", path.display(), width, depth);
+                        imported_world(world, &heightmap)
+                    }
+                    Err(e) => {
+                        eprintln!("SERVER: Failed to import {}: {}", path.display(), e);
+                        return;
+                    }
+                },
+            }
+        }
+    };
+    let sea = world.sea_level.map_or("no sea".to_string(), |level| format!("sea level {}", level));
+    let edge = cli.edge.map_or("no edge".to_string(), |edge| format!("{} at the world's edge", edge));
+    println!(
+        "SERVER: Listening on ws://{}{} ({} with seed {}, {}, {}{})",
+        SERVER_ADDR,
+        WS_PATH,
+        world.preset,
+        world.seed,
+        sea,
+        edge,
+        if cli.allow_fly { ", flying allowed" } else { "" }
+    );
+    let mut state = ServerState::new(cli.edge, world, edits);
+    if cli.edge == Some(EdgeBehavior::Wrap) && !tiles(&mut state.terrain) {
+        eprintln!("SERVER: Cannot wrap around terrain that does not tile; its opposite edges must match");
+        return;
+    }
+    state.allow_fly = cli.allow_fly;
+    let saved_revision = state.terrain.revision();
+    let shared: Shared = Arc::new(TokioMutex::new(state));
+    let Some(path) = cli.world else {
+        run_server(listener, shared).await;
+        return;
+    };
+    if !path.exists() {
+        save(&shared, &path).await;
+    }
+    tokio::spawn(autosave_loop(shared.clone(), path.clone(), saved_revision));
+    tokio::select! {
+        _ = run_server(listener, shared.clone()) => {}
+        _ = tokio::signal::ctrl_c() => save(&shared, &path).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use three_dee::bot::{BotConfig, Script, run_bot};
+    use three_dee::editing::{Brush, EDIT_REACH, TerrainEdit};
+    use three_dee::movement::{self, NoGround};
+    use three_dee::{chunks, codec, protocol, worldgen};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+    type TestSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    struct TestClient {
+        ws: TestSocket,
+        welcome: Welcome,
+        roster: Roster,
+    }
+
+    impl TestClient {
+        async fn connect(addr: SocketAddr, name: &str, codec: Codec) -> TestClient {
+            let (ws, _) = connect_async(format!("ws://{}{}", addr, WS_PATH)).await.unwrap();
+            let mut client = TestClient { ws, welcome: placeholder_welcome(), roster: Roster::default() };
+            client.send(&ClientMessage::Hello { name: name.into(), codec }).await;
+            match client.recv().await {
+                ServerMessage::Welcome(welcome) => client.welcome = welcome,
+                other => panic!("expected a welcome, got {:?}", other),
+            }
+            client
+        }
+
+        async fn send(&mut self, msg: &ClientMessage) {
+            let frame = self.welcome.codec.encode_client(msg);
+            self.ws.send(frame).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> ServerMessage {
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), self.ws.next()).await.unwrap() {
+                    Some(Ok(frame @ (WsMessage::Text(_) | WsMessage::Binary(_)))) => {
+                        let msg = codec::decode_server(&frame, &self.roster).unwrap();
+                        if let ServerMessage::Roster { players } = &msg {
+                            self.roster.replace(players.clone());
+                        }
+                        return msg;
+                    }
+                    Some(Ok(_)) => continue,
+                    other => panic!("connection ended: {:?}", other),
+                }
+            }
+        }
+    }
+
+    fn placeholder_welcome() -> Welcome {
+        Welcome {
+            your_id: String::new(),
+            your_index: 0,
+            server_version: String::new(),
+            tick_rate: 0,
+            spawn: (0.0, 0.0, 0.0),
+            codec: Codec::Json,
+            bounds: None,
+            world: worldgen::DEFAULT_WORLD,
+            edits: Vec::new(),
+            allow_fly: false,
+        }
+    }
+
+    const TEST_WORLD: WorldParams = WorldParams::new(7, TerrainPreset::Plains);
+
+    async fn spawn_server_hosting(state: ServerState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(run_server(listener, Arc::new(TokioMutex::new(state))));
+        addr
+    }
+
+    async fn spawn_server_with(edge: Option<EdgeBehavior>, world: WorldParams) -> SocketAddr {
+        spawn_server_hosting(ServerState::new(edge, world, Vec::new())).await
+    }
+
+    async fn spawn_server() -> SocketAddr {
+        spawn_server_with(None, TEST_WORLD).await
+    }
+
+    async fn inputs_move_the_player_like_the_client_predicts(codec: Codec) {
+        let addr = spawn_server().await;
+        let mut client = TestClient::connect(addr, "  alice\n", codec).await;
+        assert_eq!(client.welcome.tick_rate, TICK_RATE);
+        assert_eq!(client.welcome.codec, codec);
+
+        let mut terrain = ChunkManager::new(client.welcome.world);
+        terrain.load_around(client.welcome.spawn.0, client.welcome.spawn.2, PLAYER_CHUNK_RADIUS);
+        let mut predictor = movement::Predictor::new(client.welcome.spawn);
+        predictor.set_bounds(client.welcome.bounds);
+        let walk = MoveInput { forward: 1, yaw: 0.7, ..MoveInput::default() };
+        let inputs = (0..3).map(|_| predictor.apply(walk, 0.05, &terrain)).collect();
+        client.send(&ClientMessage::Inputs { inputs }).await;
+
+        let received = loop {
+            if let ServerMessage::Snapshot { players, .. } = client.recv().await
+                && let Some(state) = players.into_iter().find(|s| s.last_input_seq == 3)
+            {
+                break state;
+            }
+        };
+        assert_eq!(received.id, client.welcome.your_id);
+        assert_eq!(received.name, "alice");
+        assert!(Uuid::parse_str(&received.id).is_ok());
+        // Everything is acknowledged, so reconciling must not move the predicted player.
+        let predicted = predictor.position();
+        predictor.reconcile(received.last_input_seq, received.position, &terrain);
+        let error = (predictor.position().0 - predicted.0).abs() + (predictor.position().2 - predicted.2).abs();
+        assert!(error < 0.1, "server {:?} vs predicted {:?}", received.position, predicted);
+    }
+
+    #[tokio::test]
+    async fn applies_json_inputs() {
+        inputs_move_the_player_like_the_client_predicts(Codec::Json).await;
+    }
+
+    #[tokio::test]
+    async fn applies_binary_inputs() {
+        inputs_move_the_player_like_the_client_predicts(Codec::Binary).await;
+    }
+
+    /// What the server keeps of `patches` laid over `world`: one patch per edited chunk.
+    fn merged(world: WorldParams, patches: Vec<HeightPatch>) -> Vec<HeightPatch> {
+        let mut terrain = ChunkManager::new(world);
+        terrain.replace_edits(patches);
+        terrain.edits().cloned().collect()
+    }
+
+    /// A player who has just joined at `position`, with a full time budget.
+    fn player_at(position: (f32, f32, f32)) -> Player {
+        Player {
+            state: PlayerState {
+                id: "a".into(),
+                name: String::new(),
+                position,
+                rotation: (0.0, 0.0, 0.0),
+                last_input_seq: 0,
+            },
+            controller: Controller::new(position),
+            budget: TimeBudget::new(MAX_BANKED_TIME, Instant::now()),
+        }
+    }
+
+    #[test]
+    fn ignores_replayed_inputs_and_clamps_long_frames() {
+        let mut player = player_at((0.0, 0.0, 0.0));
+        let fly = MoveInput { forward: 1, fly: true, ..MoveInput::default() };
+        let bounds = WorldBounds::around((-100.0, -100.0, -100.0), (200.0, 200.0), (0.0, 0.0, 0.0), EdgeBehavior::Wall);
+        apply_input(&mut player, MoveInput { seq: 1, dt_ms: u16::MAX, ..fly }, &NoGround, Some(&bounds), true);
+        let max_step = movement::PLAYER_SPEED * MAX_INPUT_DT_MS as f32 / 1000.0;
+        assert!((player.state.position.2 + max_step).abs() < 1e-3, "{:?}", player.state.position);
+
+        apply_input(&mut player, MoveInput { seq: 1, dt_ms: 50, ..fly }, &NoGround, Some(&bounds), true);
+        assert!((player.state.position.2 + max_step).abs() < 1e-3);
+        assert_eq!(player.state.last_input_seq, 1);
+    }
+
+    #[test]
+    fn inputs_cannot_claim_more_time_than_has_passed() {
+        let mut player = player_at((0.0, 0.0, 0.0));
+        let bounds = WorldBounds::around((-100.0, -100.0, -100.0), (200.0, 200.0), (0.0, 0.0, 0.0), EdgeBehavior::Wall);
+        let fly = MoveInput { forward: 1, fly: true, dt_ms: MAX_INPUT_DT_MS, ..MoveInput::default() };
+        let banked_steps = (MAX_BANKED_TIME.as_millis() / u128::from(MAX_INPUT_DT_MS)) as u32;
+        for seq in 1..=banked_steps + 3 {
+            apply_input(&mut player, MoveInput { seq, ..fly }, &NoGround, Some(&bounds), true);
+        }
+        let step = movement::PLAYER_SPEED * MAX_INPUT_DT_MS as f32 / 1000.0;
+        assert!((player.state.position.2 + banked_steps as f32 * step).abs() < 1e-3, "{:?}", player.state.position);
+        assert_eq!(player.state.last_input_seq, banked_steps + 3);
+
+        // Another 30ms of real time buys another 30ms of movement, however long the frame.
+        let filled_at = player.budget.filled_at;
+        player.budget.fill(filled_at + Duration::from_millis(30));
+        let before = player.state.position.2;
+        apply_input(&mut player, MoveInput { seq: banked_steps + 4, ..fly }, &NoGround, Some(&bounds), true);
+        assert!((before - player.state.position.2 - movement::PLAYER_SPEED * 0.03).abs() < 1e-3);
+    }
+
+    #[test]
+    fn keeps_players_inside_the_world() {
+        let mut player = player_at((0.0, 0.0, -98.0));
+        let bounds = WorldBounds::around((-100.0, -100.0, -100.0), (200.0, 200.0), (0.0, 0.0, 0.0), EdgeBehavior::Wall);
+        apply_input(
+            &mut player,
+            MoveInput { seq: 1, forward: 1, fly: true, dt_ms: 100, ..MoveInput::default() },
+            &NoGround,
+            Some(&bounds),
+            true,
+        );
+        assert_eq!(player.state.position, (0.0, 0.0, -100.0));
+    }
+
+    #[test]
+    fn walks_flying_inputs_unless_flying_is_allowed() {
+        let bounds = WorldBounds::around((-100.0, -100.0, -100.0), (200.0, 200.0), (0.0, 0.0, 0.0), EdgeBehavior::Wall);
+        let climb = MoveInput { seq: 1, up: 1, fly: true, dt_ms: 100, ..MoveInput::default() };
+        let height_after = |allow_fly| {
+            let mut player = player_at((0.0, 0.0, 0.0));
+            apply_input(&mut player, climb, &NoGround, Some(&bounds), allow_fly);
+            player.state.position.1
+        };
+        assert!(height_after(true) > 0.0);
+        // Nothing to jump off, so a walker just falls.
+        assert!(height_after(false) < 0.0);
+    }
+
+    #[tokio::test]
+    async fn welcome_says_whether_flying_is_allowed() {
+        let client = TestClient::connect(spawn_server().await, "a", Codec::Json).await;
+        assert!(!client.welcome.allow_fly);
+        let mut state = ServerState::new(Some(EdgeBehavior::Wall), TEST_WORLD, Vec::new());
+        state.allow_fly = true;
+        let client = TestClient::connect(spawn_server_hosting(state).await, "a", Codec::Binary).await;
+        assert!(client.welcome.allow_fly);
+    }
+
+    #[tokio::test]
+    async fn welcome_carries_the_world_bounds() {
+        let addr = spawn_server_with(Some(EdgeBehavior::Respawn), TEST_WORLD).await;
+        let client = TestClient::connect(addr, "a", Codec::Binary).await;
+        let bounds = client.welcome.bounds.unwrap();
+        assert_eq!(bounds.edge, EdgeBehavior::Respawn);
+        assert_eq!((bounds.spawn.0, bounds.spawn.2), (-250.0, -250.0));
+        assert_eq!((bounds.min.0, bounds.max.0), (TERRAIN_ORIGIN.0, TERRAIN_ORIGIN.0 + worldgen::MAP_SIZE.0));
+    }
+
+    #[tokio::test]
+    async fn worlds_without_an_edge_go_on_forever() {
+        // A play area 40 units across, which a second and a half of walking east is plenty to leave.
+        let heightmap = Heightfield::new(2, 2, vec![0.0; 4], TERRAIN_ORIGIN, (40.0, 40.0));
+        let (world, edits) = imported_world(TEST_WORLD, &heightmap);
+        let addr = spawn_server_hosting(ServerState::new(None, world, edits)).await;
+        let mut client = TestClient::connect(addr, "a", Codec::Binary).await;
+        assert_eq!(client.welcome.bounds, None);
+        let east = MoveInput { forward: 1, yaw: std::f32::consts::FRAC_PI_2, dt_ms: 100, ..MoveInput::default() };
+        for batch in 0..3 {
+            let inputs = (1..=5).map(|n| MoveInput { seq: batch * 5 + n, ..east }).collect();
+            client.send(&ClientMessage::Inputs { inputs }).await;
+            tokio::time::sleep(MAX_BANKED_TIME).await;
+        }
+        let end = TERRAIN_ORIGIN.0 + 40.0;
+        loop {
+            if let ServerMessage::Snapshot { players, .. } = client.recv().await
+                && players[0].last_input_seq == 15
+            {
+                assert!(players[0].position.0 > end + 10.0, "{:?}", players[0].position);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn only_terrain_that_tiles_can_wrap() {
+        let tiles_with = |levels: Vec<f32>, size: (f32, f32)| {
+            let (world, edits) = imported_world(TEST_WORLD, &Heightfield::new(3, 3, levels, TERRAIN_ORIGIN, size));
+            tiles(&mut ServerState::new(Some(EdgeBehavior::Wrap), world, edits).terrain)
+        };
+        let bump = vec![0.0, 0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 0.0];
+        assert!(tiles_with(bump.clone(), (16.0 * CELL_SIZE, 16.0 * CELL_SIZE)));
+        // Matching edges do not help if the width falls between two grid samples.
+        assert!(!tiles_with(bump, (16.5 * CELL_SIZE, 16.0 * CELL_SIZE)));
+        let ramp = [0.0, 5.0, 10.0].repeat(3);
+        assert!(!tiles_with(ramp, (16.0 * CELL_SIZE, 16.0 * CELL_SIZE)));
+        assert!(!tiles(&mut ChunkManager::new(TEST_WORLD)));
+    }
+
+    #[tokio::test]
+    async fn chunks_are_generated_once_for_everyone_waiting_on_them() {
+        let shared = Arc::new(TokioMutex::new(ServerState::new(None, TEST_WORLD, Vec::new())));
+        let far = (3000.0, -2000.0);
+        tokio::join!(load_chunks_around(&shared, far.0, far.1), load_chunks_around(&shared, far.0 + 10.0, far.1));
+        let state = shared.lock().await;
+        assert!(state.pending_chunks.is_empty());
+        assert!(state.terrain.missing_around(far.0, far.1, PLAYER_CHUNK_RADIUS).is_empty());
+        assert!(state.terrain.height_at(far.0, far.1).is_some());
+    }
+
+    #[tokio::test]
+    async fn welcome_carries_the_world_params() {
+        let world = WorldParams { sea_level: Some(12.5), ..WorldParams::new(1234, TerrainPreset::Mountains) };
+        let addr = spawn_server_with(Some(EdgeBehavior::Wall), world).await;
+        let (a, b) =
+            tokio::join!(TestClient::connect(addr, "a", Codec::Json), TestClient::connect(addr, "b", Codec::Binary));
+        assert_eq!((a.welcome.world, b.welcome.world), (world, world));
+    }
+
+    #[test]
+    fn distant_chunks_are_dropped_once_nobody_is_near() {
+        let mut state = ServerState::new(Some(EdgeBehavior::Respawn), TEST_WORLD, Vec::new());
+        let far = (3000.0, -2000.0);
+        state.terrain.load_around(far.0, far.1, PLAYER_CHUNK_RADIUS);
+        assert!(state.terrain.height_at(far.0, far.1).is_some());
+        state.unload_distant_chunks();
+        assert!(state.terrain.height_at(far.0, far.1).is_none());
+        assert!(state.terrain.height_at(state.spawn.0, state.spawn.2).is_some());
+    }
+
+    #[test]
+    fn players_spawn_above_the_ground_whatever_the_terrain() {
+        for preset in [TerrainPreset::Plains, TerrainPreset::Mountains, TerrainPreset::Islands] {
+            let state = ServerState::new(Some(EdgeBehavior::Wall), WorldParams::new(3, preset), Vec::new());
+            let (x, z) = spawn_point(state.terrain.world());
+            let ground = state.terrain.height_at(x, z).unwrap();
+            assert_eq!(state.spawn, (x, ground + PLAYER_HEIGHT + SPAWN_DROP, z));
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_clients_get_distinct_ids() {
+        let addr = spawn_server().await;
+        let (a, b) =
+            tokio::join!(TestClient::connect(addr, "a", Codec::Json), TestClient::connect(addr, "b", Codec::Binary));
+        assert_ne!(a.welcome.your_id, b.welcome.your_id);
+        assert_ne!(a.welcome.your_index, b.welcome.your_index);
+    }
+
+    #[tokio::test]
+    async fn skips_unknown_messages_and_answers_pings() {
+        let addr = spawn_server().await;
+        let mut client = TestClient::connect(addr, "a", Codec::Json).await;
+
+        let emote = format!(r#"{{"v":{},"type":"emote","name":"wave"}}"#, protocol::PROTOCOL_VERSION);
+        client.ws.send(WsMessage::Text(emote.into())).await.unwrap();
+        client.send(&ClientMessage::Ping { nonce: 42 }).await;
+
+        loop {
+            if let ServerMessage::Pong { nonce } = client.recv().await {
+                assert_eq!(nonce, 42);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_chat_to_everyone() {
+        let addr = spawn_server().await;
+        let mut a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Binary).await;
+
+        a.send(&ClientMessage::Chat { text: "hello".into() }).await;
+
+        loop {
+            if let ServerMessage::Chat { from, text } = b.recv().await {
+                assert_eq!(from, a.welcome.your_id);
+                assert_eq!(text, "hello");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_empty_and_oversized_chat_and_strips_control_characters() {
+        let addr = spawn_server().await;
+        let mut a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Json).await;
+
+        for text in ["x".repeat(MAX_CHAT_LEN + 1), " \u{7}\n ".into(), "\u{1b}[2Jhi\r\nthere ".into()] {
+            a.send(&ClientMessage::Chat { text }).await;
+        }
+
+        loop {
+            if let ServerMessage::Chat { text, .. } = b.recv().await {
+                assert_eq!(text, "[2Jhithere");
+                break;
+            }
+        }
+        assert_eq!(sanitize_chat(&"é".repeat(MAX_CHAT_LEN)).unwrap().chars().count(), MAX_CHAT_LEN);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_edits_and_welcomes_late_joiners_with_them() {
+        let addr = spawn_server().await;
+        let mut a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Binary).await;
+        assert!(a.welcome.edits.is_empty());
+
+        let spawn = a.welcome.spawn;
+        let edit = TerrainEdit { brush: Brush::Raise, center: (spawn.0, spawn.2), radius: 10.0, strength: 1.0 };
+        a.send(&ClientMessage::Edit(edit)).await;
+
+        let patch = loop {
+            if let ServerMessage::TerrainPatch(patch) = b.recv().await {
+                break patch;
+            }
+        };
+        let mut terrain = ChunkManager::new(b.welcome.world);
+        terrain.load_around(spawn.0, spawn.2, PLAYER_CHUNK_RADIUS);
+        let before = terrain.height_at(spawn.0, spawn.2).unwrap();
+        terrain.apply_patch(patch.clone());
+        assert!(terrain.height_at(spawn.0, spawn.2).unwrap() > before);
+
+        let c = TestClient::connect(addr, "c", Codec::Json).await;
+        assert_eq!(c.welcome.edits, merged(b.welcome.world, vec![patch]));
+    }
+
+    #[tokio::test]
+    async fn hosts_the_world_in_a_world_file() {
+        let path = std::env::temp_dir().join(format!("three-dee-server-{}.world", std::process::id()));
+        let world = WorldParams::new(21, TerrainPreset::Islands);
+        let mut state = ServerState::new(Some(EdgeBehavior::Wall), world, Vec::new());
+        let spawn = state.spawn;
+        let edit = TerrainEdit { brush: Brush::Raise, center: (spawn.0, spawn.2), radius: 10.0, strength: 1.0 };
+        let patch = state.terrain.apply_edit(&edit).unwrap();
+        let shared = Arc::new(TokioMutex::new(state));
+        save(&shared, &path).await;
+
+        let file = load_world(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let edits = merged(world, vec![patch]);
+        assert_eq!((file.world, &file.edits), (world, &edits));
+        let addr =
+            spawn_server_hosting(ServerState::new(Some(EdgeBehavior::Wall), file.world, file.edit_log().unwrap()))
+                .await;
+        let client = TestClient::connect(addr, "a", Codec::Json).await;
+        assert_eq!((client.welcome.world, client.welcome.edits), (world, edits));
+        // Players drop in above the edited ground, not the generated one.
+        assert!(client.welcome.spawn.1 > spawn.1);
+    }
+
+    #[tokio::test]
+    async fn hosts_imported_heightmaps() {
+        // A 4x3 pixel ramp, 40 units a pixel.
+        let levels = [0.0, 10.0, 20.0, 30.0].repeat(3);
+        let heightmap = Heightfield::new(4, 3, levels, TERRAIN_ORIGIN, (120.0, 80.0));
+        let (world, edits) = imported_world(TEST_WORLD, &heightmap);
+        let addr = spawn_server_hosting(ServerState::new(Some(EdgeBehavior::Wall), world, edits.clone())).await;
+        let client = TestClient::connect(addr, "a", Codec::Binary).await;
+
+        assert_eq!(client.welcome.world.size, (120.0, 80.0));
+        assert_eq!(client.welcome.edits, merged(world, edits));
+        let bounds = client.welcome.bounds.unwrap();
+        assert_eq!(bounds.max, (TERRAIN_ORIGIN.0 + 120.0, TERRAIN_ORIGIN.2 + 80.0));
+        // Dropped into the middle of the ramp, 15 units up.
+        let (x, y, z) = client.welcome.spawn;
+        assert_eq!((x, z), (TERRAIN_ORIGIN.0 + 60.0, TERRAIN_ORIGIN.2 + 40.0));
+        assert!((y - (15.0 + PLAYER_HEIGHT + SPAWN_DROP)).abs() < 0.5, "spawned at {}", y);
+    }
+
+    #[tokio::test]
+    async fn ignores_edits_out_of_reach() {
+        let addr = spawn_server().await;
+        let mut a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Json).await;
+
+        let spawn = a.welcome.spawn;
+        let near = TerrainEdit { brush: Brush::Lower, center: (spawn.0, spawn.2), radius: 10.0, strength: 0.5 };
+        let far = TerrainEdit { center: (spawn.0 + 2.0 * EDIT_REACH, spawn.2), ..near };
+        let huge = TerrainEdit { radius: 1000.0, ..near };
+        for edit in [far, huge, near] {
+            a.send(&ClientMessage::Edit(edit)).await;
+        }
+
+        // Edits from one client are handled in order, so the first patch is the only valid one.
+        let patch = loop {
+            if let ServerMessage::TerrainPatch(patch) = b.recv().await {
+                break patch;
+            }
+        };
+        let cells = (2.0 * near.radius / chunks::CELL_SIZE).ceil() as usize;
+        assert!(patch.width <= cells + 2, "{:?}", patch.width);
+        let c = TestClient::connect(addr, "c", Codec::Json).await;
+        assert_eq!(c.welcome.edits, merged(a.welcome.world, vec![patch]));
+    }
+
+    #[tokio::test]
+    async fn drops_edits_sent_too_fast() {
+        let addr = spawn_server().await;
+        let mut a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Json).await;
+
+        let spawn = a.welcome.spawn;
+        let edit = TerrainEdit { brush: Brush::Raise, center: (spawn.0, spawn.2), radius: 5.0, strength: 0.1 };
+        let started = Instant::now();
+        let strokes = 3 * MAX_BANKED_EDITS;
+        for _ in 0..strokes {
+            a.send(&ClientMessage::Edit(edit)).await;
+        }
+        // The chat is handled after every stroke, so its broadcast comes after their patches.
+        a.send(&ClientMessage::Chat { text: "done".into() }).await;
+
+        let mut patches = 0;
+        loop {
+            match b.recv().await {
+                ServerMessage::TerrainPatch(_) => patches += 1,
+                ServerMessage::Chat { .. } => break,
+                _ => {}
+            }
+        }
+        let refilled = (started.elapsed().as_millis() / EDIT_INTERVAL.as_millis()) as u32;
+        assert!(patches >= MAX_BANKED_EDITS && patches <= MAX_BANKED_EDITS + refilled, "{} patches", patches);
+        assert!(patches < strokes, "{} patches", patches);
+    }
+
+    #[tokio::test]
+    async fn announces_players_that_leave() {
+        let addr = spawn_server().await;
+        let a = TestClient::connect(addr, "a", Codec::Json).await;
+        let mut b = TestClient::connect(addr, "b", Codec::Binary).await;
+        let a_id = a.welcome.your_id.clone();
+        drop(a);
+
+        loop {
+            if let ServerMessage::PlayerLeft { id } = b.recv().await {
+                assert_eq!(id, a_id);
+                break;
+            }
+        }
+        match b.recv().await {
+            ServerMessage::Roster { players } => assert!(players.iter().all(|entry| entry.id != a_id)),
+            other => panic!("expected the new roster, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_clients_that_skip_hello() {
+        let addr = spawn_server().await;
+        let (mut ws, _) = connect_async(format!("ws://{}{}", addr, WS_PATH)).await.unwrap();
+        ws.send(Codec::Json.encode_client(&ClientMessage::Ping { nonce: 1 })).await.unwrap();
+        let next = tokio::time::timeout(Duration::from_secs(5), ws.next()).await.unwrap();
+        assert!(matches!(next, None | Some(Ok(WsMessage::Close(_))) | Some(Err(_))), "got {:?}", next);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_paths() {
+        let addr = spawn_server().await;
+        assert!(connect_async(format!("ws://{}/nope", addr)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bots_play_against_the_server_and_measure_their_connection() {
+        let addr = spawn_server().await;
+        let bots: Vec<_> = (0..4u64)
+            .map(|n| {
+                tokio::spawn(run_bot(BotConfig {
+                    server_url: format!("ws://{}{}", addr, WS_PATH).parse().unwrap(),
+                    name: format!("bot-{}", n),
+                    codec: if n == 3 { Codec::Json } else { Codec::Binary },
+                    send_rate: 30,
+                    script: if n % 2 == 0 { Script::RandomWalk } else { Script::Circle },
+                    seed: n,
+                    duration: Duration::from_millis(1500),
+                }))
+            })
+            .collect();
+        let mut reports = Vec::new();
+        for bot in bots {
+            reports.push(bot.await.unwrap());
+        }
+
+        for report in &reports {
+            assert!(report.welcomed, "{}", report);
+            assert!(report.inputs_sent > 30, "{}", report);
+            assert!(report.traffic.sent_frames > 10 && report.traffic.received_frames > 10, "{}", report);
+            // Every batch but the last few is acknowledged well within a second.
+            assert!(report.latencies.len() > 10, "{}", report);
+            assert!(report.latency_percentile(0.5).unwrap() < Duration::from_secs(1), "{}", report);
+        }
+        // Snapshots carry every bot, so JSON costs more to receive than binary.
+        assert!(reports[3].download_rate() > reports[1].download_rate());
+    }
+}
@@ -0,0 +1,389 @@
+use crate::movement::Ground;
+
+/// How far past its edges, in cells, a heightfield still answers. Rounding can put a point
+/// on the border between two chunks a hair outside both of them.
+const EDGE_TOLERANCE: f32 = 1e-4;
+
+/// Terrain heights on a regular grid, split into triangles the same way for collision
+/// and for drawing, so what you stand on is what gets drawn.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightfield {
+    width: usize,
+    depth: usize,
+    /// World position of grid vertex (0, 0) at height zero.
+    origin: (f32, f32, f32),
+    cell_width: f32,
+    cell_depth: f32,
+    /// Heights above `origin.1`, row by row along z.
+    heights: Vec<f32>,
+}
+
+impl Heightfield {
+    /// `heights` holds `width * depth` samples, row by row along z, and the grid spans
+    /// `size.0` by `size.1` world units from `origin`.
+    pub fn new(
+        width: usize,
+        depth: usize,
+        heights: Vec<f32>,
+        origin: (f32, f32, f32),
+        size: (f32, f32),
+    ) -> Heightfield {
+        assert!(width >= 2 && depth >= 2, "a heightfield needs at least 2x2 samples, got {}x{}", width, depth);
+        assert_eq!(heights.len(), width * depth, "expected {}x{} height samples", width, depth);
+        Heightfield {
+            width,
+            depth,
+            origin,
+            cell_width: size.0 / (width - 1) as f32,
+            cell_depth: size.1 / (depth - 1) as f32,
+            heights,
+        }
+    }
+
+    /// Number of samples along x and z.
+    pub fn samples(&self) -> (usize, usize) {
+        (self.width, self.depth)
+    }
+
+    /// Width and depth the grid spans in world units.
+    pub fn size(&self) -> (f32, f32) {
+        ((self.width - 1) as f32 * self.cell_width, (self.depth - 1) as f32 * self.cell_depth)
+    }
+
+    /// Height of grid vertex (x, z) above `origin.1`.
+    pub fn sample(&self, x: usize, z: usize) -> f32 {
+        self.heights[z * self.width + x]
+    }
+
+    pub fn set_sample(&mut self, x: usize, z: usize, height: f32) {
+        self.heights[z * self.width + x] = height;
+    }
+
+    /// The cell containing world (x, z) and the position inside it (0..=1 on each axis),
+    /// or `None` off the grid.
+    fn locate(&self, x: f32, z: f32) -> Option<(usize, usize, f32, f32)> {
+        let grid_x = (x - self.origin.0) / self.cell_width;
+        let grid_z = (z - self.origin.2) / self.cell_depth;
+        let max_x = (self.width - 1) as f32;
+        let max_z = (self.depth - 1) as f32;
+        let (range_x, range_z) = (-EDGE_TOLERANCE..=max_x + EDGE_TOLERANCE, -EDGE_TOLERANCE..=max_z + EDGE_TOLERANCE);
+        if !range_x.contains(&grid_x) || !range_z.contains(&grid_z) {
+            return None;
+        }
+        let (grid_x, grid_z) = (grid_x.clamp(0.0, max_x), grid_z.clamp(0.0, max_z));
+        // The far edges belong to the last cell rather than to one past the grid.
+        let cell_x = (grid_x as usize).min(self.width - 2);
+        let cell_z = (grid_z as usize).min(self.depth - 2);
+        Some((cell_x, cell_z, grid_x - cell_x as f32, grid_z - cell_z as f32))
+    }
+
+    /// Height of the terrain surface at world (x, z), or `None` off the grid.
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let (cell_x, cell_z, u, v) = self.locate(x, z)?;
+        let h00 = self.sample(cell_x, cell_z);
+        let h10 = self.sample(cell_x + 1, cell_z);
+        let h01 = self.sample(cell_x, cell_z + 1);
+        let h11 = self.sample(cell_x + 1, cell_z + 1);
+
+        // Each cell is cut along the diagonal from (x + 1, z) to (x, z + 1).
+        let height = if u + v <= 1.0 {
+            h00 + u * (h10 - h00) + v * (h01 - h00)
+        } else {
+            h11 + (1.0 - u) * (h01 - h11) + (1.0 - v) * (h10 - h11)
+        };
+        Some(self.origin.1 + height)
+    }
+
+    /// Unit normal of the triangle under world (x, z), pointing up, or `None` off the grid.
+    pub fn normal_at(&self, x: f32, z: f32) -> Option<(f32, f32, f32)> {
+        let (cell_x, cell_z, u, v) = self.locate(x, z)?;
+        let h00 = self.sample(cell_x, cell_z);
+        let h10 = self.sample(cell_x + 1, cell_z);
+        let h01 = self.sample(cell_x, cell_z + 1);
+        let h11 = self.sample(cell_x + 1, cell_z + 1);
+
+        let (rise_x, rise_z) = if u + v <= 1.0 { (h10 - h00, h01 - h00) } else { (h11 - h01, h11 - h10) };
+        let (slope_x, slope_z) = (rise_x / self.cell_width, rise_z / self.cell_depth);
+        let length = (slope_x * slope_x + 1.0 + slope_z * slope_z).sqrt();
+        Some((-slope_x / length, 1.0 / length, -slope_z / length))
+    }
+
+    /// Lowest sample, relative to `origin.1`.
+    pub fn lowest(&self) -> f32 {
+        self.heights.iter().copied().fold(f32::INFINITY, f32::min)
+    }
+
+    /// Two counter-clockwise triangles per `step` by `step` block of cells, cut along the
+    /// same diagonal `height_at` uses, with each vertex colored by `color` at its grid
+    /// position. A `step` of 1 draws exactly the collision surface; coarser steps skip the
+    /// samples in between.
+    pub fn mesh(&self, step: usize, color: impl Fn(usize, usize) -> [u8; 4]) -> MeshData {
+        self.check_step(step);
+        let quads = (self.width - 1) / step * ((self.depth - 1) / step);
+        let mut mesh = MeshData {
+            positions: Vec::with_capacity(quads * 18),
+            normals: Vec::with_capacity(quads * 18),
+            texcoords: Vec::with_capacity(quads * 12),
+            colors: Vec::with_capacity(quads * 24),
+        };
+        let vertex = |x: usize, z: usize| self.vertex(x, z, self.sample(x, z), &color);
+        for z in (0..self.depth - 1).step_by(step) {
+            for x in (0..self.width - 1).step_by(step) {
+                mesh.push_triangle([vertex(x, z), vertex(x, z + step), vertex(x + step, z)]);
+                mesh.push_triangle([vertex(x + step, z), vertex(x, z + step), vertex(x + step, z + step)]);
+            }
+        }
+        mesh
+    }
+
+    /// Adds walls hanging from every edge of a mesh built with the same `step` down to
+    /// `bottom`, facing outwards. They fill the gaps where a neighbour drawn at another
+    /// step meets this one at slightly different heights.
+    pub fn add_skirts(&self, mesh: &mut MeshData, step: usize, bottom: f32, color: impl Fn(usize, usize) -> [u8; 4]) {
+        self.check_step(step);
+        let (last_x, last_z) = (self.width - 1, self.depth - 1);
+        // Once round the edge with the inside on the right, looking down: north, east,
+        // south, then west.
+        let mut corners: Vec<(usize, usize)> = Vec::new();
+        corners.extend((0..last_x).step_by(step).map(|x| (x, 0)));
+        corners.extend((0..last_z).step_by(step).map(|z| (last_x, z)));
+        corners.extend((step..=last_x).rev().step_by(step).map(|x| (x, last_z)));
+        corners.extend((step..=last_z).rev().step_by(step).map(|z| (0, z)));
+        corners.push((0, 0));
+        let top = |(x, z): (usize, usize)| self.vertex(x, z, self.sample(x, z), &color);
+        let foot = |(x, z): (usize, usize)| self.vertex(x, z, bottom, &color);
+        for pair in corners.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            mesh.push_triangle([top(a), top(b), foot(a)]);
+            mesh.push_triangle([top(b), foot(b), foot(a)]);
+        }
+    }
+
+    fn check_step(&self, step: usize) {
+        assert!(
+            step > 0 && (self.width - 1).is_multiple_of(step) && (self.depth - 1).is_multiple_of(step),
+            "a step of {} does not divide {}x{} cells",
+            step,
+            self.width - 1,
+            self.depth - 1
+        );
+    }
+
+    fn vertex(&self, x: usize, z: usize, height: f32, color: impl Fn(usize, usize) -> [u8; 4]) -> Vertex {
+        Vertex {
+            position: (x as f32 * self.cell_width, height, z as f32 * self.cell_depth),
+            texcoord: (x as f32 / (self.width - 1) as f32, z as f32 / (self.depth - 1) as f32),
+            color: color(x, z),
+        }
+    }
+}
+
+/// Vertex arrays for drawing a heightfield: three floats per position and normal, two
+/// per texture coordinate, four bytes per RGBA color, three vertices per triangle and no
+/// index buffer.
+#[derive(Debug, Clone)]
+pub struct MeshData {
+    /// Relative to the heightfield's origin.
+    pub positions: Vec<f32>,
+    /// One flat normal per triangle, repeated for each of its vertices.
+    pub normals: Vec<f32>,
+    /// The whole grid maps onto 0..=1 once.
+    pub texcoords: Vec<f32>,
+    pub colors: Vec<u8>,
+}
+
+struct Vertex {
+    position: (f32, f32, f32),
+    texcoord: (f32, f32),
+    color: [u8; 4],
+}
+
+impl MeshData {
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len() / 3
+    }
+
+    fn push_triangle(&mut self, corners: [Vertex; 3]) {
+        let [a, b, c] = [corners[0].position, corners[1].position, corners[2].position];
+        let (ab, ac) = ((b.0 - a.0, b.1 - a.1, b.2 - a.2), (c.0 - a.0, c.1 - a.1, c.2 - a.2));
+        let normal = (ab.1 * ac.2 - ab.2 * ac.1, ab.2 * ac.0 - ab.0 * ac.2, ab.0 * ac.1 - ab.1 * ac.0);
+        let length = (normal.0 * normal.0 + normal.1 * normal.1 + normal.2 * normal.2).sqrt();
+        for corner in corners {
+            self.positions.extend([corner.position.0, corner.position.1, corner.position.2]);
+            self.normals.extend([normal.0 / length, normal.1 / length, normal.2 / length]);
+            self.texcoords.extend([corner.texcoord.0, corner.texcoord.1]);
+            self.colors.extend(corner.color);
+        }
+    }
+}
+
+impl Ground for Heightfield {
+    fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        Heightfield::height_at(self, x, z)
+    }
+
+    fn normal_at(&self, x: f32, z: f32) -> Option<(f32, f32, f32)> {
+        Heightfield::normal_at(self, x, z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_fn(width: usize, depth: usize, size: (f32, f32), height: impl Fn(f32, f32) -> f32) -> Heightfield {
+        let (cell_width, cell_depth) = (size.0 / (width - 1) as f32, size.1 / (depth - 1) as f32);
+        let heights = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| height(x as f32 * cell_width, z as f32 * cell_depth))
+            .collect();
+        Heightfield::new(width, depth, heights, (0.0, 0.0, 0.0), size)
+    }
+
+    fn assert_height(field: &Heightfield, x: f32, z: f32, expected: f32) {
+        let height = field.height_at(x, z).unwrap();
+        assert!((height - expected).abs() < 1e-3, "height at ({}, {}) is {}, expected {}", x, z, height, expected);
+    }
+
+    #[test]
+    fn flat_plane_is_flat_everywhere() {
+        let field = from_fn(5, 4, (40.0, 30.0), |_, _| 7.5);
+        for (x, z) in [(0.0, 0.0), (3.3, 9.9), (17.0, 21.0), (40.0, 30.0)] {
+            assert_height(&field, x, z, 7.5);
+        }
+    }
+
+    #[test]
+    fn tilted_planes_are_reproduced_exactly() {
+        // Any plane is linear within both triangles of every cell.
+        let plane = |x: f32, z: f32| 2.0 + 0.5 * x - 0.25 * z;
+        let field = from_fn(6, 6, (50.0, 50.0), plane);
+        for (x, z) in [(1.0, 1.0), (9.0, 9.0), (12.3, 47.1), (33.3, 5.5), (50.0, 0.0)] {
+            assert_height(&field, x, z, plane(x, z));
+        }
+    }
+
+    #[test]
+    fn follows_the_triangle_split_of_the_mesh() {
+        // One cell with only the far corner raised: the first triangle stays flat and the
+        // second one ramps up towards (1, 1).
+        let field = Heightfield::new(2, 2, vec![0.0, 0.0, 0.0, 10.0], (0.0, 0.0, 0.0), (1.0, 1.0));
+        assert_height(&field, 0.3, 0.3, 0.0);
+        assert_height(&field, 0.5, 0.5, 0.0);
+        assert_height(&field, 0.75, 0.75, 5.0);
+        assert_height(&field, 1.0, 0.5, 5.0);
+        assert_height(&field, 1.0, 1.0, 10.0);
+    }
+
+    #[test]
+    fn slopes_are_continuous_across_cells() {
+        let field = from_fn(9, 9, (80.0, 80.0), |x, z| (x * 0.1).sin() * 20.0 + z * 0.3);
+        let mut previous = field.height_at(0.0, 33.0).unwrap();
+        for step in 1..=800 {
+            let height = field.height_at(step as f32 * 0.1, 33.0).unwrap();
+            assert!((height - previous).abs() < 0.5, "jump of {} at x = {}", height - previous, step as f32 * 0.1);
+            previous = height;
+        }
+    }
+
+    #[test]
+    fn respects_origin_and_rejects_points_off_the_grid() {
+        let field = Heightfield::new(2, 2, vec![1.0; 4], (-500.0, 3.0, -500.0), (500.0, 500.0));
+        assert_height(&field, -250.0, -250.0, 4.0);
+        assert!(field.height_at(-501.0, -250.0).is_none());
+        assert!(field.height_at(-250.0, 0.5).is_none());
+        assert_height(&field, -500.001, -250.0, 4.0);
+    }
+
+    #[test]
+    fn normals_point_away_from_the_slope() {
+        let flat = from_fn(3, 3, (20.0, 20.0), |_, _| 4.0);
+        assert_eq!(flat.normal_at(5.0, 15.0), Some((0.0, 1.0, 0.0)));
+
+        // Rising one unit per unit of x: 45 degrees, leaning back towards -x.
+        let ramp = from_fn(4, 4, (30.0, 30.0), |x, _| x);
+        let (nx, ny, nz) = ramp.normal_at(12.0, 7.0).unwrap();
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((nx + half).abs() < 1e-4 && (ny - half).abs() < 1e-4 && nz.abs() < 1e-4, "{:?}", (nx, ny, nz));
+        assert!(ramp.normal_at(31.0, 7.0).is_none());
+    }
+
+    #[test]
+    fn normals_differ_between_the_two_triangles_of_a_cell() {
+        let field = Heightfield::new(2, 2, vec![0.0, 0.0, 0.0, 10.0], (0.0, 0.0, 0.0), (1.0, 1.0));
+        assert_eq!(field.normal_at(0.2, 0.2), Some((0.0, 1.0, 0.0)));
+        let (nx, ny, nz) = field.normal_at(0.8, 0.8).unwrap();
+        assert!(nx < 0.0 && nz < 0.0 && ny > 0.0 && (nx - nz).abs() < 1e-6);
+        assert!(((nx * nx + ny * ny + nz * nz) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn mesh_lies_on_the_collision_surface() {
+        let field = from_fn(5, 4, (40.0, 30.0), |x, z| (x * 0.3).sin() * 6.0 + z * 0.2);
+        let field = Heightfield { origin: (-20.0, 2.0, 5.0), ..field };
+        let mesh = field.mesh(1, |x, z| [x as u8, z as u8, 0, 255]);
+        assert_eq!(mesh.vertex_count(), 4 * 3 * 6);
+        assert_eq!(mesh.normals.len(), mesh.positions.len());
+        assert_eq!(mesh.texcoords.len(), mesh.vertex_count() * 2);
+        assert_eq!(mesh.colors.len(), mesh.vertex_count() * 4);
+        // Every vertex carries the color of the grid point it sits on.
+        for (position, color) in mesh.positions.chunks(3).zip(mesh.colors.chunks(4)) {
+            assert_eq!([position[0] / 10.0, position[2] / 10.0], [color[0] as f32, color[1] as f32]);
+        }
+
+        for (triangle, normals) in mesh.positions.chunks(9).zip(mesh.normals.chunks(9)) {
+            let corners: Vec<(f32, f32, f32)> = triangle
+                .chunks(3)
+                .map(|c| (c[0] + field.origin.0, c[1] + field.origin.1, c[2] + field.origin.2))
+                .collect();
+            for &(x, y, z) in &corners {
+                assert_height(&field, x, z, y);
+            }
+            // The normal under the middle of each triangle is the one it is drawn with.
+            let (x, z) = (
+                (corners[0].0 + corners[1].0 + corners[2].0) / 3.0,
+                (corners[0].2 + corners[1].2 + corners[2].2) / 3.0,
+            );
+            let (nx, ny, nz) = field.normal_at(x, z).unwrap();
+            assert!((nx - normals[0]).abs() < 1e-5 && (ny - normals[1]).abs() < 1e-5 && (nz - normals[2]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn coarse_meshes_keep_every_step_th_sample() {
+        let field = from_fn(9, 5, (80.0, 40.0), |x, z| (x * 0.2).cos() * 5.0 + z * 0.1);
+        let mesh = field.mesh(2, |_, _| [255; 4]);
+        assert_eq!(mesh.vertex_count(), 4 * 2 * 6);
+        for position in mesh.positions.chunks(3) {
+            let (x, z) = ((position[0] / 10.0) as usize, (position[2] / 10.0) as usize);
+            assert!(x % 2 == 0 && z % 2 == 0, "vertex off the coarse grid at {:?}", position);
+            assert_eq!(position[1], field.sample(x, z));
+        }
+    }
+
+    #[test]
+    fn skirts_hang_outwards_from_every_edge() {
+        let field = from_fn(5, 5, (40.0, 40.0), |x, z| 3.0 + (x * 0.3).sin() + z * 0.05);
+        let mut mesh =
+            MeshData { positions: Vec::new(), normals: Vec::new(), texcoords: Vec::new(), colors: Vec::new() };
+        field.add_skirts(&mut mesh, 2, field.lowest() - 1.0, |_, _| [255; 4]);
+        // Two segments per side at a step of 2, two triangles per segment.
+        assert_eq!(mesh.vertex_count(), 4 * 2 * 6);
+        for (triangle, normal) in mesh.positions.chunks(9).zip(mesh.normals.chunks(9)) {
+            let (x, z) =
+                ((triangle[0] + triangle[3] + triangle[6]) / 3.0, (triangle[2] + triangle[5] + triangle[8]) / 3.0);
+            let outwards = if z == 0.0 {
+                (0.0, -1.0)
+            } else if x == 40.0 {
+                (1.0, 0.0)
+            } else if z == 40.0 {
+                (0.0, 1.0)
+            } else {
+                (-1.0, 0.0)
+            };
+            assert_eq!((normal[0], normal[1], normal[2]), (outwards.0, 0.0, outwards.1), "triangle {:?}", triangle);
+            let lowest = triangle.chunks(3).map(|c| c[1]).fold(f32::INFINITY, f32::min);
+            assert_eq!(lowest, field.lowest() - 1.0);
+        }
+    }
+}
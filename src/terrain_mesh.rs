@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use raylib::ffi::{Color, DrawModel, LoadModelFromMesh, MemAlloc, Mesh, Model, UnloadModel, UploadMesh, Vector3};
+
+use three_dee::chunks::ChunkCoord;
+use three_dee::lod;
+use three_dee::terrain::{Heightfield, MeshData};
+use three_dee::worldgen::BiomeMap;
+
+/// Direction the sunlight comes from, roughly matching the sun drawn in the sky.
+const SUN_DIRECTION: (f32, f32, f32) = (-0.4, 0.8, -0.45);
+/// Brightness of faces turned away from the sun.
+const AMBIENT: f32 = 0.45;
+
+/// One model per streamed-in chunk, each drawn at the level of detail for its distance
+/// from the player. Everything here needs the window to be open.
+#[derive(Default)]
+pub struct ChunkModels {
+    /// The chunk the player is in, which levels of detail are measured from.
+    center: ChunkCoord,
+    chunks: HashMap<ChunkCoord, ChunkModel>,
+}
+
+/// A chunk's model and what it was built from, to rebuild it at another level.
+struct ChunkModel {
+    heights: Heightfield,
+    biomes: BiomeMap,
+    level: usize,
+    model: Model,
+}
+
+impl ChunkModels {
+    /// Uploads the chunk at `coord`, replacing any model already drawn for it. `heights`
+    /// may have been edited since `biomes` were worked out from the generated ones.
+    pub fn insert(&mut self, coord: ChunkCoord, heights: &Heightfield, biomes: &BiomeMap) {
+        let level = lod::level_for(coord.distance(self.center));
+        let model = load_model(heights, biomes, level);
+        let entry = ChunkModel { heights: heights.clone(), biomes: biomes.clone(), level, model };
+        if let Some(old) = self.chunks.insert(coord, entry) {
+            unsafe { UnloadModel(old.model) };
+        }
+    }
+
+    /// Rebuilds the model at `coord`, if there is one, with edited `heights`.
+    pub fn refresh(&mut self, coord: ChunkCoord, heights: &Heightfield) {
+        if let Some(chunk) = self.chunks.get_mut(&coord) {
+            chunk.heights = heights.clone();
+            let model = load_model(&chunk.heights, &chunk.biomes, chunk.level);
+            unsafe { UnloadModel(std::mem::replace(&mut chunk.model, model)) };
+        }
+    }
+
+    /// Moves the player to chunk `center`, rebuilding every model whose level changes.
+    pub fn recenter(&mut self, center: ChunkCoord) {
+        if center == self.center {
+            return;
+        }
+        self.center = center;
+        for (coord, chunk) in &mut self.chunks {
+            let level = lod::level_for(coord.distance(center));
+            if level != chunk.level {
+                let model = load_model(&chunk.heights, &chunk.biomes, level);
+                unsafe { UnloadModel(std::mem::replace(&mut chunk.model, model)) };
+                chunk.level = level;
+            }
+        }
+    }
+
+    /// Unloads every model `keep` says no to.
+    pub fn retain(&mut self, mut keep: impl FnMut(ChunkCoord) -> bool) {
+        self.chunks.retain(|coord, chunk| {
+            let kept = keep(*coord);
+            if !kept {
+                unsafe { UnloadModel(chunk.model) };
+            }
+            kept
+        });
+    }
+
+    pub fn draw(&self) {
+        for (coord, chunk) in &self.chunks {
+            let (x, y, z) = coord.origin();
+            // The biome colors and lighting are baked into the vertices.
+            unsafe { DrawModel(chunk.model, Vector3 { x, y, z }, 1.0, Color { r: 255, g: 255, b: 255, a: 255 }) };
+        }
+    }
+}
+
+/// Uploads `heightfield` at `level` as a model to draw at its origin, colored by `biomes`.
+/// raylib's default shader does no lighting, so the sun is baked into the vertex colors.
+fn load_model(heightfield: &Heightfield, biomes: &BiomeMap, level: usize) -> Model {
+    let mut data = lod::chunk_mesh(heightfield, level, |x, z| biomes.at(x, z).color());
+    shade(&mut data);
+    unsafe {
+        let mut mesh: Mesh = std::mem::zeroed();
+        mesh.vertexCount = data.vertex_count() as i32;
+        mesh.triangleCount = mesh.vertexCount / 3;
+        mesh.vertices = copy_to_raylib(&data.positions);
+        mesh.normals = copy_to_raylib(&data.normals);
+        mesh.texcoords = copy_to_raylib(&data.texcoords);
+        mesh.colors = copy_to_raylib(&data.colors);
+        UploadMesh(&mut mesh, false);
+        LoadModelFromMesh(mesh)
+    }
+}
+
+fn shade(data: &mut MeshData) {
+    let (x, y, z) = SUN_DIRECTION;
+    let length = (x * x + y * y + z * z).sqrt();
+    for (normal, color) in data.normals.chunks(3).zip(data.colors.chunks_mut(4)) {
+        let facing = ((normal[0] * x + normal[1] * y + normal[2] * z) / length).max(0.0);
+        let brightness = AMBIENT + (1.0 - AMBIENT) * facing;
+        for channel in &mut color[..3] {
+            *channel = (*channel as f32 * brightness) as u8;
+        }
+    }
+}
+
+/// raylib frees a mesh's arrays itself when the model is unloaded, so they have to come
+/// from its allocator rather than from a `Vec`.
+unsafe fn copy_to_raylib<T: Copy>(values: &[T]) -> *mut T {
+    unsafe {
+        let copy = MemAlloc(std::mem::size_of_val(values) as u32) as *mut T;
+        std::ptr::copy_nonoverlapping(values.as_ptr(), copy, values.len());
+        copy
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::movement::Controller;
+
+/// How far below the bottom of the terrain a falling player gets before respawning.
+const KILL_DEPTH: f32 = 50.0;
+
+/// What happens to players who reach the edge of the map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeBehavior {
+    /// An invisible wall along the edge.
+    #[default]
+    Wall,
+    /// Walking off one side comes back in on the opposite one.
+    Wrap,
+    /// Nothing stops you walking off, and falling far enough sends you back to spawn.
+    Respawn,
+}
+
+impl FromStr for EdgeBehavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wall" => Ok(EdgeBehavior::Wall),
+            "wrap" => Ok(EdgeBehavior::Wrap),
+            "respawn" => Ok(EdgeBehavior::Respawn),
+            other => Err(format!("unknown edge behavior {:?}, expected wall, wrap or respawn", other)),
+        }
+    }
+}
+
+impl fmt::Display for EdgeBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgeBehavior::Wall => write!(f, "wall"),
+            EdgeBehavior::Wrap => write!(f, "wrap"),
+            EdgeBehavior::Respawn => write!(f, "respawn"),
+        }
+    }
+}
+
+/// The playable area, for servers that have one; without it the world goes on as far as
+/// the terrain does. The server picks the rules and sends them in its `Welcome`, so
+/// clients predict the edge exactly as the server enforces it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldBounds {
+    /// Smallest x and z inside the world.
+    pub min: (f32, f32),
+    /// Largest x and z inside the world.
+    pub max: (f32, f32),
+    /// Anyone below this height is sent back to `spawn`, whatever `edge` says.
+    pub kill_height: f32,
+    pub spawn: (f32, f32, f32),
+    pub edge: EdgeBehavior,
+}
+
+impl WorldBounds {
+    /// Bounds matching a terrain that spans `size` from `origin`.
+    pub fn around(
+        origin: (f32, f32, f32),
+        size: (f32, f32),
+        spawn: (f32, f32, f32),
+        edge: EdgeBehavior,
+    ) -> WorldBounds {
+        WorldBounds {
+            min: (origin.0, origin.2),
+            max: (origin.0 + size.0, origin.2 + size.1),
+            kill_height: origin.1 - KILL_DEPTH,
+            spawn,
+            edge,
+        }
+    }
+
+    /// Applies the edge rule to a controller that has just stepped. Returns whether it
+    /// fell out of the world and was put back at spawn.
+    pub fn enforce(&self, controller: &mut Controller) -> bool {
+        let (x, z) = (controller.position.0, controller.position.2);
+        match self.edge {
+            EdgeBehavior::Wall => {
+                let (clamped_x, clamped_z) = (x.clamp(self.min.0, self.max.0), z.clamp(self.min.1, self.max.1));
+                if clamped_x != x {
+                    controller.velocity.0 = 0.0;
+                }
+                if clamped_z != z {
+                    controller.velocity.2 = 0.0;
+                }
+                (controller.position.0, controller.position.2) = (clamped_x, clamped_z);
+            }
+            EdgeBehavior::Wrap => {
+                controller.position.0 = self.min.0 + (x - self.min.0).rem_euclid(self.max.0 - self.min.0);
+                controller.position.2 = self.min.1 + (z - self.min.1).rem_euclid(self.max.1 - self.min.1);
+            }
+            EdgeBehavior::Respawn => {}
+        }
+        if controller.position.1 < self.kill_height {
+            *controller = Controller::new(self.spawn);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(edge: EdgeBehavior) -> WorldBounds {
+        WorldBounds::around((-500.0, 0.0, -500.0), (500.0, 500.0), (-250.0, 40.0, -250.0), edge)
+    }
+
+    fn at(position: (f32, f32, f32), velocity: (f32, f32, f32)) -> Controller {
+        let mut controller = Controller::new(position);
+        controller.velocity = velocity;
+        controller
+    }
+
+    #[test]
+    fn players_inside_are_left_alone() {
+        for edge in [EdgeBehavior::Wall, EdgeBehavior::Wrap, EdgeBehavior::Respawn] {
+            let mut controller = at((-10.0, 5.0, -490.0), (3.0, -1.0, 2.0));
+            let before = controller;
+            assert!(!bounds(edge).enforce(&mut controller));
+            assert_eq!(controller, before);
+        }
+    }
+
+    #[test]
+    fn walls_stop_players_at_the_edge() {
+        let mut controller = at((3.0, 5.0, -510.0), (50.0, -2.0, -50.0));
+        assert!(!bounds(EdgeBehavior::Wall).enforce(&mut controller));
+        assert_eq!(controller.position, (0.0, 5.0, -500.0));
+        assert_eq!(controller.velocity, (0.0, -2.0, 0.0));
+    }
+
+    #[test]
+    fn wrapping_comes_back_in_on_the_other_side() {
+        let mut controller = at((2.0, 5.0, -503.0), (50.0, 0.0, -50.0));
+        bounds(EdgeBehavior::Wrap).enforce(&mut controller);
+        let (x, _, z) = controller.position;
+        assert!((x - -498.0).abs() < 1e-3 && (z - -3.0).abs() < 1e-3, "{:?}", controller.position);
+        assert_eq!(controller.velocity, (50.0, 0.0, -50.0));
+    }
+
+    #[test]
+    fn falling_out_of_the_world_respawns() {
+        let edge_bounds = bounds(EdgeBehavior::Respawn);
+        let mut controller = at((40.0, -10.0, 40.0), (50.0, -30.0, 0.0));
+        assert!(!edge_bounds.enforce(&mut controller));
+        assert_eq!(controller.position, (40.0, -10.0, 40.0));
+
+        controller.position.1 = edge_bounds.kill_height - 1.0;
+        assert!(edge_bounds.enforce(&mut controller));
+        assert_eq!(controller, Controller::new(edge_bounds.spawn));
+    }
+
+    #[test]
+    fn edge_behavior_parses_its_own_names() {
+        for edge in [EdgeBehavior::Wall, EdgeBehavior::Wrap, EdgeBehavior::Respawn] {
+            assert_eq!(edge.to_string().parse::<EdgeBehavior>(), Ok(edge));
+        }
+        assert!("lava".parse::<EdgeBehavior>().is_err());
+    }
+}
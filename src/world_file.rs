@@ -0,0 +1,492 @@
+//! World files: everything needed to host a world again after a restart.
+//!
+//! All numbers are little-endian.
+//!
+//! | Field        | Encoding                                                        |
+//! |--------------|-----------------------------------------------------------------|
+//! | magic        | the 4 bytes `DTRW`                                              |
+//! | version      | u16, `WORLD_FILE_VERSION`                                       |
+//! | seed         | u32                                                             |
+//! | preset       | u8: 0 plains, 1 mountains, 2 islands                            |
+//! | max height   | f32, the generator settings the play area was made with         |
+//! | feature size | f64                                                             |
+//! | octaves      | u8                                                              |
+//! | ridges       | f64                                                             |
+//! | warp         | f64                                                             |
+//! | sharpness    | f64                                                             |
+//! | island       | u8 1 or 0                                                       |
+//! | preset sea   | u8 1 then an f32 fraction of max height, or u8 0 for no sea     |
+//! | sea level    | u8 1 then an f32 world height, or u8 0 for no sea               |
+//! | size         | f32 width and f32 depth of the play area                        |
+//! | play area    | a patch with the generated heights of the play area             |
+//! | edit count   | u32                                                             |
+//! | edits        | that many patches, one per edited chunk                         |
+//!
+//! A patch is its first global sample as two i64 (x then z), its width and depth as u32,
+//! then its heights row by row along z. Its first and last samples must both lie within
+//! `SAMPLE_RANGE`, on the chunk grid. Each height is stored as the difference from the
+//! one before it, the sample to its left or at the start of a row the one above, taken
+//! between the f32 bit patterns mapped to keep their order. Neighbouring samples are close,
+//! so the zigzagged differences fit in a byte or two of LEB128 varint most of the time,
+//! and every height comes back bit for bit.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::chunks::{CELL_SIZE, SAMPLE_RANGE};
+use crate::editing::HeightPatch;
+use crate::worldgen::{Generator, GeneratorParams, MAP_SIZE_RANGE, TerrainPreset, WorldParams};
+
+pub const WORLD_FILE_VERSION: u16 = 3;
+const MAGIC: &[u8; 4] = b"DTRW";
+/// More samples than this in one patch is a corrupt file rather than a big world.
+const MAX_PATCH_SAMPLES: usize = 1 << 24;
+
+/// A world as saved to disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldFile {
+    pub world: WorldParams,
+    /// The preset's settings in the build that saved the file, which made `play_area`.
+    pub generator: GeneratorParams,
+    /// The play area as the generator built it when the file was saved. If a later build
+    /// generates something else, loading puts these heights back.
+    pub play_area: HeightPatch,
+    /// The edited heights of every chunk that has been edited, one patch per chunk.
+    pub edits: Vec<HeightPatch>,
+}
+
+#[derive(Debug)]
+pub enum WorldFileError {
+    Io(std::io::Error),
+    NotAWorld,
+    Version(u16),
+    Corrupt(String),
+    /// Saved with settings for its preset that this build's generator does not use; these
+    /// are the ones that differ.
+    Generator(Vec<&'static str>),
+}
+
+impl fmt::Display for WorldFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorldFileError::Io(e) => write!(f, "{}", e),
+            WorldFileError::NotAWorld => write!(f, "not a world file"),
+            WorldFileError::Version(v) => write!(f, "world file version {} (expected {})", v, WORLD_FILE_VERSION),
+            WorldFileError::Corrupt(e) => write!(f, "corrupt world file: {}", e),
+            WorldFileError::Generator(settings) => {
+                write!(f, "saved with other generator settings for its preset: {}", settings.join(", "))
+            }
+        }
+    }
+}
+
+/// Writes `file` to `path` through a temporary file next to it, so a crash halfway
+/// through never leaves a torn world behind.
+pub fn save_world(path: &Path, file: &WorldFile) -> Result<(), WorldFileError> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, file.encode()).map_err(WorldFileError::Io)?;
+    std::fs::rename(&temporary, path).map_err(WorldFileError::Io)
+}
+
+pub fn load_world(path: &Path) -> Result<WorldFile, WorldFileError> {
+    WorldFile::decode(&std::fs::read(path).map_err(WorldFileError::Io)?)
+}
+
+/// The generated heights of the play area, every sample from `TERRAIN_ORIGIN` to the far
+/// corner.
+pub fn play_area(generator: &Generator) -> HeightPatch {
+    let size = generator.world().size;
+    let (width, depth) = ((size.0 / CELL_SIZE) as usize + 1, (size.1 / CELL_SIZE) as usize + 1);
+    let heights = (0..depth as i64).flat_map(|z| (0..width as i64).map(move |x| generator.sample(x, z))).collect();
+    HeightPatch { first: (0, 0), width, heights }
+}
+
+impl WorldFile {
+    pub fn new(world: WorldParams, edits: Vec<HeightPatch>) -> WorldFile {
+        WorldFile { world, generator: world.preset.params(), play_area: play_area(&Generator::new(world)), edits }
+    }
+
+    /// The generator settings that differ between the file and this build's preset, which
+    /// would make different terrain outside the play area.
+    pub fn changed_settings(&self) -> Vec<&'static str> {
+        let (saved, ours) = (&self.generator, self.world.preset.params());
+        [
+            ("max height", saved.max_height != ours.max_height),
+            ("feature size", saved.feature_size != ours.feature_size),
+            ("octaves", saved.octaves != ours.octaves),
+            ("ridges", saved.ridges != ours.ridges),
+            ("warp", saved.warp != ours.warp),
+            ("sharpness", saved.sharpness != ours.sharpness),
+            ("island", saved.island != ours.island),
+            ("sea", saved.sea_level != ours.sea_level),
+        ]
+        .into_iter()
+        .filter_map(|(setting, changed)| changed.then_some(setting))
+        .collect()
+    }
+
+    /// The edits to host the world with: the saved ones, after the saved play area if this
+    /// build's generator no longer makes the same one from the same settings. Files saved
+    /// with other settings are refused, since the terrain around the play area would change.
+    pub fn edit_log(self) -> Result<Vec<HeightPatch>, WorldFileError> {
+        let changed = self.changed_settings();
+        if !changed.is_empty() {
+            return Err(WorldFileError::Generator(changed));
+        }
+        if play_area(&Generator::new(self.world)) == self.play_area {
+            return Ok(self.edits);
+        }
+        Ok(std::iter::once(self.play_area).chain(self.edits).collect())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(WORLD_FILE_VERSION.to_le_bytes());
+        bytes.extend(self.world.seed.to_le_bytes());
+        bytes.push(match self.world.preset {
+            TerrainPreset::Plains => 0,
+            TerrainPreset::Mountains => 1,
+            TerrainPreset::Islands => 2,
+        });
+        let generator = &self.generator;
+        bytes.extend(generator.max_height.to_le_bytes());
+        bytes.extend(generator.feature_size.to_le_bytes());
+        bytes.push(generator.octaves as u8);
+        bytes.extend(generator.ridges.to_le_bytes());
+        bytes.extend(generator.warp.to_le_bytes());
+        bytes.extend(generator.sharpness.to_le_bytes());
+        bytes.push(generator.island as u8);
+        encode_height(&mut bytes, generator.sea_level);
+        encode_height(&mut bytes, self.world.sea_level);
+        bytes.extend(self.world.size.0.to_le_bytes());
+        bytes.extend(self.world.size.1.to_le_bytes());
+        encode_patch(&mut bytes, &self.play_area);
+        bytes.extend((self.edits.len() as u32).to_le_bytes());
+        for patch in &self.edits {
+            encode_patch(&mut bytes, patch);
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<WorldFile, WorldFileError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(WorldFileError::NotAWorld);
+        }
+        let version = reader.u16()?;
+        if version != WORLD_FILE_VERSION {
+            return Err(WorldFileError::Version(version));
+        }
+        let seed = reader.u32()?;
+        let preset = match reader.u8()? {
+            0 => TerrainPreset::Plains,
+            1 => TerrainPreset::Mountains,
+            2 => TerrainPreset::Islands,
+            other => return Err(WorldFileError::Corrupt(format!("unknown terrain preset {}", other))),
+        };
+        let generator = GeneratorParams {
+            max_height: reader.f32()?,
+            feature_size: reader.f64()?,
+            octaves: reader.u8()?.into(),
+            ridges: reader.f64()?,
+            warp: reader.f64()?,
+            sharpness: reader.f64()?,
+            island: match reader.u8()? {
+                0 => false,
+                1 => true,
+                other => return Err(WorldFileError::Corrupt(format!("bad island flag {}", other))),
+            },
+            sea_level: decode_height(&mut reader)?,
+        };
+        let knobs = [
+            generator.max_height.into(),
+            generator.feature_size,
+            generator.ridges,
+            generator.warp,
+            generator.sharpness,
+        ];
+        if !knobs.iter().all(|knob| knob.is_finite()) || generator.octaves == 0 {
+            return Err(WorldFileError::Corrupt(format!("generator settings {:?}", generator)));
+        }
+        let sea_level = decode_height(&mut reader)?;
+        let size = (reader.f32()?, reader.f32()?);
+        if !MAP_SIZE_RANGE.contains(&size.0) || !MAP_SIZE_RANGE.contains(&size.1) {
+            return Err(WorldFileError::Corrupt(format!("a play area of {:?}", size)));
+        }
+        let play_area = decode_patch(&mut reader)?;
+        let count = reader.u32()?;
+        // Every patch takes at least 24 bytes, which bounds the count by the file size.
+        let mut edits = Vec::with_capacity((count as usize).min(reader.bytes.len() / 24));
+        for _ in 0..count {
+            edits.push(decode_patch(&mut reader)?);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(WorldFileError::Corrupt(format!("{} bytes after the last edit", reader.bytes.len())));
+        }
+        Ok(WorldFile { world: WorldParams { seed, preset, sea_level, size }, generator, play_area, edits })
+    }
+}
+
+/// A sea height, fraction or level: a flag byte, then the f32 if there is one.
+fn encode_height(bytes: &mut Vec<u8>, height: Option<f32>) {
+    match height {
+        Some(height) => {
+            bytes.push(1);
+            bytes.extend(height.to_le_bytes());
+        }
+        None => bytes.push(0),
+    }
+}
+
+fn decode_height(reader: &mut Reader) -> Result<Option<f32>, WorldFileError> {
+    match reader.u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(reader.f32()?)),
+        other => Err(WorldFileError::Corrupt(format!("bad sea level flag {}", other))),
+    }
+}
+
+/// Maps an f32's bits onto a u32 that sorts the same way, so close heights get close keys.
+fn height_key(height: f32) -> u32 {
+    let bits = height.to_bits();
+    if bits >> 31 == 1 { !bits } else { bits | 1 << 31 }
+}
+
+fn key_height(key: u32) -> f32 {
+    f32::from_bits(if key >> 31 == 1 { key & !(1 << 31) } else { !key })
+}
+
+/// The key each sample is stored relative to: the one to its left, the one above at the
+/// start of a row, and zero for the very first.
+fn predicted_key(keys: &[u32], width: usize) -> u32 {
+    match keys.len() {
+        0 => 0,
+        n if n.is_multiple_of(width) => keys[n - width],
+        n => keys[n - 1],
+    }
+}
+
+fn encode_patch(bytes: &mut Vec<u8>, patch: &HeightPatch) {
+    bytes.extend(patch.first.0.to_le_bytes());
+    bytes.extend(patch.first.1.to_le_bytes());
+    bytes.extend((patch.width as u32).to_le_bytes());
+    bytes.extend((patch.depth() as u32).to_le_bytes());
+    let mut keys = Vec::with_capacity(patch.heights.len());
+    for &height in &patch.heights {
+        let key = height_key(height);
+        let delta = key.wrapping_sub(predicted_key(&keys, patch.width)) as i32;
+        write_varint(bytes, ((delta << 1) ^ (delta >> 31)) as u32);
+        keys.push(key);
+    }
+}
+
+fn decode_patch(reader: &mut Reader) -> Result<HeightPatch, WorldFileError> {
+    let first = (reader.i64()?, reader.i64()?);
+    let (width, depth) = (reader.u32()? as usize, reader.u32()? as usize);
+    let samples = width.checked_mul(depth).filter(|&samples| samples > 0 && samples <= MAX_PATCH_SAMPLES);
+    let Some(samples) = samples else {
+        return Err(WorldFileError::Corrupt(format!("a patch of {}x{} samples", width, depth)));
+    };
+    let mut keys: Vec<u32> = Vec::with_capacity(samples.min(reader.bytes.len()));
+    for _ in 0..samples {
+        let zigzag = reader.varint()?;
+        let delta = ((zigzag >> 1) as i32) ^ -((zigzag & 1) as i32);
+        keys.push(predicted_key(&keys, width).wrapping_add(delta as u32));
+    }
+    let heights: Vec<f32> = keys.into_iter().map(key_height).collect();
+    if let Some(bad) = heights.iter().find(|height| !height.is_finite()) {
+        return Err(WorldFileError::Corrupt(format!("height {} in a patch", bad)));
+    }
+    let patch = HeightPatch { first, width, heights };
+    let last = patch.last();
+    if ![first.0, first.1, last.0, last.1].iter().all(|sample| SAMPLE_RANGE.contains(sample)) {
+        return Err(WorldFileError::Corrupt(format!("a patch from {:?} to {:?}, off the chunk grid", first, last)));
+    }
+    Ok(patch)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], WorldFileError> {
+        if self.bytes.len() < n {
+            return Err(WorldFileError::Corrupt("the file ends too soon".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], WorldFileError> {
+        Ok(self.take(N)?.try_into().expect("took exactly N bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, WorldFileError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, WorldFileError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, WorldFileError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, WorldFileError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, WorldFileError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, WorldFileError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    fn varint(&mut self) -> Result<u32, WorldFileError> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(WorldFileError::Corrupt("a varint longer than 5 bytes".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small world covering every part of the format. The golden file below is this world
+    /// saved; if a change to the format is on purpose, bump `WORLD_FILE_VERSION` and rerun
+    /// the tests with `UPDATE_GOLDEN=1` to write the new one.
+    fn sample_world() -> WorldFile {
+        WorldFile {
+            world: WorldParams {
+                sea_level: Some(12.5),
+                size: (250.0, 750.5),
+                ..WorldParams::new(1234, TerrainPreset::Mountains)
+            },
+            generator: GeneratorParams { octaves: 7, sea_level: Some(0.25), ..TerrainPreset::Mountains.params() },
+            play_area: HeightPatch { first: (0, 0), width: 3, heights: vec![0.0, 0.5, 1.0, -0.0, 20.25, 19.75] },
+            edits: vec![
+                HeightPatch { first: (-3, 40), width: 2, heights: vec![1.5, -2.0, 0.25, 9.0] },
+                HeightPatch {
+                    first: (*SAMPLE_RANGE.end() - 1, *SAMPLE_RANGE.start()),
+                    width: 2,
+                    heights: vec![200.0; 2],
+                },
+            ],
+        }
+    }
+
+    const GOLDEN_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/sample.world");
+    const GOLDEN: &[u8] = include_bytes!("../testdata/sample.world");
+
+    #[test]
+    fn matches_the_golden_file() {
+        let encoded = sample_world().encode();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(GOLDEN_PATH, &encoded).unwrap();
+            return;
+        }
+        assert_eq!(encoded, GOLDEN, "the world file format changed; see sample_world");
+        assert_eq!(WorldFile::decode(GOLDEN).unwrap(), sample_world());
+    }
+
+    #[test]
+    fn round_trips_a_whole_play_area_bit_for_bit() {
+        let world = WorldParams::new(99, TerrainPreset::Islands);
+        let file = WorldFile::new(world, sample_world().edits);
+        let encoded = file.encode();
+        assert_eq!(WorldFile::decode(&encoded).unwrap(), file);
+        // The raw heights would take 4 bytes a sample.
+        assert!(encoded.len() < file.play_area.heights.len() * 3, "{} bytes", encoded.len());
+    }
+
+    #[test]
+    fn saves_and_loads_through_the_filesystem() {
+        let path = std::env::temp_dir().join(format!("three-dee-{}.world", std::process::id()));
+        save_world(&path, &sample_world()).unwrap();
+        let loaded = load_world(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), sample_world());
+        assert!(matches!(load_world(&path), Err(WorldFileError::Io(_))));
+    }
+
+    #[test]
+    fn puts_the_play_area_back_if_the_generator_changed() {
+        let world = WorldParams::new(5, TerrainPreset::Plains);
+        let edits = sample_world().edits;
+        assert_eq!(WorldFile::new(world, edits.clone()).edit_log().unwrap(), edits);
+
+        let mut file = WorldFile::new(world, edits.clone());
+        file.play_area.heights[7] += 1.0;
+        let log = file.clone().edit_log().unwrap();
+        assert_eq!(log[0], file.play_area);
+        assert_eq!(&log[1..], edits.as_slice());
+    }
+
+    #[test]
+    fn refuses_worlds_saved_with_other_generator_settings() {
+        let file = WorldFile::new(WorldParams::new(5, TerrainPreset::Islands), Vec::new());
+        assert!(file.changed_settings().is_empty());
+        assert_eq!(WorldFile::decode(&file.encode()).unwrap().generator, TerrainPreset::Islands.params());
+
+        let loaded = WorldFile::decode(&sample_world().encode()).unwrap();
+        assert_eq!(loaded.generator.octaves, 7);
+        assert_eq!(loaded.changed_settings(), ["octaves", "sea"]);
+        let refused = loaded.edit_log();
+        assert!(matches!(&refused, Err(WorldFileError::Generator(settings)) if settings == &["octaves", "sea"]));
+        assert_eq!(
+            refused.unwrap_err().to_string(),
+            "saved with other generator settings for its preset: octaves, sea"
+        );
+    }
+
+    #[test]
+    fn rejects_files_it_cannot_read() {
+        let golden = sample_world().encode();
+        assert!(matches!(WorldFile::decode(b"PNG\0 not a world"), Err(WorldFileError::NotAWorld)));
+
+        let mut newer = golden.clone();
+        newer[4..6].copy_from_slice(&(WORLD_FILE_VERSION + 1).to_le_bytes());
+        assert!(matches!(WorldFile::decode(&newer), Err(WorldFileError::Version(v)) if v == WORLD_FILE_VERSION + 1));
+
+        for cut in [golden.len() - 1, 20, 6] {
+            assert!(matches!(WorldFile::decode(&golden[..cut]), Err(WorldFileError::Corrupt(_))), "cut at {}", cut);
+        }
+        let mut longer = golden.clone();
+        longer.push(0);
+        assert!(matches!(WorldFile::decode(&longer), Err(WorldFileError::Corrupt(_))));
+
+        // Patches that start or end off the chunk grid would land in the wrong chunks.
+        for (first, width) in [((i64::MIN, 0), 1), ((0, *SAMPLE_RANGE.end() + 1), 1), ((*SAMPLE_RANGE.end(), 0), 2)] {
+            let stray = HeightPatch { first, width, heights: vec![1.0; width] };
+            let file = WorldFile { edits: vec![stray], ..sample_world() };
+            assert!(matches!(WorldFile::decode(&file.encode()), Err(WorldFileError::Corrupt(_))), "{:?}", first);
+        }
+
+        // Width 0 after the header, seed, preset, generator settings, sea level, size and first sample.
+        let mut empty = golden;
+        let generator_len = 4 + 8 + 1 + 3 * 8 + 1 + 5;
+        let width_at = 4 + 2 + 4 + 1 + generator_len + 5 + 8 + 16;
+        empty[width_at..width_at + 4].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(WorldFile::decode(&empty), Err(WorldFileError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,474 @@
+use std::fmt;
+use std::str::FromStr;
+
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti};
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::{CELL_SIZE, CHUNK_CELLS, ChunkCoord};
+use crate::terrain::Heightfield;
+
+/// Width and depth of the play area in world units, unless the world says otherwise. The
+/// terrain itself goes on forever.
+pub const MAP_SIZE: (f32, f32) = (500.0, 500.0);
+/// Play area sides a world may have. The largest still fits a whole play area of edits
+/// in the welcome.
+pub const MAP_SIZE_RANGE: std::ops::RangeInclusive<f32> = CELL_SIZE..=1024.0 * CELL_SIZE;
+/// Corner of the play area, and grid vertex (0, 0) of the terrain at height zero.
+pub const TERRAIN_ORIGIN: (f32, f32, f32) = (-MAP_SIZE.0, 0.0, -MAP_SIZE.1);
+/// World a client stands on before any server has described one.
+pub const DEFAULT_WORLD: WorldParams = WorldParams::new(0, TerrainPreset::Plains);
+
+/// Land this close above the sea is beach.
+const BEACH_HEIGHT: f32 = 1.5;
+/// How much colder it gets per world unit above the sea, on the 0..=1 temperature scale.
+const LAPSE_RATE: f64 = 0.008;
+
+/// Broad shape of the generated terrain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TerrainPreset {
+    /// Low rolling hills.
+    #[default]
+    Plains,
+    /// Ridged peaks over deep valleys.
+    Mountains,
+    /// Land in the middle of the play area, falling away into the sea towards its edges.
+    Islands,
+}
+
+impl FromStr for TerrainPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plains" => Ok(TerrainPreset::Plains),
+            "mountains" => Ok(TerrainPreset::Mountains),
+            "islands" => Ok(TerrainPreset::Islands),
+            other => Err(format!("unknown terrain preset {:?}, expected plains, mountains or islands", other)),
+        }
+    }
+}
+
+impl fmt::Display for TerrainPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainPreset::Plains => write!(f, "plains"),
+            TerrainPreset::Mountains => write!(f, "mountains"),
+            TerrainPreset::Islands => write!(f, "islands"),
+        }
+    }
+}
+
+/// Knobs the generator turns for one preset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeneratorParams {
+    /// Height of the highest point the generator can produce.
+    pub max_height: f32,
+    /// Width of the coarsest hills in world units.
+    pub feature_size: f64,
+    pub octaves: usize,
+    /// Blend from smooth fBm hills (0) to ridged multifractal crests (1).
+    pub ridges: f64,
+    /// How far domain warping drags sample points, in coarsest features.
+    pub warp: f64,
+    /// Exponent on the 0..=1 height: above 1 flattens valleys and sharpens peaks.
+    pub sharpness: f64,
+    /// Whether the land sinks into the sea away from the middle of the play area.
+    pub island: bool,
+    /// Fraction of `max_height` under water, if the preset comes with a sea.
+    pub sea_level: Option<f32>,
+}
+
+impl TerrainPreset {
+    pub const fn params(self) -> GeneratorParams {
+        match self {
+            TerrainPreset::Plains => GeneratorParams {
+                max_height: 20.0,
+                feature_size: 170.0,
+                octaves: 4,
+                ridges: 0.0,
+                warp: 0.25,
+                sharpness: 1.0,
+                island: false,
+                sea_level: None,
+            },
+            TerrainPreset::Mountains => GeneratorParams {
+                max_height: 80.0,
+                feature_size: 170.0,
+                octaves: 6,
+                ridges: 0.7,
+                warp: 0.5,
+                sharpness: 1.8,
+                island: false,
+                sea_level: None,
+            },
+            TerrainPreset::Islands => GeneratorParams {
+                max_height: 30.0,
+                feature_size: 125.0,
+                octaves: 5,
+                ridges: 0.2,
+                warp: 0.6,
+                sharpness: 1.2,
+                island: true,
+                sea_level: Some(0.3),
+            },
+        }
+    }
+}
+
+/// Everything a client needs to rebuild the server's terrain; the server sends it in its
+/// `Welcome`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldParams {
+    pub seed: u32,
+    pub preset: TerrainPreset,
+    /// World height of the water surface, or `None` for a world without water. Anything
+    /// below it is under the sea, however far inland.
+    pub sea_level: Option<f32>,
+    /// Width and depth of the play area from `TERRAIN_ORIGIN`, in world units.
+    pub size: (f32, f32),
+}
+
+impl WorldParams {
+    /// A world with the preset's own sea level, if it has one, and a `MAP_SIZE` play area.
+    pub const fn new(seed: u32, preset: TerrainPreset) -> WorldParams {
+        let params = preset.params();
+        let sea_level = match params.sea_level {
+            Some(level) => Some(TERRAIN_ORIGIN.1 + level * params.max_height),
+            None => None,
+        };
+        WorldParams { seed, preset, sea_level, size: MAP_SIZE }
+    }
+}
+
+/// Turns a `WorldParams` into terrain, one chunk at a time. Only the seed and preset go
+/// over the wire, so the server and every client must build bit for bit the same heights.
+/// The noise is pure Rust arithmetic, and the one fractional power goes through `libm`
+/// rather than the platform's maths library, whose results may differ between targets.
+pub struct Generator {
+    world: WorldParams,
+    params: GeneratorParams,
+    hills: Fbm<Perlin>,
+    ridged: RidgedMulti<Perlin>,
+    warp_x: Fbm<Perlin>,
+    warp_z: Fbm<Perlin>,
+    temperature: Fbm<Perlin>,
+    moisture: Fbm<Perlin>,
+}
+
+impl Generator {
+    pub fn new(world: WorldParams) -> Generator {
+        let params = world.preset.params();
+        Generator {
+            world,
+            params,
+            hills: Fbm::new(world.seed).set_octaves(params.octaves).set_lacunarity(2.0),
+            ridged: RidgedMulti::new(world.seed.wrapping_add(1)).set_octaves(params.octaves).set_lacunarity(2.0),
+            warp_x: Fbm::new(world.seed.wrapping_add(2)).set_octaves(3),
+            warp_z: Fbm::new(world.seed.wrapping_add(3)).set_octaves(3),
+            temperature: Fbm::new(world.seed.wrapping_add(4)).set_octaves(3),
+            moisture: Fbm::new(world.seed.wrapping_add(5)).set_octaves(3),
+        }
+    }
+
+    pub fn world(&self) -> WorldParams {
+        self.world
+    }
+
+    /// Height above `TERRAIN_ORIGIN.1` of grid vertex (x, z), counted in cells from
+    /// `TERRAIN_ORIGIN`. Chunks sample by these global indices, so the vertices on a shared
+    /// edge come out bit for bit the same on both sides.
+    pub fn sample(&self, x: i64, z: i64) -> f32 {
+        let params = &self.params;
+        let (offset_x, offset_z) = (x as f64 * CELL_SIZE as f64, z as f64 * CELL_SIZE as f64);
+        let point = [offset_x / params.feature_size, offset_z / params.feature_size];
+        let warped = [point[0] + params.warp * self.warp_x.get(point), point[1] + params.warp * self.warp_z.get(point)];
+        // Both generators land in roughly -1..=1; the odd stray sample is flattened onto the range.
+        let blend = self.hills.get(warped) * (1.0 - params.ridges) + self.ridged.get(warped) * params.ridges;
+        let mut level = libm::pow(((blend + 1.0) / 2.0).clamp(0.0, 1.0), params.sharpness);
+        if params.island {
+            // Squared distance from the middle of the play area, reaching 1 halfway along each edge.
+            let (half_x, half_z) = (self.world.size.0 as f64 / 2.0, self.world.size.1 as f64 / 2.0);
+            let (dx, dz) = ((offset_x - half_x) / half_x, (offset_z - half_z) / half_z);
+            level *= (1.0 - (dx * dx + dz * dz)).max(0.0);
+        }
+        level as f32 * params.max_height
+    }
+
+    /// Heights for one chunk, including the row and column it shares with its neighbours.
+    pub fn chunk(&self, coord: ChunkCoord) -> Heightfield {
+        let samples = CHUNK_CELLS + 1;
+        let (first_x, first_z) = coord.first_sample();
+        let heights = (0..samples as i64)
+            .flat_map(|z| (0..samples as i64).map(move |x| (x, z)))
+            .map(|(x, z)| self.sample(first_x + x, first_z + z))
+            .collect();
+        let size = CHUNK_CELLS as f32 * CELL_SIZE;
+        Heightfield::new(samples, samples, heights, coord.origin(), (size, size))
+    }
+
+    /// Biomes for `heights`, which must be what `chunk` built for `coord`. Only clients
+    /// need these, to color the terrain; collision never looks at them.
+    pub fn biomes(&self, coord: ChunkCoord, heights: &Heightfield) -> BiomeMap {
+        let params = &self.params;
+        let sea_level = self.world.sea_level.map(|level| level - TERRAIN_ORIGIN.1);
+        // Climate changes more slowly across the world than the terrain does.
+        let climate_size = params.feature_size * 2.0;
+        let (first_x, first_z) = coord.first_sample();
+
+        let (width, depth) = heights.samples();
+        let biomes = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| {
+                let offset =
+                    ((first_x + x as i64) as f64 * CELL_SIZE as f64, (first_z + z as i64) as f64 * CELL_SIZE as f64);
+                let point = [offset.0 / climate_size, offset.1 / climate_size];
+                let altitude = heights.sample(x, z) - sea_level.unwrap_or(0.0);
+                let warmth = (self.temperature.get(point) + 1.0) / 2.0 - altitude.max(0.0) as f64 * LAPSE_RATE;
+                let wetness = (self.moisture.get(point) + 1.0) / 2.0;
+                Biome::classify(sea_level.map(|_| altitude), warmth.clamp(0.0, 1.0), wetness.clamp(0.0, 1.0))
+            })
+            .collect();
+        BiomeMap { width, biomes }
+    }
+}
+
+/// What grows on a patch of terrain, picked from its height, temperature and moisture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Biome {
+    /// Below the sea.
+    Ocean,
+    Beach,
+    Grassland,
+    Forest,
+    Desert,
+    Tundra,
+    Snow,
+}
+
+impl Biome {
+    /// `height_above_sea` is negative underwater and `None` in a world without a sea.
+    /// `temperature` and `moisture` run 0..=1, and the temperature already accounts for
+    /// altitude.
+    pub fn classify(height_above_sea: Option<f32>, temperature: f64, moisture: f64) -> Biome {
+        match height_above_sea {
+            Some(height) if height < 0.0 => return Biome::Ocean,
+            Some(height) if height < BEACH_HEIGHT => return Biome::Beach,
+            _ => {}
+        }
+        if temperature < 0.2 {
+            Biome::Snow
+        } else if temperature < 0.35 {
+            Biome::Tundra
+        } else if temperature > 0.6 && moisture < 0.35 {
+            Biome::Desert
+        } else if moisture > 0.55 {
+            Biome::Forest
+        } else {
+            Biome::Grassland
+        }
+    }
+
+    /// RGBA vertex color the terrain is drawn with.
+    pub fn color(self) -> [u8; 4] {
+        match self {
+            Biome::Ocean => [60, 80, 110, 255],
+            Biome::Beach => [220, 205, 150, 255],
+            Biome::Grassland => [110, 170, 70, 255],
+            Biome::Forest => [40, 110, 45, 255],
+            Biome::Desert => [215, 190, 120, 255],
+            Biome::Tundra => [150, 150, 120, 255],
+            Biome::Snow => [240, 240, 245, 255],
+        }
+    }
+}
+
+/// One biome per heightfield sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiomeMap {
+    width: usize,
+    biomes: Vec<Biome>,
+}
+
+impl BiomeMap {
+    pub fn at(&self, x: usize, z: usize) -> Biome {
+        self.biomes[z * self.width + x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRESETS: [TerrainPreset; 3] = [TerrainPreset::Plains, TerrainPreset::Mountains, TerrainPreset::Islands];
+
+    fn generator(seed: u32, preset: TerrainPreset) -> Generator {
+        Generator::new(WorldParams::new(seed, preset))
+    }
+
+    /// Every sample across the play area, which is 4x4 chunks.
+    fn play_area(generator: &Generator) -> Vec<f32> {
+        let cells = (MAP_SIZE.0 / CELL_SIZE) as i64;
+        (0..=cells).flat_map(|z| (0..=cells).map(move |x| generator.sample(x, z))).collect()
+    }
+
+    fn height_range(heights: &[f32]) -> (f32, f32) {
+        heights.iter().fold((f32::MAX, f32::MIN), |(low, high), &h| (low.min(h), high.max(h)))
+    }
+
+    #[test]
+    fn the_same_seed_builds_the_same_world() {
+        let coord = ChunkCoord { x: 1, z: 2 };
+        for preset in PRESETS {
+            assert_eq!(generator(42, preset).chunk(coord), generator(42, preset).chunk(coord));
+            assert_ne!(generator(42, preset).chunk(coord), generator(43, preset).chunk(coord));
+        }
+    }
+
+    #[test]
+    fn heights_do_not_drift_between_builds() {
+        // Clients on other machines or versions must still agree with the server, so a
+        // change to the generator or the noise crate has to show up here first.
+        let pinned = [
+            (TerrainPreset::Plains, (17, 90), 12.516169),
+            (TerrainPreset::Plains, (-300, 5), 8.502807),
+            (TerrainPreset::Mountains, (17, 90), 41.510048),
+            (TerrainPreset::Mountains, (64, 64), 7.9936595),
+            (TerrainPreset::Islands, (64, 64), 10.494759),
+        ];
+        for (preset, (x, z), expected) in pinned {
+            let height = generator(42, preset).sample(x, z);
+            assert!(
+                (height - expected).abs() < 1e-4,
+                "{} height at ({}, {}) is {}, expected {}",
+                preset,
+                x,
+                z,
+                height,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn heights_stay_within_each_preset() {
+        for preset in PRESETS {
+            let max_height = preset.params().max_height;
+            let (low, high) = height_range(&play_area(&generator(7, preset)));
+            assert!(low >= 0.0 && high <= max_height, "{}: {}..{}", preset, low, high);
+            assert!(high - low > max_height * 0.25, "{} is suspiciously flat: {}..{}", preset, low, high);
+        }
+    }
+
+    #[test]
+    fn mountains_tower_over_plains() {
+        let (_, plains) = height_range(&play_area(&generator(7, TerrainPreset::Plains)));
+        let (_, mountains) = height_range(&play_area(&generator(7, TerrainPreset::Mountains)));
+        assert!(mountains > plains * 2.0, "mountains {} vs plains {}", mountains, plains);
+    }
+
+    #[test]
+    fn islands_sink_into_the_sea_around_the_play_area() {
+        let islands = generator(7, TerrainPreset::Islands);
+        let sea_level = islands.world().sea_level.unwrap();
+        let cells = (MAP_SIZE.0 / CELL_SIZE) as i64;
+        for i in -cells..=cells * 2 {
+            for (x, z) in [(i, 0), (i, cells), (0, i), (cells, i), (i, -cells * 3)] {
+                assert!(islands.sample(x, z) < sea_level, "dry land at ({}, {})", x, z);
+            }
+        }
+        assert!(play_area(&islands).iter().any(|&h| h > sea_level + BEACH_HEIGHT), "no island at all");
+        assert_eq!(generator(7, TerrainPreset::Plains).world().sea_level, None);
+    }
+
+    #[test]
+    fn islands_fill_whatever_play_area_the_world_has() {
+        let big = Generator::new(WorldParams {
+            size: (MAP_SIZE.0 * 2.0, MAP_SIZE.1 * 2.0),
+            ..WorldParams::new(7, TerrainPreset::Islands)
+        });
+        let sea_level = big.world().sea_level.unwrap();
+        let cells = (MAP_SIZE.0 / CELL_SIZE) as i64 * 2;
+        for i in 0..=cells {
+            for (x, z) in [(i, 0), (i, cells), (0, i), (cells, i)] {
+                assert!(big.sample(x, z) < sea_level, "dry land at ({}, {})", x, z);
+            }
+        }
+        // The middle of the default play area is the corner of this one's.
+        let middle = (MAP_SIZE.0 / CELL_SIZE / 2.0) as i64;
+        assert!(big.sample(middle, middle) < generator(7, TerrainPreset::Islands).sample(middle, middle));
+    }
+
+    #[test]
+    fn chunks_sit_on_the_shared_grid() {
+        let generator = generator(7, TerrainPreset::Mountains);
+        let coord = ChunkCoord { x: -2, z: 5 };
+        let chunk = generator.chunk(coord);
+        assert_eq!(chunk.samples(), (CHUNK_CELLS + 1, CHUNK_CELLS + 1));
+        let (first_x, first_z) = coord.first_sample();
+        assert_eq!(chunk.sample(3, 7), generator.sample(first_x + 3, first_z + 7));
+        let (x, y, z) = coord.origin();
+        assert_eq!(chunk.height_at(x, z), Some(y + generator.sample(first_x, first_z)));
+    }
+
+    #[test]
+    fn biomes_follow_height_and_climate() {
+        assert_eq!(Biome::classify(Some(-3.0), 0.9, 0.9), Biome::Ocean);
+        assert_eq!(Biome::classify(Some(0.5), 0.1, 0.9), Biome::Beach);
+        assert_eq!(Biome::classify(None, 0.1, 0.5), Biome::Snow);
+        assert_eq!(Biome::classify(Some(20.0), 0.3, 0.5), Biome::Tundra);
+        assert_eq!(Biome::classify(None, 0.8, 0.1), Biome::Desert);
+        assert_eq!(Biome::classify(None, 0.5, 0.8), Biome::Forest);
+        assert_eq!(Biome::classify(None, 0.5, 0.45), Biome::Grassland);
+    }
+
+    #[test]
+    fn island_biomes_ring_the_land_with_sea() {
+        let islands = generator(7, TerrainPreset::Islands);
+        let (mut land, mut total) = (0, 0);
+        for coord in (0..4).flat_map(|z| (0..4).map(move |x| ChunkCoord { x, z })) {
+            let heights = islands.chunk(coord);
+            let biomes = islands.biomes(coord, &heights);
+            assert_eq!(biomes, islands.biomes(coord, &heights));
+            let samples = CHUNK_CELLS + 1;
+            total += samples * samples;
+            land += (0..samples)
+                .flat_map(|z| (0..samples).map(move |x| (x, z)))
+                .filter(|&(x, z)| biomes.at(x, z) != Biome::Ocean)
+                .count();
+        }
+        assert_eq!(
+            islands.biomes(ChunkCoord { x: 0, z: 0 }, &islands.chunk(ChunkCoord { x: 0, z: 0 })).at(0, 0),
+            Biome::Ocean
+        );
+        assert!(land > total / 10, "only {} of {} samples are land", land, total);
+    }
+
+    #[test]
+    fn any_world_can_be_flooded() {
+        let dry = generator(7, TerrainPreset::Plains);
+        let flooded = Generator::new(WorldParams { sea_level: Some(TERRAIN_ORIGIN.1 + 8.0), ..dry.world() });
+        let coord = ChunkCoord { x: 1, z: 1 };
+        let heights = flooded.chunk(coord);
+        assert_eq!(heights, dry.chunk(coord), "the sea should not move the ground");
+        let (dry_biomes, wet_biomes) = (dry.biomes(coord, &heights), flooded.biomes(coord, &heights));
+        let samples = CHUNK_CELLS + 1;
+        let mut seabed = 0;
+        for (x, z) in (0..samples).flat_map(|z| (0..samples).map(move |x| (x, z))) {
+            assert_ne!(dry_biomes.at(x, z), Biome::Ocean);
+            if heights.sample(x, z) < 8.0 {
+                assert_eq!(wet_biomes.at(x, z), Biome::Ocean);
+                seabed += 1;
+            }
+        }
+        assert!(seabed > 0, "nothing below the new sea level to flood");
+    }
+
+    #[test]
+    fn presets_parse_their_own_names() {
+        for preset in PRESETS {
+            assert_eq!(preset.to_string().parse::<TerrainPreset>(), Ok(preset));
+        }
+        assert!("swamp".parse::<TerrainPreset>().is_err());
+    }
+}